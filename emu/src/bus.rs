@@ -1,5 +1,7 @@
 use crate::cpu::Exception;
 use core::ops::Range;
+use core::sync::atomic::*;
+use std::sync::Mutex;
 
 macro_rules! mmap {
     ($db: tt $ds: tt $dr: tt $base: expr, $size: expr) => {
@@ -9,31 +11,163 @@ macro_rules! mmap {
     };
 }
 
-mmap!(RAM_BASE      RAM_SIZE      RAM_RANGE      0x8000_0000, 32 * 1024 * 1024);
+mmap!(RAM_BASE      RAM_SIZE      RAM_RANGE      0x8000_0000, 128 * 1024 * 1024);
 mmap!(PLIC_BASE     PLIC_SIZE     PLIC_RANGE     0x0c00_0000, 0x0400_0000);
+mmap!(ROM_BASE      ROM_SIZE      ROM_RANGE      0x0000_1000, 0x0000_f000);
+mmap!(SYSCON_BASE   SYSCON_SIZE   SYSCON_RANGE   0x0010_0000, 0x0000_1000);
+mmap!(RTC_BASE      RTC_SIZE      RTC_RANGE      0x0010_1000, 0x0000_1000);
 mmap!(CLINT_BASE    CLINT_SIZE    CLINT_RANGE    0x0200_0000, 0x0001_0000);
-mmap!(UART_BASE     UART_SIZE     UART_RANGE     0x1000_0000, 0x0000_0008);
+mmap!(SSWI_BASE     SSWI_SIZE     SSWI_RANGE     0x02f0_0000, 0x0000_4000);
+mmap!(UART_BASE     UART_SIZE     UART_RANGE     0x1000_0000, 0x0000_0100);
+mmap!(VIRTIO_BASE   VIRTIO_SIZE   VIRTIO_RANGE   0x1000_1000, 0x0000_1000);
+mmap!(FB_BASE       FB_SIZE       FB_RANGE       0x5000_0000, 0x0200_0000);
+
+/// Number of virtio-mmio slots, one every `VIRTIO_SIZE` from `VIRTIO_BASE`
+pub const VIRTIO_SLOTS: usize = 8;
+/// PLIC source of the first virtio-mmio slot, the others follow
+pub const VIRTIO_IRQ: usize = 1;
+
+/// `mip` bits that are driven by devices rather than written by software
+pub(crate) const HW_INTS: u64 = 0xa88;
 
 macro_rules! gen {
     ($l: tt $s: tt $t: tt $sz: tt $($range:tt $device:ident : $device_ty:ty),*) => {
-        impl Bus<'_> {
-            pub(crate) fn $l(&mut self, addr: u64) -> Result<$t, Exception> {
-                $(if $range.contains(&addr) { return self.$device.$l(addr); })*
+        impl Bus {
+            pub(crate) fn $l(&self, addr: u64) -> Result<$t, Exception> {
+                if RAM_RANGE.contains(&addr) { return self.ram.$l(addr); }
+                if CLINT_RANGE.contains(&addr) || SSWI_RANGE.contains(&addr) { return self.clint.$l(addr); }
+                if ROM_RANGE.contains(&addr) { return self.rom.$l(addr); }
+                if SYSCON_RANGE.contains(&addr) { return self.syscon.$l(addr); }
+                $(if $range.contains(&addr) {
+                    let mut d = self.$device.lock().unwrap();
+                    let v = d.$l(addr);
+                    self.sync_irq(&*d);
+                    return v;
+                })*
+                if let Some(m) = self.devices.iter().find(|m| m.range.contains(&addr)) {
+                    let mut d = m.device.lock().unwrap();
+                    let v = d.$l(addr);
+                    d.run(self);
+                    self.sync_irq(&**d);
+                    return v;
+                }
                 Err(Exception::LoadAccessFault)
             }
 
-            pub(crate) fn $s(&mut self, addr: u64, val: $t) -> Result<(), Exception> {
-                $(if $range.contains(&addr) { return self.$device.$s(addr, val); })*
+            pub(crate) fn $s(&self, addr: u64, val: $t) -> Result<(), Exception> {
+                if RAM_RANGE.contains(&addr) { return self.ram.$s(addr, val); }
+                if CLINT_RANGE.contains(&addr) || SSWI_RANGE.contains(&addr) { return self.clint.$s(addr, val); }
+                if ROM_RANGE.contains(&addr) { return self.rom.$s(addr, val); }
+                if SYSCON_RANGE.contains(&addr) { return self.syscon.$s(addr, val); }
+                $(if $range.contains(&addr) {
+                    let mut d = self.$device.lock().unwrap();
+                    let v = d.$s(addr, val);
+                    self.sync_irq(&*d);
+                    return v;
+                })*
+                if let Some(m) = self.devices.iter().find(|m| m.range.contains(&addr)) {
+                    let mut d = m.device.lock().unwrap();
+                    let v = d.$s(addr, val);
+                    d.run(self);
+                    self.sync_irq(&**d);
+                    return v;
+                }
                 Err(Exception::StoreAccessFault)
             }
         }
     };
 }
 
+macro_rules! gen_amo {
+    ($amo: tt $l: tt $s: tt $t: tt) => {
+        impl Bus {
+            /// Atomically replaces the value with `f(old)` and returns the old value. Stores nothing
+            /// if `f` returns `None`.
+            pub(crate) fn $amo<F: FnMut($t) -> Option<$t>>(&self, addr: u64, order: Ordering, mut f: F) -> Result<$t, Exception> {
+                if RAM_RANGE.contains(&addr) {
+                    return self.ram.$amo(addr, order, f);
+                }
+
+                // devices have no host atomics, the lock has to do
+                let _guard = self.mmio_amo.lock().unwrap();
+                let v = self.$l(addr).map_err(|_| Exception::StoreAccessFault)?;
+                if let Some(n) = f(v) {
+                    self.$s(addr, n)?;
+                }
+
+                Ok(v)
+            }
+        }
+    };
+}
+
 macro_rules! bus {
     {$($range:tt $device:ident : $device_ty:ty),* $(,)?} => {
-        pub struct Bus<'a> {
-            $($device: $device_ty),*
+        pub struct Bus {
+            ram: crate::ram::Ram,
+            clint: crate::clint::Clint,
+            syscon: crate::syscon::Syscon,
+            rom: crate::rom::Rom,
+            /// Where the harts start, at reset
+            reset_vector: u64,
+            /// What was loaded into RAM before the harts started, loaded again at reset
+            images: Mutex<Vec<(u64, Box<[u8]>)>>,
+            $($device: Mutex<$device_ty>,)*
+            /// Devices added when the machine is built, like the UARTs
+            devices: Vec<Mapped>,
+
+            /// LR reservation of each hart, so that stores from one hart can break the reservations
+            /// of the others
+            reservations: Box<[AtomicU64]>,
+            mmio_amo: Mutex<()>,
+            /// External interrupt outputs of the PLIC, one per context
+            plic_eip: std::sync::Arc<[AtomicBool]>,
+            /// Instructions of hart 0 since the devices were last polled
+            ticks: AtomicU64,
+            uarts: usize,
+            virtio: usize,
+            rtc: bool,
+            screen: Option<crate::framebuffer::Screen>,
+            monitor: Mutex<Option<crate::monitor::Monitor>>,
+            /// HSM states of the harts, when the built-in SBI firmware runs instead of M-mode code
+            sbi: Option<crate::cpu::Hsm>,
+        }
+
+        impl Bus {
+            /// Puts every device back in its power-on state and updates its interrupt line
+            fn reset_devices(&self) {
+                $({
+                    let mut d = self.$device.lock().unwrap();
+                    d.reset();
+                    self.sync_irq(&*d);
+                })*
+
+                for m in self.devices.iter() {
+                    let mut d = m.device.lock().unwrap();
+                    d.reset();
+                    self.sync_irq(&**d);
+                }
+            }
+
+            /// Lets every device take in host input and update its interrupt line
+            fn poll_devices(&self) {
+                $({
+                    let mut d = self.$device.lock().unwrap();
+                    d.poll();
+                    self.sync_irq(&*d);
+                })*
+
+                for m in self.devices.iter() {
+                    let mut d = m.device.lock().unwrap();
+                    d.poll();
+                    d.run(self);
+                    self.sync_irq(&**d);
+                }
+
+                if let Some(m) = self.monitor.lock().unwrap().as_mut() {
+                    m.poll(self);
+                }
+            }
         }
 
         gen!(load_u8 store_u8 u8 1    $($range $device: $device_ty),*);
@@ -44,21 +178,309 @@ macro_rules! bus {
 }
 
 bus! {
-    RAM_RANGE   ram: crate::ram::Ram<'a>,
     PLIC_RANGE  plic: crate::plic::Plic,
-    CLINT_RANGE clint: crate::clint::Clint,
-    UART_RANGE  uart: crate::uart::Uart,
 }
 
-impl<'a> Bus<'a> {
-    pub fn new(ram: crate::ram::Ram<'a>) -> Self {
+struct Mapped {
+    range: Range<u64>,
+    device: Mutex<Box<dyn Device + Send>>,
+}
+
+gen_amo!(amo_u32 load_u32 store_u32 u32);
+gen_amo!(amo_u64 load_u64 store_u64 u64);
+
+const NO_RESERVATION: u64 = u64::MAX;
+
+/// Instructions of hart 0 between polls of the devices for host input
+const POLL_TICKS: u64 = 1024;
+
+/// Size and alignment of the reservation set of an `lr`, in bytes
+pub const RESERVATION_GRANULE: u64 = 64;
+
+impl Bus {
+    pub fn new(ram: crate::ram::Ram, harts: usize, timebase: crate::clint::Timebase) -> Self {
+        let plic = crate::plic::Plic::new(harts);
+
         Self {
             ram,
-            plic: crate::plic::Plic::new(),
-            clint: crate::clint::Clint::new(),
-            uart: crate::uart::Uart::new(),
+            clint: crate::clint::Clint::new(harts, timebase),
+            syscon: crate::syscon::Syscon::default(),
+            rom: crate::rom::Rom::new(RAM_BASE, 0),
+            reset_vector: RAM_BASE,
+            images: Mutex::new(Vec::new()),
+            plic_eip: plic.outputs(),
+            plic: Mutex::new(plic),
+            devices: Vec::new(),
+
+            reservations: (0..harts).map(|_| AtomicU64::new(NO_RESERVATION)).collect(),
+            mmio_amo: Mutex::new(()),
+            ticks: AtomicU64::new(0),
+            uarts: 0,
+            virtio: 0,
+            rtc: false,
+            screen: None,
+            monitor: Mutex::new(None),
+            sbi: None,
+        }
+    }
+
+    /// Returns the `mip` bits of a hart that are currently asserted by devices
+    pub(crate) fn pending_interrupts(&self, hart: usize) -> u64 {
+        let meip = self.plic_eip[hart * 2].load(Ordering::SeqCst);
+        let seip = self.plic_eip[hart * 2 + 1].load(Ordering::SeqCst);
+
+        (self.clint.msip(hart) as u64) << 3
+            | (self.clint.mtip(hart) as u64) << 7
+            | (seip as u64) << 9
+            | (meip as u64) << 11
+    }
+
+    /// Whether `mtime` has reached `cmp`, cheap enough to check after every instruction
+    pub(crate) fn timer_reached(&self, cmp: u64) -> bool {
+        self.clint.reached(cmp)
+    }
+
+    /// Sets the level of a PLIC interrupt line, for devices
+    pub(crate) fn set_irq(&self, irq: usize, level: bool) {
+        self.plic.lock().unwrap().set_irq(irq, level);
+    }
+
+    /// Returns the edge triggered `mip` bits raised for a hart since the last call
+    pub(crate) fn take_interrupts(&self, hart: usize) -> u64 {
+        (self.clint.take_ssip(hart) as u64) << 1
+    }
+
+    /// Advances the timebase and polls the devices, called after every instruction of hart 0
+    pub(crate) fn tick(&self) {
+        self.clint.tick();
+
+        if self.ticks.fetch_add(1, Ordering::Relaxed) + 1 >= POLL_TICKS {
+            self.ticks.store(0, Ordering::Relaxed);
+            self.poll_devices();
+        }
+    }
+
+    fn sync_irq<D: Device + ?Sized>(&self, d: &D) {
+        if let Some((irq, level)) = d.irq() {
+            self.set_irq(irq, level);
+        }
+    }
+
+    fn map(&mut self, range: Range<u64>, device: Box<dyn Device + Send>) {
+        assert!(!self.devices.iter().any(|m| m.range.start < range.end && range.start < m.range.end));
+        self.devices.push(Mapped { range, device: Mutex::new(device) });
+    }
+
+    /// Adds the next UART, attached to `backend`. Returns its index, or `None` if all
+    /// `uart::UARTS` are taken.
+    pub fn add_uart(&mut self, backend: Box<dyn crate::chardev::Chardev>) -> Option<usize> {
+        let n = self.uarts;
+        let base = UART_BASE + UART_SIZE * n as u64;
+        let irq = *crate::uart::UART_IRQS.get(n)?;
+
+        self.map(base..base + UART_SIZE, Box::new(crate::uart::Uart::new(base, irq, backend)));
+        self.uarts += 1;
+        Some(n)
+    }
+
+    /// Puts a virtio device in the next virtio-mmio slot. Returns the slot, or `None` if all
+    /// `VIRTIO_SLOTS` are taken.
+    pub fn add_virtio<D: crate::virtio::Device + 'static>(&mut self, device: D, legacy: bool) -> Option<usize> {
+        let n = self.virtio;
+        if n == VIRTIO_SLOTS {
+            return None;
+        }
+
+        let base = VIRTIO_BASE + VIRTIO_SIZE * n as u64;
+        self.map(base..base + VIRTIO_SIZE, Box::new(crate::virtio::Mmio::new(base, VIRTIO_IRQ + n, device, legacy)));
+        self.virtio += 1;
+        Some(n)
+    }
+
+    /// Makes the harts start at `pc`, like `ROM_BASE` to boot through the boot ROM. Without this
+    /// they start at `RAM_BASE`.
+    pub fn set_reset_vector(&mut self, pc: u64) {
+        self.reset_vector = pc;
+    }
+
+    pub(crate) fn reset_vector(&self) -> u64 {
+        self.reset_vector
+    }
+
+    /// Makes the boot ROM jump to `entry`, `RAM_BASE` by default
+    pub fn set_entry(&mut self, entry: u64) {
+        self.rom.set_entry(entry);
+    }
+
+    /// Makes the boot ROM pass `dtb` in a1, 0 by default
+    pub fn set_dtb(&mut self, dtb: u64) {
+        self.rom.set_dtb(dtb);
+    }
+
+    /// Makes the boot ROM tell `fw_dynamic` firmware to go on to `addr` in S-mode
+    pub fn set_next_addr(&mut self, addr: u64) {
+        self.rom.set_next_addr(addr);
+    }
+
+    pub(crate) fn entry(&self) -> u64 {
+        self.rom.entry()
+    }
+
+    pub(crate) fn dtb(&self) -> u64 {
+        self.rom.dtb()
+    }
+
+    /// Copies a device tree blob to the top of RAM and makes the boot ROM pass it. Returns its
+    /// address, or `None` if it does not fit.
+    pub fn load_dtb(&mut self, dtb: &[u8]) -> Option<u64> {
+        let addr = (RAM_SIZE.checked_sub(dtb.len() as u64)? & !0xfff) + RAM_BASE;
+        self.load(addr, dtb).ok()?;
+        self.set_dtb(addr);
+        Some(addr)
+    }
+
+    /// Copies an image, like a program or a kernel, into RAM. It is copied there again whenever
+    /// the machine resets.
+    pub fn load(&self, addr: u64, data: &[u8]) -> std::io::Result<()> {
+        if self.dma_write(addr, data).is_err() {
+            let msg = format!("{:#x} bytes at {addr:#x} are not in RAM", data.len());
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, msg));
+        }
+
+        self.images.lock().unwrap().push((addr, data.into()));
+        Ok(())
+    }
+
+    /// Puts the machine back in the state it was in before the harts first ran: RAM holds nothing
+    /// but the loaded images, and every device, the PLIC and the CLINT are reset. `mtime` keeps
+    /// counting, and disks keep what was written to them.
+    pub(crate) fn reset(&self) {
+        self.ram.clear();
+        for (addr, data) in self.images.lock().unwrap().iter() {
+            _ = self.ram.write(*addr, data);
+        }
+
+        self.clint.reset();
+        self.reset_devices();
+
+        for r in self.reservations.iter() {
+            r.store(NO_RESERVATION, Ordering::SeqCst);
         }
     }
+
+    pub(crate) fn harts(&self) -> usize {
+        self.reservations.len()
+    }
+
+    pub(crate) fn uarts(&self) -> usize {
+        self.uarts
+    }
+
+    /// Number of virtio-mmio slots in use, they are taken in order
+    pub(crate) fn virtio_slots(&self) -> usize {
+        self.virtio
+    }
+
+    pub(crate) fn has_rtc(&self) -> bool {
+        self.rtc
+    }
+
+    /// The power off or reset the guest asked for through the syscon, if any
+    pub(crate) fn exit(&self) -> Option<crate::syscon::Exit> {
+        self.syscon.exit()
+    }
+
+    /// Like `exit`, and lets the guest ask again
+    pub(crate) fn take_exit(&self) -> Option<crate::syscon::Exit> {
+        self.syscon.take_exit()
+    }
+
+    /// Powers off or resets the machine, as if the guest wrote to the syscon
+    pub(crate) fn request_exit(&self, exit: crate::syscon::Exit) {
+        self.syscon.request(exit);
+    }
+
+    /// Runs the harts in S-mode on top of the built-in SBI firmware, which handles what would trap
+    /// to M-mode. The harts start at the entry of the boot ROM with the device tree in a1, the
+    /// reset vector is not used. Call before the harts are created.
+    pub fn enable_sbi(&mut self) {
+        self.sbi = Some(crate::cpu::Hsm::new(self.harts()));
+    }
+
+    pub(crate) fn hsm(&self) -> Option<&crate::cpu::Hsm> {
+        self.sbi.as_ref()
+    }
+
+    pub fn add_rtc(&mut self, rtc: crate::rtc::Rtc) {
+        self.map(RTC_RANGE, Box::new(rtc));
+        self.rtc = true;
+    }
+
+    /// Maps the framebuffer at `FB_BASE`. Returns the host's view of it, or `None` if the machine
+    /// already has one.
+    pub fn add_framebuffer(&mut self, fb: crate::framebuffer::Framebuffer) -> Option<crate::framebuffer::Screen> {
+        if self.screen.is_some() {
+            return None;
+        }
+
+        let screen = fb.screen();
+        let size = (screen.mode().size() as u64).next_multiple_of(0x1000);
+        self.map(FB_BASE..FB_BASE + size, Box::new(fb));
+        self.screen = Some(screen.clone());
+        Some(screen)
+    }
+
+    pub fn screen(&self) -> Option<&crate::framebuffer::Screen> {
+        self.screen.as_ref()
+    }
+
+    /// Runs a monitor on the host's input until the machine stops
+    pub fn set_monitor(&mut self, monitor: crate::monitor::Monitor) {
+        *self.monitor.get_mut().unwrap() = Some(monitor);
+    }
+
+    pub(crate) fn mtime(&self) -> u64 {
+        self.clint.mtime()
+    }
+
+    /// Replaces the reservation of a hart with the granule containing `addr`
+    pub(crate) fn reserve(&self, hart: usize, addr: u64) {
+        self.reservations[hart].store(addr & !(RESERVATION_GRANULE - 1), Ordering::SeqCst);
+    }
+
+    pub(crate) fn reservation_valid(&self, hart: usize, addr: u64) -> bool {
+        self.reservations[hart].load(Ordering::SeqCst) == addr & !(RESERVATION_GRANULE - 1)
+    }
+
+    pub(crate) fn clear_reservation(&self, hart: usize) {
+        self.reservations[hart].store(NO_RESERVATION, Ordering::SeqCst);
+    }
+
+    /// Called on every store of a hart, breaks the reservations of the other harts on the granules
+    /// the store touches
+    pub(crate) fn break_reservations(&self, hart: usize, addr: u64, len: u64) {
+        let first = addr & !(RESERVATION_GRANULE - 1);
+        let last = (addr + len.max(1) - 1) & !(RESERVATION_GRANULE - 1);
+
+        for (i, r) in self.reservations.iter().enumerate() {
+            let g = r.load(Ordering::SeqCst);
+            if i != hart && (first..=last).contains(&g) {
+                _ = r.compare_exchange(g, NO_RESERVATION, Ordering::SeqCst, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Reads guest memory on behalf of a device
+    pub(crate) fn dma_read(&self, addr: u64, buf: &mut [u8]) -> Result<(), Exception> {
+        self.ram.read(addr, buf)
+    }
+
+    /// Writes guest memory on behalf of a device, breaking the reservations of every hart on it
+    pub(crate) fn dma_write(&self, addr: u64, buf: &[u8]) -> Result<(), Exception> {
+        self.ram.write(addr, buf)?;
+        self.break_reservations(usize::MAX, addr, buf.len() as u64);
+        Ok(())
+    }
 }
 
 pub(crate) trait Device {
@@ -71,4 +493,27 @@ pub(crate) trait Device {
     fn store_u16(&mut self, _addr: u64, _val: u16) -> Result<(), Exception> { Err(Exception::StoreAccessFault) }
     fn store_u32(&mut self, _addr: u64, _val: u32) -> Result<(), Exception> { Err(Exception::StoreAccessFault) }
     fn store_u64(&mut self, _addr: u64, _val: u64) -> Result<(), Exception> { Err(Exception::StoreAccessFault) }
+
+    /// Called regularly, to take in input from the host
+    fn poll(&mut self) {}
+    /// Called after every access and poll, for work that needs guest memory, like DMA
+    fn run(&mut self, _bus: &Bus) {}
+    /// The PLIC source of the device and the level of its line
+    fn irq(&self) -> Option<(usize, bool)> { None }
+    /// Puts the device back in its power-on state, when the machine resets
+    fn reset(&mut self) {}
+}
+
+/// A device that synchronizes itself, so that harts on different threads can access it without
+/// taking a lock
+pub(crate) trait SharedDevice {
+    fn load_u8(&self, _addr: u64) -> Result<u8, Exception> { Err(Exception::LoadAccessFault) }
+    fn load_u16(&self, _addr: u64) -> Result<u16, Exception> { Err(Exception::LoadAccessFault) }
+    fn load_u32(&self, _addr: u64) -> Result<u32, Exception> { Err(Exception::LoadAccessFault) }
+    fn load_u64(&self, _addr: u64) -> Result<u64, Exception> { Err(Exception::LoadAccessFault) }
+
+    fn store_u8(&self, _addr: u64, _val: u8) -> Result<(), Exception> { Err(Exception::StoreAccessFault) }
+    fn store_u16(&self, _addr: u64, _val: u16) -> Result<(), Exception> { Err(Exception::StoreAccessFault) }
+    fn store_u32(&self, _addr: u64, _val: u32) -> Result<(), Exception> { Err(Exception::StoreAccessFault) }
+    fn store_u64(&self, _addr: u64, _val: u64) -> Result<(), Exception> { Err(Exception::StoreAccessFault) }
 }
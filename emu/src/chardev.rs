@@ -0,0 +1,382 @@
+//! Host side of character devices like the UART
+
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Mutex};
+
+pub trait Chardev: Send {
+    /// Returns the next byte from the host, without blocking
+    fn read(&mut self) -> Option<u8>;
+    fn write(&mut self, b: u8);
+}
+
+/// Parses a backend spec: `stdio`, `pty`, `unix:PATH`, `file:PATH`, `tcp:ADDR` or `null`
+pub fn open(spec: &str) -> std::io::Result<Box<dyn Chardev>> {
+    let (kind, arg) = spec.split_once(':').unwrap_or((spec, ""));
+
+    Ok(match kind {
+        "stdio" => Box::new(Stdio::spawn()),
+        "pty" => Box::new(Pty::new()?),
+        "unix" => {
+            remove_socket(arg)?;
+            Box::new(Socket::Unix(UnixListener::bind(arg)?, None, VecDeque::new()).listening()?)
+        },
+        "file" => Box::new(File(std::fs::File::create(arg)?)),
+        "tcp" => Box::new(Socket::Tcp(TcpListener::bind(arg)?, None, VecDeque::new()).listening()?),
+        "null" => Box::new(Null),
+        _ => return Err(std::io::Error::new(ErrorKind::InvalidInput, format!("unknown chardev `{kind}`"))),
+    })
+}
+
+/// Removes a socket left at `path` by an earlier run, so that a new one can be bound there. Anything
+/// else at `path` is left alone and is an error.
+pub(crate) fn remove_socket(path: &str) -> std::io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(m) if m.file_type().is_socket() => std::fs::remove_file(path),
+        Ok(_) => Err(std::io::Error::new(ErrorKind::AlreadyExists, format!("`{path}` exists and is not a socket"))),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Drops output and never has input
+pub struct Null;
+
+impl Chardev for Null {
+    fn read(&mut self) -> Option<u8> { None }
+    fn write(&mut self, _b: u8) {}
+}
+
+/// The emulator's own stdin and stdout. Stdin is read on a thread of its own, and since ctrl-c goes
+/// to the guest when the terminal is in raw mode, ctrl-a x quits and ctrl-a ctrl-a sends a ctrl-a.
+pub struct Stdio {
+    input: Receiver<u8>,
+}
+
+impl Stdio {
+    pub fn spawn() -> Self {
+        let (tx, rx) = channel();
+
+        std::thread::spawn(move || {
+            let mut escape = false;
+
+            for b in std::io::stdin().lock().bytes() {
+                let Ok(b) = b else { break };
+
+                if escape {
+                    escape = false;
+                    match b {
+                        b'x' => std::process::exit(0),
+                        1 => {},
+                        _ => continue,
+                    }
+                } else if b == 1 {
+                    escape = true;
+                    continue;
+                }
+
+                if tx.send(b).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Self { input: rx }
+    }
+}
+
+impl Chardev for Stdio {
+    fn read(&mut self) -> Option<u8> {
+        self.input.try_recv().ok()
+    }
+
+    fn write(&mut self, b: u8) {
+        let mut out = std::io::stdout();
+        _ = out.write_all(&[b]);
+        _ = out.flush();
+    }
+}
+
+/// A new pseudo terminal, whose path is printed on stderr
+pub struct Pty {
+    master: std::fs::File,
+    buf: VecDeque<u8>,
+}
+
+impl Pty {
+    pub fn new() -> std::io::Result<Self> {
+        use std::os::fd::FromRawFd;
+
+        unsafe {
+            let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_NONBLOCK);
+            if fd < 0 || libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+
+            let master = std::fs::File::from_raw_fd(fd);
+
+            // raw, so that the guest sees every byte as it was typed
+            let mut t = core::mem::zeroed();
+            if libc::tcgetattr(fd, &mut t) == 0 {
+                libc::cfmakeraw(&mut t);
+                libc::tcsetattr(fd, libc::TCSANOW, &t);
+            }
+
+            let name = std::ffi::CStr::from_ptr(libc::ptsname(fd));
+            eprintln!("char device redirected to {}", name.to_string_lossy());
+
+            Ok(Self { master, buf: VecDeque::new() })
+        }
+    }
+}
+
+impl Chardev for Pty {
+    fn read(&mut self) -> Option<u8> {
+        fill(&mut self.master, &mut self.buf);
+        self.buf.pop_front()
+    }
+
+    fn write(&mut self, b: u8) {
+        // nobody has the pty open if this fails, drop the byte
+        _ = self.master.write_all(&[b]);
+    }
+}
+
+/// Reads whatever is available from a non-blocking reader. Returns false once it is closed.
+fn fill(r: &mut impl Read, buf: &mut VecDeque<u8>) -> bool {
+    let mut chunk = [0; 256];
+
+    match r.read(&mut chunk) {
+        Ok(0) => false,
+        Ok(n) => {
+            buf.extend(&chunk[..n]);
+            true
+        },
+        Err(e) => e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::Interrupted,
+    }
+}
+
+/// A listening socket that takes one client at a time. Output is dropped while no one is connected.
+pub enum Socket {
+    Unix(UnixListener, Option<UnixStream>, VecDeque<u8>),
+    Tcp(TcpListener, Option<TcpStream>, VecDeque<u8>),
+}
+
+impl Socket {
+    fn listening(self) -> std::io::Result<Self> {
+        match &self {
+            Self::Unix(l, ..) => l.set_nonblocking(true)?,
+            Self::Tcp(l, ..) => l.set_nonblocking(true)?,
+        }
+
+        Ok(self)
+    }
+
+    /// Accepts a client if there is none yet
+    fn accept(&mut self) {
+        match self {
+            Self::Unix(l, s @ None, _) => if let Ok((c, _)) = l.accept() {
+                _ = c.set_nonblocking(true);
+                *s = Some(c);
+            },
+            Self::Tcp(l, s @ None, _) => if let Ok((c, _)) = l.accept() {
+                _ = c.set_nonblocking(true);
+                _ = c.set_nodelay(true);
+                *s = Some(c);
+            },
+            _ => {},
+        }
+    }
+}
+
+impl Chardev for Socket {
+    fn read(&mut self) -> Option<u8> {
+        self.accept();
+
+        match self {
+            Self::Unix(_, s, buf) => {
+                if s.as_mut().is_some_and(|c| !fill(c, buf)) {
+                    *s = None;
+                }
+
+                buf.pop_front()
+            },
+            Self::Tcp(_, s, buf) => {
+                if s.as_mut().is_some_and(|c| !fill(c, buf)) {
+                    *s = None;
+                }
+
+                buf.pop_front()
+            },
+        }
+    }
+
+    fn write(&mut self, b: u8) {
+        self.accept();
+
+        let res = match self {
+            Self::Unix(_, Some(c), _) => c.write(&[b]),
+            Self::Tcp(_, Some(c), _) => c.write(&[b]),
+            _ => return,
+        };
+
+        // a client that does not keep up loses the byte, only a real error disconnects it
+        let gone = res.is_err_and(|e| e.kind() != ErrorKind::WouldBlock && e.kind() != ErrorKind::Interrupted);
+        if gone {
+            match self {
+                Self::Unix(_, s, _) => *s = None,
+                Self::Tcp(_, s, _) => *s = None,
+            }
+        }
+    }
+}
+
+/// Output only, into a file
+pub struct File(std::fs::File);
+
+impl Chardev for File {
+    fn read(&mut self) -> Option<u8> { None }
+
+    fn write(&mut self, b: u8) {
+        _ = self.0.write_all(&[b]);
+    }
+}
+
+/// In-memory backend for tests: input is queued and output recorded through a `MemoryHandle`
+pub struct Memory(Arc<Mutex<MemoryState>>);
+
+#[derive(Default)]
+struct MemoryState {
+    input: VecDeque<u8>,
+    transcript: Vec<u8>,
+}
+
+#[derive(Clone)]
+pub struct MemoryHandle(Arc<Mutex<MemoryState>>);
+
+impl Memory {
+    pub fn new() -> (Self, MemoryHandle) {
+        let state = Arc::new(Mutex::new(MemoryState::default()));
+        (Self(Arc::clone(&state)), MemoryHandle(state))
+    }
+}
+
+impl Chardev for Memory {
+    fn read(&mut self) -> Option<u8> {
+        self.0.lock().unwrap().input.pop_front()
+    }
+
+    fn write(&mut self, b: u8) {
+        self.0.lock().unwrap().transcript.push(b);
+    }
+}
+
+impl MemoryHandle {
+    /// Queues input for the guest
+    pub fn send(&self, data: &[u8]) {
+        self.0.lock().unwrap().input.extend(data);
+    }
+
+    /// Everything the guest has written so far
+    pub fn transcript(&self) -> Vec<u8> {
+        self.0.lock().unwrap().transcript.clone()
+    }
+
+    /// Whether the guest has consumed all queued input
+    pub fn input_empty(&self) -> bool {
+        self.0.lock().unwrap().input.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A path under the system temporary directory, with nothing there yet
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("emu-chardev-{name}-{}", std::process::id()));
+        _ = std::fs::remove_file(&path);
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn memory() {
+        let (mut dev, handle) = Memory::new();
+        assert_eq!(dev.read(), None);
+
+        handle.send(b"hi");
+        assert!(!handle.input_empty());
+        assert_eq!((dev.read(), dev.read(), dev.read()), (Some(b'h'), Some(b'i'), None));
+        assert!(handle.input_empty());
+
+        dev.write(b'o');
+        dev.write(b'k');
+        assert_eq!(handle.transcript(), b"ok");
+    }
+
+    #[test]
+    fn file() {
+        let path = temp_path("file");
+        std::fs::write(&path, "old").unwrap();
+
+        let mut dev = open(&format!("file:{path}")).unwrap();
+        for b in b"out" {
+            dev.write(*b);
+        }
+        assert_eq!(dev.read(), None);
+        assert_eq!(std::fs::read(&path).unwrap(), b"out");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn unix() {
+        let path = temp_path("unix");
+        let mut dev = open(&format!("unix:{path}")).unwrap();
+
+        // output is dropped until a client connects
+        dev.write(b'x');
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.write_all(b"in").unwrap();
+        assert_eq!((dev.read(), dev.read(), dev.read()), (Some(b'i'), Some(b'n'), None));
+        dev.write(b'y');
+        let mut b = [0];
+        client.read_exact(&mut b).unwrap();
+        assert_eq!(b, *b"y");
+
+        // a burst bigger than the socket buffer drops bytes, not the client
+        for _ in 0..1 << 20 {
+            dev.write(b'.');
+        }
+        client.set_nonblocking(true).unwrap();
+        let mut chunk = [0; 4096];
+        while client.read(&mut chunk).is_ok_and(|n| n > 0) {}
+        client.set_nonblocking(false).unwrap();
+        dev.write(b'w');
+        client.read_exact(&mut b).unwrap();
+        assert_eq!(b, *b"w");
+
+        // the socket of an earlier run is replaced
+        drop(dev);
+        drop(client);
+        let mut dev = open(&format!("unix:{path}")).unwrap();
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.write_all(b"z").unwrap();
+        assert_eq!(dev.read(), Some(b'z'));
+        drop(dev);
+        std::fs::remove_file(&path).unwrap();
+
+        // but anything else is not
+        std::fs::write(&path, "data").unwrap();
+        let err = open(&format!("unix:{path}")).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read(&path).unwrap(), b"data");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
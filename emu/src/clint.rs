@@ -1,37 +1,190 @@
 use crate::bus::*;
 use crate::cpu::Exception;
+use core::sync::atomic::*;
+use std::time::Instant;
 
+/// What drives `mtime`
+#[derive(Debug, Clone, Copy)]
+pub enum Timebase {
+    /// `mtime` advances once every this many instructions of hart 0, which keeps runs deterministic
+    Instructions(u64),
+    /// `mtime` runs at this frequency (in Hz) off the host monotonic clock
+    Host(u64),
+}
+
+/// With `Timebase::Host`, instructions of hart 0 between reads of the host clock
+const HOST_SYNC_STEPS: u64 = 256;
+
+/// Core local interruptor, laid out as an ACLINT: MSWI and MTIMER in the usual CLINT window, and
+/// SSWI for supervisor IPIs in a window of its own. Every register is a host atomic, so that IPIs
+/// and timer writes from harts on other threads are seen without locking.
 pub struct Clint {
-    mtime: u64,
-    mtimecmp: u64,
+    timebase: Timebase,
+    start: Instant,
+    /// Instructions of hart 0 since `mtime` was last advanced or synced to the host clock
+    steps: AtomicU64,
+    /// Value of `mtime` when the host clock started, with `Timebase::Host`
+    offset: AtomicU64,
+
+    mtime: AtomicU64,
+    msip: Box<[AtomicBool]>,
+    mtimecmp: Box<[AtomicU64]>,
+    /// `setssip` writes not yet seen by their hart
+    ssip: Box<[AtomicBool]>,
 }
 
+const CLINT_MSIP: u64 = CLINT_BASE;
 const CLINT_MTIMECMP: u64 = CLINT_BASE + 0x4000;
 const CLINT_MTIME: u64 = CLINT_BASE + 0xbff8;
+const SSWI_SETSSIP: u64 = SSWI_BASE;
 
 impl Clint {
-    pub fn new() -> Self {
+    pub fn new(harts: usize, timebase: Timebase) -> Self {
         Self {
-            mtime: 0,
-            mtimecmp: 0,
+            timebase,
+            start: Instant::now(),
+            steps: AtomicU64::new(0),
+            offset: AtomicU64::new(0),
+
+            mtime: AtomicU64::new(0),
+            msip: (0..harts).map(|_| AtomicBool::new(false)).collect(),
+            mtimecmp: (0..harts).map(|_| AtomicU64::new(u64::MAX)).collect(),
+            ssip: (0..harts).map(|_| AtomicBool::new(false)).collect(),
+        }
+    }
+
+    /// Clears the software interrupts and the timer compare registers, `mtime` keeps counting
+    pub(crate) fn reset(&self) {
+        for h in 0..self.msip.len() {
+            self.msip[h].store(false, Ordering::SeqCst);
+            self.mtimecmp[h].store(u64::MAX, Ordering::SeqCst);
+            self.ssip[h].store(false, Ordering::SeqCst);
+        }
+    }
+
+    pub(crate) fn msip(&self, hart: usize) -> bool {
+        self.msip[hart].load(Ordering::SeqCst)
+    }
+
+    /// Whether `mtime` has reached the `mtimecmp` of a hart. This uses the last synced `mtime`,
+    /// so it is cheap enough to check after every instruction.
+    pub(crate) fn mtip(&self, hart: usize) -> bool {
+        self.reached(self.mtimecmp[hart].load(Ordering::SeqCst))
+    }
+
+    /// Whether the last synced `mtime` has reached `cmp`, like `stimecmp`
+    pub(crate) fn reached(&self, cmp: u64) -> bool {
+        self.mtime.load(Ordering::SeqCst) >= cmp
+    }
+
+    /// Returns whether there was a `setssip` write for a hart since the last call. Unlike the other
+    /// interrupts, SSIP is edge triggered and then cleared by software.
+    pub(crate) fn take_ssip(&self, hart: usize) -> bool {
+        self.ssip[hart].swap(false, Ordering::SeqCst)
+    }
+
+    /// Called after every instruction of hart 0
+    pub(crate) fn tick(&self) {
+        let steps = self.steps.load(Ordering::Relaxed) + 1;
+
+        match self.timebase {
+            Timebase::Instructions(n) if steps >= n => {
+                self.steps.store(0, Ordering::Relaxed);
+                self.mtime.fetch_add(1, Ordering::SeqCst);
+            },
+            Timebase::Host(_) if steps >= HOST_SYNC_STEPS => {
+                self.steps.store(0, Ordering::Relaxed);
+                self.mtime();
+            },
+            _ => self.steps.store(steps, Ordering::Relaxed),
+        }
+    }
+
+    fn host_ticks(&self, freq: u64) -> u64 {
+        (self.start.elapsed().as_nanos() * freq as u128 / 1_000_000_000) as u64
+    }
+
+    pub(crate) fn mtime(&self) -> u64 {
+        match self.timebase {
+            Timebase::Instructions(_) => self.mtime.load(Ordering::SeqCst),
+            Timebase::Host(f) => {
+                let t = self.host_ticks(f).wrapping_add(self.offset.load(Ordering::SeqCst));
+                self.mtime.store(t, Ordering::SeqCst);
+                t
+            },
+        }
+    }
+
+    fn set_mtime(&self, val: u64) {
+        if let Timebase::Host(f) = self.timebase {
+            self.offset.store(val.wrapping_sub(self.host_ticks(f)), Ordering::SeqCst);
         }
+
+        self.mtime.store(val, Ordering::SeqCst);
+    }
+
+    fn hart_of(&self, addr: u64, base: u64, stride: u64) -> Option<usize> {
+        let off = addr.checked_sub(base)?;
+        let hart = (off / stride) as usize;
+        (off % stride == 0 && hart < self.msip.len()).then_some(hart)
     }
 }
 
-impl Device for Clint {
-    fn load_u64(&mut self, addr: u64) -> Result<u64, Exception> {
-        Ok(match addr {
-            CLINT_MTIMECMP => self.mtimecmp,
-            CLINT_MTIME => self.mtime,
-            _ => 0,
-        })
+impl Clint {
+    fn load_reg64(&self, addr: u64) -> u64 {
+        match addr {
+            CLINT_MTIME => self.mtime(),
+            _ => match self.hart_of(addr, CLINT_MTIMECMP, 8) {
+                Some(h) => self.mtimecmp[h].load(Ordering::SeqCst),
+                None => 0,
+            },
+        }
+    }
+
+    /// `f` gets the old value, which lets 32-bit writes replace one half
+    fn store_reg64(&self, addr: u64, f: impl Fn(u64) -> u64) {
+        match addr {
+            CLINT_MTIME => self.set_mtime(f(self.mtime())),
+            _ => if let Some(h) = self.hart_of(addr, CLINT_MTIMECMP, 8) {
+                _ = self.mtimecmp[h].fetch_update(Ordering::SeqCst, Ordering::SeqCst, |v| Some(f(v)));
+            },
+        }
     }
+}
+
+impl SharedDevice for Clint {
+    fn load_u32(&self, addr: u64) -> Result<u32, Exception> {
+        if SSWI_RANGE.contains(&addr) {
+            return Ok(0);
+        }
 
-    fn store_u64(&mut self, addr: u64, val: u64) -> Result<(), Exception> {
-        Ok(match addr {
-            CLINT_MTIMECMP => self.mtimecmp = val,
-            CLINT_MTIME => self.mtime = val,
-            _ => {},
+        Ok(match self.hart_of(addr, CLINT_MSIP, 4) {
+            Some(h) => self.msip(h) as u32,
+            None => (self.load_reg64(addr & !7) >> ((addr & 4) * 8)) as u32,
         })
     }
+
+    fn store_u32(&self, addr: u64, val: u32) -> Result<(), Exception> {
+        if let Some(h) = self.hart_of(addr, SSWI_SETSSIP, 4) {
+            if val & 1 != 0 {
+                self.ssip[h].store(true, Ordering::SeqCst);
+            }
+        } else if let Some(h) = self.hart_of(addr, CLINT_MSIP, 4) {
+            self.msip[h].store(val & 1 != 0, Ordering::SeqCst);
+        } else {
+            let shift = (addr & 4) * 8;
+            self.store_reg64(addr & !7, |v| (v & !(0xffff_ffff << shift)) | (val as u64) << shift);
+        }
+
+        Ok(())
+    }
+
+    fn load_u64(&self, addr: u64) -> Result<u64, Exception> {
+        Ok(self.load_reg64(addr))
+    }
+
+    fn store_u64(&self, addr: u64, val: u64) -> Result<(), Exception> {
+        self.store_reg64(addr, |_| val);
+        Ok(())
+    }
 }
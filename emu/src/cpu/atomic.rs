@@ -1,31 +1,99 @@
 use super::*;
+use core::sync::atomic::{fence, Ordering};
+
+/// Longest constrained lr/sc loop, in instructions, that gets a forward progress guarantee
+pub(crate) const CONSTRAINED_LOOP: u64 = 16;
 
 macro_rules! gen {
-    ($t: tt $l: tt $s: tt $amo: tt $nl: tt $ns: tt $sz: tt) => {
+    ($t: tt $l: tt $s: tt $amo: tt $bamo: tt $nl: tt $sz: tt) => {
         impl<'a> Cpu<'a> {
-            pub(crate) fn $l(&mut self, a: u64, _aqrl: AqRlMode) -> Result<$t, Exception> {
-                self.amo_rs.aquire(a, $sz);
-                self.$nl(a)
+            pub(crate) fn $l(&mut self, a: u64, aqrl: AqRlMode) -> Result<$t, Exception> {
+                self.check_amo_align(a, $sz, Exception::LoadAddrMisalign)?;
+
+                let pa = self.mmu_translate(a, false)?;
+                self.order_atomic(pa, $sz, aqrl.rl);
+                self.bus.reserve(self.hartid, pa);
+
+                let v = self.$nl(a)?;
+                self.reservation = Some(Reservation { addr: a, size: $sz, value: v as u64, steps: 0 });
+                aqrl.fence();
+                Ok(v)
             }
 
-            pub(crate) fn $s(&mut self, a: u64, d: $t, _aqrl: AqRlMode) -> Result<(), ()> {
-                self.amo_rs.check_ownership(a, $sz)?;
-                self.amo_rs.length = 0;
-                self.$ns(a, d).map_err(|_| ())
+            /// Returns whether the store happened
+            pub(crate) fn $s(&mut self, a: u64, d: $t, aqrl: AqRlMode) -> Result<bool, Exception> {
+                self.check_amo_align(a, $sz, Exception::StoreAddrMisalign)?;
+
+                // an sc always ends the reservation, whether it succeeds or not
+                let r = self.reservation.take();
+                let pa = self.mmu_translate(a, true)?;
+                self.order_atomic(pa, $sz, aqrl.rl);
+                let valid = self.bus.reservation_valid(self.hartid, pa);
+                self.bus.clear_reservation(self.hartid);
+
+                let r = match r {
+                    Some(r) if valid && r.addr == a && r.size == $sz => r,
+                    _ => return Ok(false),
+                };
+
+                // a store that raced with the check above still changes the value
+                let expected = r.value as $t;
+                let old = self.bus.$bamo(pa, aqrl.ordering(), |v| (v == expected).then_some(d)).map_err(|e| {
+                    self.fault = TrapVal { tval: a, ..Default::default() };
+                    e
+                })?;
+
+                if old != expected {
+                    return Ok(false);
+                }
+
+                self.bus.break_reservations(self.hartid, pa, $sz);
+                Ok(true)
             }
 
-            pub(crate) fn $amo<T: Fn($t) -> $t>(&mut self, a: u64, _aqrl: AqRlMode, f: T) -> Result<$t, Exception> {
-                let b = self.$nl(a)?;
-                let v = f(b);
-                self.$ns(a, v)?;
-                Ok(b)
+            pub(crate) fn $amo<T: Fn($t) -> $t>(&mut self, a: u64, aqrl: AqRlMode, f: T) -> Result<$t, Exception> {
+                self.check_amo_align(a, $sz, Exception::StoreAddrMisalign)?;
+
+                let pa = self.mmu_translate(a, true)?;
+                self.order_atomic(pa, $sz, aqrl.rl);
+                let v = self.bus.$bamo(pa, aqrl.ordering(), |v| Some(f(v))).map_err(|e| {
+                    self.fault = TrapVal { tval: a, ..Default::default() };
+                    e
+                })?;
+
+                self.bus.break_reservations(self.hartid, pa, $sz);
+                Ok(v)
             }
         }
     };
 }
 
-gen!(u32 atomic_load_u32 atomic_store_u32 atomic_mo_u32 mmu_load_u32 mmu_store_u32 1);
-gen!(u64 atomic_load_u64 atomic_store_u64 atomic_mo_u64 mmu_load_u64 mmu_store_u64 2);
+gen!(u32 atomic_load_u32 atomic_store_u32 atomic_mo_u32 amo_u32 mmu_load_u32 4);
+gen!(u64 atomic_load_u64 atomic_store_u64 atomic_mo_u64 amo_u64 mmu_load_u64 8);
+
+impl<'a> Cpu<'a> {
+    /// Atomics have no misaligned support (no Zam), so they always raise the misaligned exception
+    fn check_amo_align(&mut self, a: u64, size: u64, ex: Exception) -> Result<(), Exception> {
+        if !a.is_multiple_of(size) {
+            self.fault = TrapVal { tval: a, ..Default::default() };
+            return Err(ex);
+        }
+
+        Ok(())
+    }
+
+    /// Drops the reservation of this hart, on an sc, a trap or an xret
+    pub(crate) fn clear_reservation(&mut self) {
+        self.reservation = None;
+        self.bus.clear_reservation(self.hartid);
+    }
+
+    /// Whether this hart is possibly inside a constrained lr/sc loop, which the scheduler should not
+    /// interrupt to guarantee forward progress
+    pub fn in_lrsc_loop(&self) -> bool {
+        self.reservation.as_ref().is_some_and(|r| r.steps < CONSTRAINED_LOOP)
+    }
+}
 
 pub(crate) struct AqRlMode {
     pub aq: bool,
@@ -39,43 +107,31 @@ impl AqRlMode {
             rl: b & 1 != 0,
         }
     }
-}
-
-const SET_SIZE: usize = 16;
 
-pub(crate) struct ReservationSet {
-    set: [u64; SET_SIZE],
-    length: usize,
-}
-
-impl ReservationSet {
-    pub(crate) fn new() -> Self {
-        Self {
-            set: [0; 16],
-            length: 0,
+    /// Host memory ordering for the atomic access, for when harts run on separate threads
+    fn ordering(&self) -> Ordering {
+        match (self.aq, self.rl) {
+            (true, true) => Ordering::SeqCst,
+            (true, false) => Ordering::Acquire,
+            (false, true) => Ordering::Release,
+            (false, false) => Ordering::Relaxed,
         }
     }
 
-    fn aquire_4(&mut self, addr: u64) {
-        self.set[self.length] = addr;
-        self.length = (self.length + 1) % SET_SIZE;
-    }
-
-    fn aquire(&mut self, addr: u64, sz: usize) {
-        for i in 0..sz {
-            self.aquire_4(addr + i as u64 * 4)
+    /// Orders the plain load of an `lr` after it
+    fn fence(&self) {
+        if self.aq {
+            fence(Ordering::Acquire);
         }
     }
+}
 
-    fn check_ownership_4(&mut self, addr: u64) -> Result<(), ()> {
-        self.set[..self.length].contains(&addr).then_some(()).ok_or(())
-    }
-
-    fn check_ownership(&mut self, addr: u64, sz: usize) -> Result<(), ()> {
-        for i in 0..sz {
-            self.check_ownership_4(addr + i as u64 * 4)?;
-        }
-
-        Ok(())
-    }
+/// The reservation of the last `lr`, the reservation set itself is tracked by the bus
+pub(crate) struct Reservation {
+    addr: u64,
+    size: u64,
+    /// Value loaded by the `lr`
+    value: u64,
+    /// Instructions executed since the `lr`
+    pub(crate) steps: u64,
 }
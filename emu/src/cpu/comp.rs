@@ -141,7 +141,7 @@ impl Cpu<'_> {
                     | ((inst & 0x100) << 2) as i16 // [10]
                     | ((inst as i16 & 0x1000) << 3 >> 4); // [11]
                 let imm = imm as u32;
-                Ok(0x0000006f_u32 | ((imm & 0x7fe) << 20) | ((imm & 0x800) << 9) | (imm & 0xff000) | ((imm & 0x10000) << 11))
+                Ok(0x0000006f_u32 | ((imm & 0x7fe) << 20) | ((imm & 0x800) << 9) | (imm & 0xff000) | ((imm & 0x100000) << 11))
             },
             (1, 6) => decode!(cb |r1, imm| Ok(b(0x00000063_u32, r1, imm))),
             (1, 7) => decode!(cb |r1, imm| Ok(b(0x00001063_u32, r1, imm))),
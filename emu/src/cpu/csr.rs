@@ -1,5 +1,8 @@
 use super::*;
 
+/// rv64imafdch_su (Z extensions are not in here)
+pub(crate) const MISA: u64 = 0x8000_0000_0014_11ad;
+
 // floating point csrs
 pub(crate) const CSR_FFLAGS: u64 = 0x001;
 pub(crate) const CSR_FRM: u64 = 0x002;
@@ -20,6 +23,7 @@ pub(crate) const CSR_SEPC: u64 = 0x141;
 pub(crate) const CSR_SCAUSE: u64 = 0x142;
 pub(crate) const CSR_STVAL: u64 = 0x143;
 pub(crate) const CSR_SIP: u64 = 0x144;
+pub(crate) const CSR_STIMECMP: u64 = 0x14d;
 pub(crate) const CSR_SCOUNTOVF: u64 = 0xda0;
 // supervisor protection & translation
 pub(crate) const CSR_SATP: u64 = 0x180;
@@ -31,6 +35,37 @@ pub(crate) const CSR_SSTATEEN1: u64 = 0x10d;
 pub(crate) const CSR_SSTATEEN2: u64 = 0x10e;
 pub(crate) const CSR_SSTATEEN3: u64 = 0x10f;
 
+// hypervisor trap setup
+pub(crate) const CSR_HSTATUS: u64 = 0x600;
+pub(crate) const CSR_HEDELEG: u64 = 0x602;
+pub(crate) const CSR_HIDELEG: u64 = 0x603;
+pub(crate) const CSR_HIE: u64 = 0x604;
+pub(crate) const CSR_HCOUNTEREN: u64 = 0x606;
+pub(crate) const CSR_HGEIE: u64 = 0x607;
+// hypervisor trap handling
+pub(crate) const CSR_HTVAL: u64 = 0x643;
+pub(crate) const CSR_HIP: u64 = 0x644;
+pub(crate) const CSR_HVIP: u64 = 0x645;
+pub(crate) const CSR_HTINST: u64 = 0x64a;
+pub(crate) const CSR_HGEIP: u64 = 0xe12;
+// hypervisor protection & translation
+pub(crate) const CSR_HGATP: u64 = 0x680;
+// hypervisor counter/timer virtualization
+pub(crate) const CSR_HTIMEDELTA: u64 = 0x605;
+// virtual supervisor registers
+pub(crate) const CSR_VSSTATUS: u64 = 0x200;
+pub(crate) const CSR_VSIE: u64 = 0x204;
+pub(crate) const CSR_VSTVEC: u64 = 0x205;
+pub(crate) const CSR_VSSCRATCH: u64 = 0x240;
+pub(crate) const CSR_VSEPC: u64 = 0x241;
+pub(crate) const CSR_VSCAUSE: u64 = 0x242;
+pub(crate) const CSR_VSTVAL: u64 = 0x243;
+pub(crate) const CSR_VSIP: u64 = 0x244;
+pub(crate) const CSR_VSATP: u64 = 0x280;
+
+// unprivileged counter/timers
+pub(crate) const CSR_TIME: u64 = 0xc01;
+
 // machine info
 pub(crate) const CSR_MVENDORID: u64 = 0xf11;
 pub(crate) const CSR_MARCHID: u64 = 0xf12;
@@ -45,21 +80,38 @@ pub(crate) const CSR_MIDELEG: u64 = 0x303;
 pub(crate) const CSR_MIE: u64 = 0x304;
 pub(crate) const CSR_MTVEC: u64 = 0x305;
 pub(crate) const CSR_MCOUNTEREN: u64 = 0x306;
+// machine configuration
+pub(crate) const CSR_MENVCFG: u64 = 0x30a;
 // machine trap handling
 pub(crate) const CSR_MSCRATCH: u64 = 0x340;
 pub(crate) const CSR_MEPC: u64 = 0x341;
 pub(crate) const CSR_MCAUSE: u64 = 0x342;
 pub(crate) const CSR_MTVAL: u64 = 0x343;
 pub(crate) const CSR_MIP: u64 = 0x344;
-pub(crate) const CSR_MTINST: u64 = 0x345;
-pub(crate) const CSR_MTVAL2: u64 = 0x346;
+pub(crate) const CSR_MTINST: u64 = 0x34a;
+pub(crate) const CSR_MTVAL2: u64 = 0x34b;
 
 const MSTAT_S_MASK: u64 = 0x8000_0003_000f_e7e2;
 const MSTAT_W_MASK: u64 = 0x7fff_ffc0_fff6_79bf;
 
+// gva, spv, spvp, hu, vtvm, vtw, vtsr
+const HSTAT_W_MASK: u64 = 0x0000_0000_0070_03c0;
+const HEDELEG_W_MASK: u64 = 0xb1ff;
+
+/// VS-level interrupts (VSSIP, VSTIP, VSEIP)
+pub(crate) const VS_INTS: u64 = 0x444;
+/// VS-level interrupts and SGEIP, all of which are read-only one in `mideleg`
+pub(crate) const H_INTS: u64 = 0x1444;
+/// S-level interrupts (SSIP, STIP, SEIP)
+pub(crate) const S_INTS: u64 = 0x222;
+/// `menvcfg.STCE`, STIP follows `stimecmp` instead of being written by M-mode (Sstc)
+pub(crate) const MENVCFG_STCE: u64 = 1 << 63;
+
 impl<'a> Cpu<'a> {
     pub(crate) fn csr_init(&mut self) {
         self.csrs[CSR_MSTATUS as usize] = 0x0000_000a_0000_2000;
+        self.csrs[CSR_HSTATUS as usize] = 0x0000_0002_0000_0000;
+        self.csrs[CSR_VSSTATUS as usize] = 0x0000_0002_0000_0000;
     }
 
     pub(crate) fn csr_read_cpu(&self, a: u64) -> u64 {
@@ -67,18 +119,17 @@ impl<'a> Cpu<'a> {
     }
 
     pub(crate) fn csr_read(&self, a: u64) -> Result<u64, Exception> {
-        println!("csrr {a:03x}");
+        // println!("csrr {a:03x}");
         self._csr_read(a, true)
     }
 
     fn _csr_read(&self, a: u64, err: bool) -> Result<u64, Exception> {
-        let a = a & 4095;
-        self.check_csr_perm(a, err)?;
+        let a = self.check_csr_perm(a & 4095, err)?;
 
         Ok(match a {
-            CSR_MISA => 0x8000000000141125, // rv64imafdc_su (Z extensions are not in here)
-            CSR_MHARTID => 0,
-            CSR_MSTATUS => {
+            CSR_MISA => MISA,
+            CSR_MHARTID => self.hartid as _,
+            CSR_MSTATUS | CSR_VSSTATUS => {
                 let mut s = self.csrs[a as usize];
                 s |= (((s >> 13) & 3 == 3) as u64) << 63;
                 s
@@ -91,6 +142,37 @@ impl<'a> Cpu<'a> {
 
                 self.csrs[a as usize]
             }
+            CSR_VSATP => {
+                if err && self.virt && (self.csr_read_cpu(CSR_HSTATUS) >> 20) & 1 == 1 {
+                    return Err(Exception::VirtualInst);
+                }
+
+                self.csrs[a as usize]
+            },
+            CSR_MIDELEG => self.csrs[a as usize] | H_INTS,
+            CSR_MIP => self.csrs[a as usize] | (self.csrs[CSR_HVIP as usize] & VS_INTS),
+            CSR_SIP => self.csr_read_cpu(CSR_MIP) & self.csr_read_cpu(CSR_MIDELEG) & S_INTS,
+            CSR_SIE => self.csrs[CSR_MIE as usize] & self.csr_read_cpu(CSR_MIDELEG) & S_INTS,
+            CSR_HIP => self.csr_read_cpu(CSR_MIP) & H_INTS,
+            CSR_HIE => self.csrs[CSR_MIE as usize] & H_INTS,
+            CSR_VSIP => (self.csr_read_cpu(CSR_MIP) & self.csrs[CSR_HIDELEG as usize]) >> 1,
+            CSR_VSIE => (self.csrs[CSR_MIE as usize] & self.csrs[CSR_HIDELEG as usize]) >> 1,
+            CSR_HGEIP | CSR_HGEIE => 0,
+            CSR_TIME => {
+                if err {
+                    self.check_counter(2)?; // TM
+                }
+
+                let t = self.bus.mtime();
+                if self.virt { t.wrapping_add(self.csrs[CSR_HTIMEDELTA as usize]) } else { t }
+            },
+            CSR_STIMECMP => {
+                if err {
+                    self.check_stimecmp()?;
+                }
+
+                self.csrs[a as usize]
+            },
             CSR_FFLAGS => self.csr_read_cpu(CSR_FCSR) & 0x1f,
             CSR_FRM => (self.csr_read_cpu(CSR_FCSR) >> 5) & 7,
             0x7a0 | 0x7a5 => 1, // throw off debug mode tests
@@ -103,13 +185,12 @@ impl<'a> Cpu<'a> {
     }
 
     pub(crate) fn csr_write(&mut self, a: u64, d: u64) -> Result<(), Exception> {
-        println!("csrw {a:03x} {d:016x}");
+        // println!("csrw {a:03x} {d:016x}");
         self._csr_write(a, d, true)
     }
 
     fn _csr_write(&mut self, a: u64, d: u64, err: bool) -> Result<(), Exception> {
-        let a = a & 4095;
-        self.check_csr_perm(a, err)?;
+        let a = self.check_csr_perm(a & 4095, err)?;
 
         if a >> 10 == 3 {
             return Err(Exception::IllegalInst);
@@ -129,6 +210,56 @@ impl<'a> Cpu<'a> {
                 mstat |= d & MASK;
                 self.csr_write_cpu(CSR_MSTATUS, mstat);
             },
+            CSR_VSSTATUS => {
+                const MASK: u64 = MSTAT_S_MASK & MSTAT_W_MASK;
+
+                self.csrs[a as usize] &= !MASK;
+                self.csrs[a as usize] |= d & MASK;
+            },
+            CSR_HSTATUS => {
+                self.csrs[a as usize] &= !HSTAT_W_MASK;
+                self.csrs[a as usize] |= d & HSTAT_W_MASK;
+            },
+            CSR_HEDELEG => self.csrs[a as usize] = d & HEDELEG_W_MASK,
+            CSR_HIDELEG | CSR_HVIP => self.csrs[a as usize] = d & VS_INTS,
+            CSR_MIP => {
+                self.set_masked(CSR_MIP, !(H_INTS | bus::HW_INTS), d);
+                self.set_masked(CSR_HVIP, 0x4, d);
+            },
+            CSR_HIP => self.set_masked(CSR_HVIP, 0x4, d),
+            CSR_VSIP => {
+                let mask = self.csrs[CSR_HIDELEG as usize] & 0x4;
+                self.set_masked(CSR_HVIP, mask, d << 1);
+            },
+            CSR_SIP => {
+                let mask = self.csr_read_cpu(CSR_MIDELEG) & 0x2;
+                self.set_masked(CSR_MIP, mask, d);
+            },
+            CSR_SIE => {
+                let mask = self.csr_read_cpu(CSR_MIDELEG) & S_INTS;
+                self.set_masked(CSR_MIE, mask, d);
+            },
+            CSR_HIE => self.set_masked(CSR_MIE, VS_INTS, d),
+            CSR_VSIE => {
+                let mask = self.csrs[CSR_HIDELEG as usize];
+                self.set_masked(CSR_MIE, mask, d << 1);
+            },
+            CSR_HGEIE => {},
+            CSR_HGATP => {
+                // sv39x4 and sv48x4 only, no vmid bits
+                if matches!(d >> 60, 0 | 8 | 9) {
+                    self.csrs[a as usize] = d & 0xf000_0fff_ffff_fffc;
+                    self.flush_g_mapping();
+                }
+            },
+            CSR_VSATP => {
+                if err && self.virt && (self.csr_read_cpu(CSR_HSTATUS) >> 20) & 1 == 1 {
+                    return Err(Exception::VirtualInst);
+                }
+
+                self.csrs[a as usize] = d;
+                self.flush_vs_mapping();
+            },
             CSR_SATP => {
                 if err && (self.csr_read_cpu(CSR_MSTATUS) >> 20) & 1 == 1 && self.mode == Mode::Supervisor {
                     return Err(Exception::IllegalInst);
@@ -136,6 +267,13 @@ impl<'a> Cpu<'a> {
 
                 self.csrs[a as usize] = d;
             },
+            CSR_STIMECMP => {
+                if err {
+                    self.check_stimecmp()?;
+                }
+
+                self.csrs[a as usize] = d;
+            },
             CSR_FCSR => {
                 self.mut_fp_state();
                 self.csrs[a as usize] = d & 0xff;
@@ -158,11 +296,78 @@ impl<'a> Cpu<'a> {
         Ok(())
     }
 
-    fn check_csr_perm(&self, a: u64, err: bool) -> Result<(), Exception> {
-        if err && (a >> 8) & 3 > self.mode as _ {
+    fn set_masked(&mut self, a: u64, mask: u64, d: u64) {
+        self.csrs[a as usize] &= !mask;
+        self.csrs[a as usize] |= d & mask;
+    }
+
+    /// Checks the `counteren` bit of a counter for the current mode
+    fn check_counter(&self, bit: u64) -> Result<(), Exception> {
+        if self.mode < Mode::Machine && self.csrs[CSR_MCOUNTEREN as usize] & bit == 0 {
+            return Err(Exception::IllegalInst);
+        }
+
+        if self.virt && self.csrs[CSR_HCOUNTEREN as usize] & bit == 0 {
+            return Err(Exception::VirtualInst);
+        }
+
+        if self.mode == Mode::User && self.csrs[CSR_SCOUNTEREN as usize] & bit == 0 {
+            return Err(if self.virt { Exception::VirtualInst } else { Exception::IllegalInst });
+        }
+
+        Ok(())
+    }
+
+    /// `stimecmp` is there for S-mode with both `menvcfg.STCE` and `mcounteren.TM` set. There is
+    /// no `vstimecmp`.
+    fn check_stimecmp(&self) -> Result<(), Exception> {
+        if self.virt {
+            return Err(Exception::VirtualInst);
+        }
+
+        if self.mode < Mode::Machine && (self.csrs[CSR_MENVCFG as usize] & MENVCFG_STCE == 0 || self.csrs[CSR_MCOUNTEREN as usize] & 2 == 0) {
             return Err(Exception::IllegalInst);
         }
 
         Ok(())
     }
+
+    /// Checks if the current mode can access a CSR, and returns the address of the CSR that should
+    /// actually be accessed (S-level CSRs are swapped for their VS counterpart when V=1).
+    fn check_csr_perm(&self, a: u64, err: bool) -> Result<u64, Exception> {
+        if !err {
+            return Ok(a);
+        }
+
+        let level = (a >> 8) & 3;
+
+        if self.virt {
+            return match level {
+                3 => Err(Exception::IllegalInst),
+                2 => Err(Exception::VirtualInst),
+                1 if self.mode == Mode::User => Err(Exception::VirtualInst),
+                1 => Ok(match a {
+                    CSR_SSTATUS => CSR_VSSTATUS,
+                    CSR_SIE => CSR_VSIE,
+                    CSR_STVEC => CSR_VSTVEC,
+                    CSR_SSCRATCH => CSR_VSSCRATCH,
+                    CSR_SEPC => CSR_VSEPC,
+                    CSR_SCAUSE => CSR_VSCAUSE,
+                    CSR_STVAL => CSR_VSTVAL,
+                    CSR_SIP => CSR_VSIP,
+                    CSR_SATP => CSR_VSATP,
+                    _ => a,
+                }),
+                _ => Ok(a),
+            };
+        }
+
+        // hypervisor csrs are accessible from hs-mode
+        let level = if level == 2 { Mode::Supervisor as _ } else { level };
+        if level > self.mode as _ {
+            return Err(Exception::IllegalInst);
+        }
+
+        Ok(a)
+    }
 }
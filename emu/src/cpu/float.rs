@@ -12,12 +12,19 @@ pub(crate) const F64_CNAN: u64 = 0x7ff8_0000_0000_0000;
 impl<'a> Cpu<'a> {
     pub(crate) fn can_use_fp(&self) -> bool {
         (self.csr_read_cpu(csr::CSR_MSTATUS) >> 13) & 3 != 0
+            && (!self.virt || (self.csr_read_cpu(csr::CSR_VSSTATUS) >> 13) & 3 != 0)
     }
 
     pub(crate) fn mut_fp_state(&mut self) {
         let mut ms = self.csr_read_cpu(csr::CSR_MSTATUS);
         ms |= 0x6000;
         self.csr_write_cpu(csr::CSR_MSTATUS, ms);
+
+        if self.virt {
+            let mut vs = self.csr_read_cpu(csr::CSR_VSSTATUS);
+            vs |= 0x6000;
+            self.csr_write_cpu(csr::CSR_VSSTATUS, vs);
+        }
     }
 
     fn read_float_reg(&self, n: usize) -> u64 { self.float_regs[n] }
@@ -58,7 +65,7 @@ impl<'a> Cpu<'a> {
                 | (fenv::fetestexcept(fenv::FE_OVERFLOW as _) != 0) as u64 * OF
                 | (fenv::fetestexcept(fenv::FE_UNDERFLOW as _) != 0) as u64 * UF;
             self.float_set_flags(f);
-            println!("{f:02x}");
+            // println!("{f:02x}");
         }
     }
 
@@ -0,0 +1,59 @@
+//! Hypervisor extension instructions
+
+use super::*;
+
+impl<'a> Cpu<'a> {
+    /// `hlv`, `hlvx` and `hsv` are legal in hs-mode, and in u-mode when `hstatus.hu` is set
+    pub(crate) fn check_hlsv(&self) -> Result<(), Exception> {
+        if self.virt {
+            Err(Exception::VirtualInst)
+        } else if self.mode == Mode::User && (self.csr_read_cpu(csr::CSR_HSTATUS) >> 9) & 1 == 0 {
+            Err(Exception::IllegalInst)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn hfence_vvma(&mut self) -> Result<(), Exception> {
+        if self.virt {
+            return Err(Exception::VirtualInst);
+        }
+
+        self.flush_vs_mapping();
+        Ok(())
+    }
+
+    pub(crate) fn hfence_gvma(&mut self) -> Result<(), Exception> {
+        if self.virt {
+            return Err(Exception::VirtualInst);
+        }
+
+        if self.mode == Mode::Supervisor && (self.csr_read_cpu(csr::CSR_MSTATUS) >> 20) & 1 == 1 {
+            return Err(Exception::IllegalInst);
+        }
+
+        self.flush_g_mapping();
+        Ok(())
+    }
+}
+
+macro_rules! gen {
+    ($t: tt $ra: tt $wa: tt $hl: tt $hs: tt) => {
+        impl Cpu<'_> {
+            pub(crate) fn $hl(&mut self, a: u64, hlvx: bool) -> Result<$t, Exception> {
+                self.check_hlsv()?;
+                self.$ra(a, self.hyper_access(false, hlvx))
+            }
+
+            pub(crate) fn $hs(&mut self, a: u64, d: $t) -> Result<(), Exception> {
+                self.check_hlsv()?;
+                self.$wa(a, d, self.hyper_access(true, false))
+            }
+        }
+    };
+}
+
+gen!(u8 mmu_load_u8_as mmu_store_u8_as hyper_load_u8 hyper_store_u8);
+gen!(u16 mmu_load_u16_as mmu_store_u16_as hyper_load_u16 hyper_store_u16);
+gen!(u32 mmu_load_u32_as mmu_store_u32_as hyper_load_u32 hyper_store_u32);
+gen!(u64 mmu_load_u64_as mmu_store_u64_as hyper_load_u64 hyper_store_u64);
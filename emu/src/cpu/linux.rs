@@ -0,0 +1,959 @@
+//! Linux user-mode emulation, for running static riscv64 Linux programs like `qemu-riscv64` does.
+//! The hart stays in U-mode, the address space is a map of address ranges kept here instead of page
+//! tables, backed by RAM pages handed out on first touch, and `ecall`s are system calls done by the
+//! host.
+//! File descriptors are the host's. There are no signal handlers: a fault, or a signal sent to
+//! itself, ends the program like an uncaught signal does.
+
+use super::*;
+use crate::elf::Elf;
+use crate::syscon::Exit;
+use mmu::{Fault, PERM_R, PERM_W, PERM_X};
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::io::{Error, ErrorKind};
+use std::os::unix::fs::FileExt;
+use std::sync::Arc;
+
+const PAGE_SIZE: u64 = 0x1000;
+
+/// Top of the stack, which takes the top of what Sv39 gives user space
+const STACK_TOP: u64 = 0x40_0000_0000;
+const STACK_SIZE: u64 = 0x80_0000;
+/// Where `mmap` places mappings without a usable hint, growing up towards the stack
+const MMAP_BASE: u64 = 0x20_0000_0000;
+
+/// Largest read or write done at once, a short count is fine for the program
+const MAX_IO: usize = 1 << 20;
+const PATH_MAX: usize = 4096;
+
+// system calls of the generic Linux ABI that riscv64 uses
+const SYS_GETCWD: u64 = 17;
+const SYS_DUP: u64 = 23;
+const SYS_DUP3: u64 = 24;
+const SYS_FCNTL: u64 = 25;
+const SYS_IOCTL: u64 = 29;
+const SYS_MKDIRAT: u64 = 34;
+const SYS_UNLINKAT: u64 = 35;
+const SYS_FACCESSAT: u64 = 48;
+const SYS_CHDIR: u64 = 49;
+const SYS_OPENAT: u64 = 56;
+const SYS_CLOSE: u64 = 57;
+const SYS_PIPE2: u64 = 59;
+const SYS_GETDENTS64: u64 = 61;
+const SYS_LSEEK: u64 = 62;
+const SYS_READ: u64 = 63;
+const SYS_WRITE: u64 = 64;
+const SYS_READV: u64 = 65;
+const SYS_WRITEV: u64 = 66;
+const SYS_PREAD64: u64 = 67;
+const SYS_PWRITE64: u64 = 68;
+const SYS_READLINKAT: u64 = 78;
+const SYS_NEWFSTATAT: u64 = 79;
+const SYS_FSTAT: u64 = 80;
+const SYS_EXIT: u64 = 93;
+const SYS_EXIT_GROUP: u64 = 94;
+const SYS_SET_TID_ADDRESS: u64 = 96;
+const SYS_FUTEX: u64 = 98;
+const SYS_SET_ROBUST_LIST: u64 = 99;
+const SYS_NANOSLEEP: u64 = 101;
+const SYS_CLOCK_GETTIME: u64 = 113;
+const SYS_CLOCK_GETRES: u64 = 114;
+const SYS_CLOCK_NANOSLEEP: u64 = 115;
+const SYS_SCHED_YIELD: u64 = 124;
+const SYS_KILL: u64 = 129;
+const SYS_TKILL: u64 = 130;
+const SYS_TGKILL: u64 = 131;
+const SYS_SIGALTSTACK: u64 = 132;
+const SYS_RT_SIGACTION: u64 = 134;
+const SYS_RT_SIGPROCMASK: u64 = 135;
+const SYS_UNAME: u64 = 160;
+const SYS_GETRLIMIT: u64 = 163;
+const SYS_GETTIMEOFDAY: u64 = 169;
+const SYS_GETPID: u64 = 172;
+const SYS_GETPPID: u64 = 173;
+const SYS_GETUID: u64 = 174;
+const SYS_GETEUID: u64 = 175;
+const SYS_GETGID: u64 = 176;
+const SYS_GETEGID: u64 = 177;
+const SYS_GETTID: u64 = 178;
+const SYS_BRK: u64 = 214;
+const SYS_MUNMAP: u64 = 215;
+const SYS_MREMAP: u64 = 216;
+const SYS_MMAP: u64 = 222;
+const SYS_MPROTECT: u64 = 226;
+const SYS_MADVISE: u64 = 233;
+const SYS_RISCV_FLUSH_ICACHE: u64 = 259;
+const SYS_PRLIMIT64: u64 = 261;
+const SYS_RENAMEAT2: u64 = 276;
+const SYS_GETRANDOM: u64 = 278;
+const SYS_STATX: u64 = 291;
+const SYS_FACCESSAT2: u64 = 439;
+
+// auxiliary vector
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_BASE: u64 = 7;
+const AT_ENTRY: u64 = 9;
+const AT_UID: u64 = 11;
+const AT_EUID: u64 = 12;
+const AT_GID: u64 = 13;
+const AT_EGID: u64 = 14;
+const AT_HWCAP: u64 = 16;
+const AT_CLKTCK: u64 = 17;
+const AT_SECURE: u64 = 23;
+const AT_RANDOM: u64 = 25;
+const AT_EXECFN: u64 = 31;
+
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+const MAP_FIXED_NOREPLACE: u64 = 0x10_0000;
+const MADV_DONTNEED: u64 = 4;
+
+const TCGETS: u64 = 0x5401;
+const TCSETS: u64 = 0x5402;
+const TCSETSF: u64 = 0x5404;
+const TIOCGWINSZ: u64 = 0x5413;
+/// Size of the kernel `struct termios`
+const TERMIOS_SIZE: usize = 36;
+
+const F_DUPFD: u64 = 0;
+const F_GETFD: u64 = 1;
+const F_SETFD: u64 = 2;
+const F_GETFL: u64 = 3;
+const F_SETFL: u64 = 4;
+const F_DUPFD_CLOEXEC: u64 = 1030;
+
+/// Open flags that some hosts number differently from the generic ABI, the others are the same on
+/// every host
+const OPEN_FLAGS: [(u64, i32); 4] =
+    [(0o40000, libc::O_DIRECT), (0o100000, libc::O_LARGEFILE), (0o200000, libc::O_DIRECTORY), (0o400000, libc::O_NOFOLLOW)];
+
+fn host_open_flags(flags: u64) -> i32 {
+    let mut host = (flags & !OPEN_FLAGS.iter().fold(0, |m, f| m | f.0)) as i32;
+    for (generic, h) in OPEN_FLAGS {
+        if flags & generic != 0 {
+            host |= h;
+        }
+    }
+    host
+}
+
+fn guest_open_flags(host: i32) -> u64 {
+    let mut flags = (host & !OPEN_FLAGS.iter().fold(0, |m, f| m | f.1)) as u64;
+    for (generic, h) in OPEN_FLAGS {
+        if h != 0 && host & h == h {
+            flags |= generic;
+        }
+    }
+    flags
+}
+
+/// Errors are Linux errnos, which the host has too
+fn errno() -> u32 {
+    Error::last_os_error().raw_os_error().unwrap_or(libc::EIO) as u32
+}
+
+/// Result of a host call that returns -1 and sets errno on failure
+fn host(ret: i64) -> Result<u64, u32> {
+    if ret < 0 { Err(errno()) } else { Ok(ret as u64) }
+}
+
+/// Two 64-bit fields, like a `struct timespec`, `struct timeval` or `struct rlimit`
+fn pair_bytes(a: u64, b: u64) -> [u8; 16] {
+    let mut out = [0; 16];
+    out[..8].copy_from_slice(&a.to_le_bytes());
+    out[8..].copy_from_slice(&b.to_le_bytes());
+    out
+}
+
+/// The generic `struct stat` of riscv64, which hosts lay out their own way
+fn stat_bytes(st: &libc::stat) -> [u8; 128] {
+    let fields: [(usize, u64, usize); 17] = [
+        (0, st.st_dev as _, 8),
+        (8, st.st_ino as _, 8),
+        (16, st.st_mode as _, 4),
+        (20, st.st_nlink as _, 4),
+        (24, st.st_uid as _, 4),
+        (28, st.st_gid as _, 4),
+        (32, st.st_rdev as _, 8),
+        (48, st.st_size as _, 8),
+        (56, st.st_blksize as _, 4),
+        (64, st.st_blocks as _, 8),
+        (72, st.st_atime as _, 8),
+        (80, st.st_atime_nsec as _, 8),
+        (88, st.st_mtime as _, 8),
+        (96, st.st_mtime_nsec as _, 8),
+        (104, st.st_ctime as _, 8),
+        (112, st.st_ctime_nsec as _, 8),
+        (120, 0, 8),
+    ];
+
+    let mut b = [0; 128];
+    for (off, v, size) in fields {
+        b[off..off + size].copy_from_slice(&v.to_le_bytes()[..size]);
+    }
+    b
+}
+
+/// A mapped range of the address space, RAM for its pages is only taken when they are first touched
+#[derive(Debug, Clone)]
+struct Vma {
+    end: u64,
+    /// `PERM_R`, `PERM_W` and `PERM_X`, none for `PROT_NONE`
+    perm: u64,
+    /// The file and the offset in it of the start, which the pages are read from as they are
+    /// touched. Changes are not written back, even for shared mappings.
+    file: Option<(Arc<std::fs::File>, u64)>,
+}
+
+/// The address space of the program and what the system calls keep track of
+pub(crate) struct Process {
+    /// Mapped ranges by their start, they never overlap
+    vmas: BTreeMap<u64, Vma>,
+    /// RAM pages of the touched pages, by the address of the page
+    frames: BTreeMap<u64, u64>,
+    /// Zeroed RAM pages given back by `munmap`
+    free: Vec<u64>,
+    /// RAM from here up was never handed out
+    next_frame: u64,
+    /// The heap is from the end of the program to the break
+    heap: u64,
+    brk: u64,
+    mmap_next: u64,
+    /// What `/proc/self/exe` links to
+    exe: String,
+}
+
+impl Process {
+    /// The range `a` is in
+    fn vma(&self, a: u64) -> Option<(u64, &Vma)> {
+        self.vmas.range(..=a).next_back().filter(|(_, v)| a < v.end).map(|(s, v)| (*s, v))
+    }
+
+    /// The RAM page behind the page at `page`, if it is mapped with at least `perm`. A page of a
+    /// file is read in the first time.
+    fn frame(&mut self, bus: &bus::Bus, page: u64, perm: u64) -> Option<u64> {
+        let (start, vma) = self.vma(page).filter(|(_, v)| v.perm & perm == perm)?;
+        if let Some(frame) = self.frames.get(&page) {
+            return Some(*frame);
+        }
+
+        let file = vma.file.as_ref().map(|(f, off)| (Arc::clone(f), off + (page - start)));
+        let frame = match self.free.pop() {
+            Some(f) => f,
+            None if self.next_frame < bus::RAM_BASE + bus::RAM_SIZE => {
+                self.next_frame += PAGE_SIZE;
+                self.next_frame - PAGE_SIZE
+            },
+            None => return None,
+        };
+
+        // past the end of the file is zeros, like the rest of a fresh page
+        if let Some((f, off)) = file {
+            let mut data = [0; PAGE_SIZE as usize];
+            let mut at = 0;
+            while at < data.len() {
+                match f.read_at(&mut data[at..], off + at as u64) {
+                    Ok(0) => break,
+                    Ok(n) => at += n,
+                    Err(e) if e.kind() == ErrorKind::Interrupted => {},
+                    Err(_) => break,
+                }
+            }
+            _ = bus.dma_write(frame, &data[..at]);
+        }
+
+        self.frames.insert(page, frame);
+        Some(frame)
+    }
+
+    /// Makes `at` the start of a range if it is in the middle of one
+    fn split(&mut self, at: u64) {
+        let Some((start, vma)) = self.vma(at).filter(|(s, _)| *s != at) else { return };
+        let mut tail = vma.clone();
+        if let Some((_, off)) = &mut tail.file {
+            *off += at - start;
+        }
+
+        self.vmas.get_mut(&start).unwrap().end = at;
+        self.vmas.insert(at, tail);
+    }
+
+    /// The ranges from `start` to `end`, after splitting the ones that stick out of it
+    fn vmas_in(&mut self, start: u64, end: u64) -> impl Iterator<Item = (&u64, &mut Vma)> {
+        self.split(start);
+        self.split(end);
+        self.vmas.range_mut(start..end)
+    }
+
+    /// Maps the pages from `start` to `end`, and adds `perm` to those that already are
+    fn map(&mut self, start: u64, end: u64, perm: u64) {
+        let mut gaps = Vec::new();
+        let mut at = start;
+        for (s, v) in self.vmas_in(start, end) {
+            v.perm |= perm;
+            if at < *s {
+                gaps.push((at, *s));
+            }
+            at = v.end;
+        }
+        if at < end {
+            gaps.push((at, end));
+        }
+
+        for (s, e) in gaps {
+            self.vmas.insert(s, Vma { end: e, perm, file: None });
+        }
+    }
+
+    /// Whether no page from `start` to `end` is mapped, and the range is below the stack
+    fn is_free(&self, start: u64, end: u64) -> bool {
+        start < end && end <= STACK_TOP - STACK_SIZE && self.vmas.range(..end).next_back().is_none_or(|(_, v)| v.end <= start)
+    }
+
+    /// Whether every page from `start` to `end` is mapped
+    fn is_mapped(&self, start: u64, end: u64) -> bool {
+        let mut at = start;
+        while at < end {
+            match self.vma(at) {
+                Some((_, v)) => at = v.end,
+                None => return false,
+            }
+        }
+        true
+    }
+}
+
+fn page_up(a: u64) -> Option<u64> {
+    a.checked_add(PAGE_SIZE - 1).map(|a| a & !(PAGE_SIZE - 1))
+}
+
+impl<'a> Cpu<'a> {
+    /// A hart that runs the static Linux program `elf` in U-mode, with `argv` and `envp` on its
+    /// stack. The bus is only used for its RAM.
+    pub fn user(bus: &'a bus::Bus, elf: &Elf, argv: &[String], envp: &[String]) -> std::io::Result<Self> {
+        let invalid = |msg: &str| Error::new(ErrorKind::InvalidInput, msg);
+        if elf.interp {
+            return Err(invalid("dynamically linked programs are not supported"));
+        }
+
+        let mut cpu = Self::new(bus, 0);
+        let exe = argv.first().ok_or_else(|| invalid("no argv[0]"))?;
+        let exe = std::fs::canonicalize(exe).map_or_else(|_| exe.clone(), |p| p.to_string_lossy().into_owned());
+        let mut p = Process {
+            vmas: BTreeMap::new(),
+            frames: BTreeMap::new(),
+            free: Vec::new(),
+            next_frame: bus::RAM_BASE,
+            heap: 0,
+            brk: 0,
+            mmap_next: MMAP_BASE,
+            exe,
+        };
+
+        let mut end = 0;
+        for s in elf.segments.iter() {
+            let top = s.vaddr.checked_add(s.memsz).filter(|t| *t <= MMAP_BASE);
+            let top = top.ok_or_else(|| invalid("segment out of the program area"))?;
+            let perm = [(PF_R, PERM_R), (PF_W, PERM_W), (PF_X, PERM_X)].iter().filter(|f| s.flags & f.0 != 0).fold(0, |m, f| m | f.1);
+            p.map(s.vaddr & !(PAGE_SIZE - 1), page_up(top).unwrap(), perm);
+            end = end.max(top);
+        }
+
+        p.heap = page_up(end).unwrap();
+        p.brk = p.heap;
+        p.map(STACK_TOP - STACK_SIZE, STACK_TOP, PERM_R | PERM_W);
+        cpu.process = Some(Box::new(p));
+
+        let no_room = || invalid("program does not fit in RAM");
+        for s in elf.segments.iter() {
+            cpu.user_write_as(s.vaddr, s.data, 0).map_err(|_| no_room())?;
+        }
+
+        // the program headers, where the first segment has them
+        let phdrs = elf.phoff..elf.phoff + elf.phnum as u64 * elf.phentsize as u64;
+        let phdr = elf.segments.iter().find(|s| s.offset <= phdrs.start && phdrs.end <= s.offset + s.data.len() as u64);
+        let phdr = phdr.map_or(0, |s| s.vaddr + phdrs.start - s.offset);
+
+        let sp = cpu.user_stack(elf, phdr, argv, envp).map_err(|_| no_room())?;
+        cpu.mode = Mode::User;
+        cpu.csrs[csr::CSR_MCOUNTEREN as usize] = 7;
+        cpu.csrs[csr::CSR_SCOUNTEREN as usize] = 7;
+        _ = cpu.write_reg(2, sp);
+        _ = cpu.write_pc(elf.entry);
+        Ok(cpu)
+    }
+
+    /// Lays out the stack the kernel starts a program with: the strings at the top, then 16 byte
+    /// aligned argc, argv, envp and the auxiliary vector. Returns the stack pointer.
+    fn user_stack(&mut self, elf: &Elf, phdr: u64, argv: &[String], envp: &[String]) -> Result<u64, u32> {
+        let mut sp = STACK_TOP;
+        let mut push = |cpu: &mut Self, data: &[u8]| {
+            sp -= data.len() as u64;
+            cpu.user_write(sp, data).map(|_| sp)
+        };
+
+        let mut strings = Vec::new();
+        for s in argv.iter().chain(envp.iter()) {
+            strings.push(push(self, CString::new(s.as_str()).map_err(|_| libc::EINVAL as u32)?.as_bytes_with_nul())?);
+        }
+
+        let mut random = [0u8; 16];
+        unsafe { libc::getrandom(random.as_mut_ptr().cast(), random.len(), 0) };
+        let random = push(self, &random)?;
+        let execfn = strings[0];
+
+        let ids = unsafe { [libc::getuid(), libc::geteuid(), libc::getgid(), libc::getegid()] };
+        let auxv = [
+            (AT_PHDR, phdr),
+            (AT_PHENT, elf.phentsize as u64),
+            (AT_PHNUM, elf.phnum as u64),
+            (AT_PAGESZ, PAGE_SIZE),
+            (AT_BASE, 0),
+            (AT_ENTRY, elf.entry),
+            (AT_UID, ids[0] as u64),
+            (AT_EUID, ids[1] as u64),
+            (AT_GID, ids[2] as u64),
+            (AT_EGID, ids[3] as u64),
+            // the single letter extensions are the same bits as in misa
+            (AT_HWCAP, MISA & 0x3ff_ffff),
+            (AT_CLKTCK, 100),
+            (AT_SECURE, 0),
+            (AT_RANDOM, random),
+            (AT_EXECFN, execfn),
+            (AT_NULL, 0),
+        ];
+
+        let mut words = vec![argv.len() as u64];
+        words.extend_from_slice(&strings[..argv.len()]);
+        words.push(0);
+        words.extend_from_slice(&strings[argv.len()..]);
+        words.push(0);
+        words.extend(auxv.iter().flat_map(|(k, v)| [*k, *v]));
+
+        let sp = (sp - 8 * words.len() as u64) & !15;
+        self.user_write(sp, &words.iter().flat_map(|w| w.to_le_bytes()).collect::<Vec<_>>())?;
+        Ok(sp)
+    }
+
+    fn process(&mut self) -> &mut Process {
+        self.process.as_mut().unwrap()
+    }
+
+    /// Translation through the page map instead of page tables
+    pub(super) fn user_translate(&mut self, a: u64, perm: u64) -> Result<u64, Fault> {
+        let bus = self.bus;
+        let frame = self.process().frame(bus, a & !(PAGE_SIZE - 1), perm).ok_or(Fault::Page)?;
+        Ok(frame | a & (PAGE_SIZE - 1))
+    }
+
+    /// Calls `f` with the RAM address and the range of each piece of `len` bytes at `a` that
+    /// is in one page, failing with EFAULT if any page is not mapped with `perm`
+    fn user_pages(&mut self, a: u64, len: usize, perm: u64, mut f: impl FnMut(&bus::Bus, u64, core::ops::Range<usize>)) -> Result<(), u32> {
+        let mut done = 0;
+        while done < len {
+            let va = a.checked_add(done as u64).ok_or(libc::EFAULT as u32)?;
+            let n = ((PAGE_SIZE - va % PAGE_SIZE) as usize).min(len - done);
+            let pa = self.user_translate(va, perm).map_err(|_| libc::EFAULT as u32)?;
+            f(self.bus, pa, done..done + n);
+            done += n;
+        }
+
+        Ok(())
+    }
+
+    fn user_read(&mut self, a: u64, buf: &mut [u8]) -> Result<(), u32> {
+        self.user_pages(a, buf.len(), PERM_R, |bus, pa, r| _ = bus.dma_read(pa, &mut buf[r]))
+    }
+
+    fn user_write(&mut self, a: u64, data: &[u8]) -> Result<(), u32> {
+        self.user_write_as(a, data, PERM_W)
+    }
+
+    /// Writes program memory that is mapped with at least `perm`, which is 0 when loading
+    fn user_write_as(&mut self, a: u64, data: &[u8], perm: u64) -> Result<(), u32> {
+        self.user_pages(a, data.len(), perm, |bus, pa, r| _ = bus.dma_write(pa, &data[r]))
+    }
+
+    /// Fails with EFAULT unless all `len` bytes at `a` can be written, for before taking data from
+    /// the host that could not be put back
+    fn user_check_write(&mut self, a: u64, len: usize) -> Result<(), u32> {
+        self.user_pages(a, len, PERM_W, |_, _, _| ())
+    }
+
+    fn user_u64(&mut self, a: u64) -> Result<u64, u32> {
+        let mut b = [0; 8];
+        self.user_read(a, &mut b)?;
+        Ok(u64::from_le_bytes(b))
+    }
+
+    /// A NUL terminated string
+    fn user_cstr(&mut self, a: u64) -> Result<CString, u32> {
+        let mut s = Vec::new();
+        loop {
+            let mut b = [0];
+            self.user_read(a + s.len() as u64, &mut b)?;
+            if b[0] == 0 {
+                return Ok(CString::new(s).unwrap());
+            }
+
+            s.push(b[0]);
+            if s.len() == PATH_MAX {
+                return Err(libc::ENAMETOOLONG as u32);
+            }
+        }
+    }
+
+    /// Handles what would trap to the kernel: `ecall`s are system calls, exceptions end the program
+    /// like the signal the kernel sends for them would
+    pub(super) fn linux_trap(&mut self, cause: u64, epc: u64, tv: TrapVal) {
+        if cause >> 63 == 1 {
+            return;
+        }
+
+        if cause == Exception::EcallFromUser as u64 {
+            _ = self.write_pc(epc + 4);
+            let args = [10, 11, 12, 13, 14, 15].map(|r| self.read_reg(r));
+            let ret = self.syscall(self.read_reg(17), args).unwrap_or_else(|e| (e as i64).wrapping_neg() as u64);
+            _ = self.write_reg(10, ret);
+            return;
+        }
+
+        let signal = match cause {
+            c if c == Exception::IllegalInst as u64 => libc::SIGILL,
+            c if c == Exception::Breakpoint as u64 => libc::SIGTRAP,
+            c if c == Exception::InstAddrMisalign as u64 || c == Exception::StoreAddrMisalign as u64 => libc::SIGBUS,
+            _ => libc::SIGSEGV,
+        };
+
+        eprintln!("rv64: signal {signal} from exception {cause} at pc {epc:#x}, tval {:#x}", tv.tval);
+        self.linux_exit(128 + signal as u64);
+    }
+
+    fn linux_exit(&mut self, status: u64) {
+        self.bus.request_exit(match status as u8 {
+            0 => Exit::Pass,
+            s => Exit::Fail(s as u16),
+        });
+    }
+
+    fn syscall(&mut self, nr: u64, args: [u64; 6]) -> Result<u64, u32> {
+        let fd = args[0] as i32;
+
+        match nr {
+            SYS_READ | SYS_PREAD64 => {
+                let mut buf = vec![0u8; (args[2] as usize).min(MAX_IO)];
+                self.user_check_write(args[1], buf.len())?;
+                let n = match nr {
+                    SYS_READ => host(unsafe { libc::read(fd, buf.as_mut_ptr().cast(), buf.len()) } as i64)?,
+                    _ => host(unsafe { libc::pread(fd, buf.as_mut_ptr().cast(), buf.len(), args[3] as i64) } as i64)?,
+                };
+                self.user_write(args[1], &buf[..n as usize])?;
+                Ok(n)
+            },
+            SYS_WRITE | SYS_PWRITE64 => {
+                let mut buf = vec![0u8; (args[2] as usize).min(MAX_IO)];
+                self.user_read(args[1], &mut buf)?;
+                match nr {
+                    SYS_WRITE => host(unsafe { libc::write(fd, buf.as_ptr().cast(), buf.len()) } as i64),
+                    _ => host(unsafe { libc::pwrite(fd, buf.as_ptr().cast(), buf.len(), args[3] as i64) } as i64),
+                }
+            },
+            SYS_READV | SYS_WRITEV => {
+                if args[2] > libc::UIO_MAXIOV as u64 {
+                    return Err(libc::EINVAL as u32);
+                }
+
+                let mut iov = Vec::new();
+                for i in 0..args[2] {
+                    iov.push((self.user_u64(args[1] + 16 * i)?, self.user_u64(args[1] + 16 * i + 8)? as usize));
+                }
+
+                let total = iov.iter().fold(0usize, |t, v| t.saturating_add(v.1)).min(MAX_IO);
+                let mut buf = vec![0u8; total];
+                if nr == SYS_WRITEV {
+                    let mut at = 0;
+                    for (base, len) in iov {
+                        let len = len.min(total - at);
+                        self.user_read(base, &mut buf[at..at + len])?;
+                        at += len;
+                    }
+
+                    return host(unsafe { libc::write(fd, buf.as_ptr().cast(), buf.len()) } as i64);
+                }
+
+                let n = host(unsafe { libc::read(fd, buf.as_mut_ptr().cast(), buf.len()) } as i64)? as usize;
+                let mut at = 0;
+                for (base, len) in iov {
+                    let len = len.min(n - at);
+                    self.user_write(base, &buf[at..at + len])?;
+                    at += len;
+                }
+
+                Ok(n as u64)
+            },
+            SYS_OPENAT => {
+                let path = self.user_cstr(args[1])?;
+                host(unsafe { libc::openat(fd, path.as_ptr(), host_open_flags(args[2]), args[3] as libc::c_uint) } as i64)
+            },
+            SYS_CLOSE => host(unsafe { libc::close(fd) } as i64),
+            SYS_LSEEK => host(unsafe { libc::lseek(fd, args[1] as i64, args[2] as i32) }),
+            SYS_DUP => host(unsafe { libc::dup(fd) } as i64),
+            SYS_DUP3 => host(unsafe { libc::dup3(fd, args[1] as i32, host_open_flags(args[2])) } as i64),
+            SYS_FCNTL => match args[1] {
+                // fd flags and duplicating take an int, the rest but locks take none
+                F_DUPFD | F_DUPFD_CLOEXEC | F_GETFD | F_SETFD => {
+                    host(unsafe { libc::fcntl(fd, args[1] as i32, args[2] as i32) } as i64)
+                },
+                F_GETFL => host(unsafe { libc::fcntl(fd, libc::F_GETFL) } as i64).map(|f| guest_open_flags(f as i32)),
+                F_SETFL => host(unsafe { libc::fcntl(fd, libc::F_SETFL, host_open_flags(args[2])) } as i64),
+                _ => Err(libc::EINVAL as u32),
+            },
+            SYS_PIPE2 => {
+                let mut fds = [0i32; 2];
+                host(unsafe { libc::pipe2(fds.as_mut_ptr(), host_open_flags(args[1])) } as i64)?;
+                self.user_write(args[0], &fds.iter().flat_map(|f| f.to_le_bytes()).collect::<Vec<_>>())?;
+                Ok(0)
+            },
+            SYS_IOCTL => self.sys_ioctl(fd, args[1], args[2]),
+            SYS_GETDENTS64 => {
+                // the same layout everywhere
+                let mut buf = vec![0u8; (args[2] as usize).min(MAX_IO)];
+                self.user_check_write(args[1], buf.len())?;
+                let n = host(unsafe { libc::syscall(libc::SYS_getdents64, fd, buf.as_mut_ptr(), buf.len()) })?;
+                self.user_write(args[1], &buf[..n as usize])?;
+                Ok(n)
+            },
+            SYS_FSTAT | SYS_NEWFSTATAT => {
+                let mut st = unsafe { core::mem::zeroed::<libc::stat>() };
+                let out = if nr == SYS_FSTAT {
+                    host(unsafe { libc::fstat(fd, &mut st) } as i64)?;
+                    args[1]
+                } else {
+                    let path = self.user_cstr(args[1])?;
+                    host(unsafe { libc::fstatat(fd, path.as_ptr(), &mut st, args[3] as i32) } as i64)?;
+                    args[2]
+                };
+
+                self.user_write(out, &stat_bytes(&st))?;
+                Ok(0)
+            },
+            SYS_STATX => {
+                // the same layout everywhere
+                let path = self.user_cstr(args[1])?;
+                let mut buf = [0u8; 256];
+                host(unsafe { libc::syscall(libc::SYS_statx, fd, path.as_ptr(), args[2] as i32, args[3] as u32, buf.as_mut_ptr()) })?;
+                self.user_write(args[4], &buf)?;
+                Ok(0)
+            },
+            SYS_READLINKAT => {
+                let path = self.user_cstr(args[1])?;
+                let target = if path.as_bytes() == b"/proc/self/exe" {
+                    self.process().exe.clone().into_bytes()
+                } else {
+                    let mut buf = vec![0u8; PATH_MAX];
+                    let n = host(unsafe { libc::readlinkat(fd, path.as_ptr(), buf.as_mut_ptr().cast(), buf.len()) } as i64)?;
+                    buf.truncate(n as usize);
+                    buf
+                };
+
+                let n = target.len().min(args[3] as usize);
+                self.user_write(args[2], &target[..n])?;
+                Ok(n as u64)
+            },
+            SYS_GETCWD => {
+                let mut buf = vec![0u8; (args[1] as usize).min(PATH_MAX)];
+                if unsafe { libc::getcwd(buf.as_mut_ptr().cast(), buf.len()) }.is_null() {
+                    return Err(errno());
+                }
+
+                let n = buf.iter().position(|b| *b == 0).unwrap() + 1;
+                self.user_write(args[0], &buf[..n])?;
+                Ok(n as u64)
+            },
+            SYS_CHDIR => {
+                let path = self.user_cstr(args[0])?;
+                host(unsafe { libc::chdir(path.as_ptr()) } as i64)
+            },
+            SYS_MKDIRAT => {
+                let path = self.user_cstr(args[1])?;
+                host(unsafe { libc::mkdirat(fd, path.as_ptr(), args[2] as libc::mode_t) } as i64)
+            },
+            SYS_UNLINKAT => {
+                let path = self.user_cstr(args[1])?;
+                host(unsafe { libc::unlinkat(fd, path.as_ptr(), args[2] as i32) } as i64)
+            },
+            SYS_FACCESSAT | SYS_FACCESSAT2 => {
+                let path = self.user_cstr(args[1])?;
+                let flags = if nr == SYS_FACCESSAT2 { args[3] as i32 } else { 0 };
+                host(unsafe { libc::faccessat(fd, path.as_ptr(), args[2] as i32, flags) } as i64)
+            },
+            SYS_RENAMEAT2 => {
+                let (old, new) = (self.user_cstr(args[1])?, self.user_cstr(args[3])?);
+                host(unsafe { libc::renameat2(fd, old.as_ptr(), args[2] as i32, new.as_ptr(), args[4] as u32) } as i64)
+            },
+            SYS_EXIT | SYS_EXIT_GROUP => {
+                self.linux_exit(args[0]);
+                Ok(0)
+            },
+            SYS_KILL | SYS_TKILL | SYS_TGKILL => {
+                let (target, sig) = match nr {
+                    SYS_TGKILL => (args[1], args[2]),
+                    _ => (args[0], args[1]),
+                };
+
+                if target as i32 != unsafe { libc::getpid() } {
+                    return Err(libc::EPERM as u32);
+                }
+
+                match sig as i32 {
+                    0 | libc::SIGCHLD | libc::SIGURG | libc::SIGWINCH => {},
+                    1..=64 => self.linux_exit(128 + sig),
+                    _ => return Err(libc::EINVAL as u32),
+                }
+
+                Ok(0)
+            },
+            SYS_RT_SIGACTION => {
+                // nothing is delivered, so every handler stays the default
+                if args[2] != 0 {
+                    self.user_write(args[2], &[0; 24])?;
+                }
+                Ok(0)
+            },
+            SYS_RT_SIGPROCMASK => {
+                if args[2] != 0 {
+                    self.user_write(args[2], &[0; 8])?;
+                }
+                Ok(0)
+            },
+            SYS_SIGALTSTACK | SYS_SET_ROBUST_LIST | SYS_SCHED_YIELD | SYS_RISCV_FLUSH_ICACHE => Ok(0),
+            // the only thread, so no other waiter or waker
+            SYS_FUTEX => match args[1] & 0x7f {
+                0 => Err(libc::EAGAIN as u32),
+                1 => Ok(0),
+                _ => Err(libc::ENOSYS as u32),
+            },
+            SYS_SET_TID_ADDRESS | SYS_GETPID | SYS_GETTID => Ok(unsafe { libc::getpid() } as u64),
+            SYS_GETPPID => Ok(unsafe { libc::getppid() } as u64),
+            SYS_GETUID => Ok(unsafe { libc::getuid() } as u64),
+            SYS_GETEUID => Ok(unsafe { libc::geteuid() } as u64),
+            SYS_GETGID => Ok(unsafe { libc::getgid() } as u64),
+            SYS_GETEGID => Ok(unsafe { libc::getegid() } as u64),
+            SYS_CLOCK_GETTIME | SYS_CLOCK_GETRES => {
+                let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+                match nr {
+                    SYS_CLOCK_GETTIME => host(unsafe { libc::clock_gettime(args[0] as i32, &mut ts) } as i64)?,
+                    _ => host(unsafe { libc::clock_getres(args[0] as i32, &mut ts) } as i64)?,
+                };
+                if args[1] != 0 {
+                    self.user_write(args[1], &pair_bytes(ts.tv_sec as u64, ts.tv_nsec as u64))?;
+                }
+                Ok(0)
+            },
+            SYS_GETTIMEOFDAY => {
+                let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+                unsafe { libc::clock_gettime(libc::CLOCK_REALTIME, &mut ts) };
+                if args[0] != 0 {
+                    self.user_write(args[0], &pair_bytes(ts.tv_sec as u64, ts.tv_nsec as u64 / 1000))?;
+                }
+                Ok(0)
+            },
+            SYS_NANOSLEEP | SYS_CLOCK_NANOSLEEP => {
+                let (clock, flags, req) = match nr {
+                    SYS_NANOSLEEP => (libc::CLOCK_MONOTONIC, 0, args[0]),
+                    _ => (args[0] as i32, args[1] as i32, args[2]),
+                };
+                let ts = libc::timespec { tv_sec: self.user_u64(req)? as i64, tv_nsec: self.user_u64(req + 8)? as i64 };
+                match unsafe { libc::clock_nanosleep(clock, flags, &ts, core::ptr::null_mut()) } {
+                    0 => Ok(0),
+                    e => Err(e as u32),
+                }
+            },
+            SYS_UNAME => {
+                let mut uts = unsafe { core::mem::zeroed::<libc::utsname>() };
+                host(unsafe { libc::uname(&mut uts) } as i64)?;
+                let mut machine = [0; 65];
+                machine[..7].copy_from_slice(b"riscv64");
+
+                let fields = [uts.sysname, uts.nodename, uts.release, uts.version, machine.map(|b: u8| b as libc::c_char), uts.domainname];
+                self.user_write(args[0], &fields.iter().flatten().map(|c| *c as u8).collect::<Vec<_>>())?;
+                Ok(0)
+            },
+            SYS_GETRLIMIT => self.sys_prlimit(args[0], 0, args[1]),
+            SYS_PRLIMIT64 => {
+                if args[0] != 0 && args[0] as i32 != unsafe { libc::getpid() } {
+                    return Err(libc::EPERM as u32);
+                }
+                self.sys_prlimit(args[1], args[2], args[3])
+            },
+            SYS_GETRANDOM => {
+                let mut buf = vec![0u8; (args[1] as usize).min(MAX_IO)];
+                let n = host(unsafe { libc::getrandom(buf.as_mut_ptr().cast(), buf.len(), args[2] as u32) } as i64)?;
+                self.user_write(args[0], &buf[..n as usize])?;
+                Ok(n)
+            },
+            SYS_BRK => Ok(self.sys_brk(args[0])),
+            SYS_MMAP => self.sys_mmap(args[0], args[1], args[2], args[3], fd_arg(args[4]), args[5]),
+            SYS_MUNMAP => {
+                let end = self.page_range(args[0], args[1])?;
+                self.unmap(args[0], end, true);
+                Ok(0)
+            },
+            SYS_MPROTECT => {
+                let end = self.page_range(args[0], args[1])?;
+                let p = self.process();
+                if !p.is_mapped(args[0], end) {
+                    return Err(libc::ENOMEM as u32);
+                }
+
+                for (_, v) in p.vmas_in(args[0], end) {
+                    v.perm = prot_perm(args[2]);
+                }
+                Ok(0)
+            },
+            SYS_MADVISE => {
+                let end = self.page_range(args[0], args[1])?;
+                if args[2] == MADV_DONTNEED {
+                    // anonymous memory reads as zeros again
+                    self.unmap(args[0], end, false);
+                }
+                Ok(0)
+            },
+            // callers copy instead
+            SYS_MREMAP => Err(libc::ENOMEM as u32),
+            _ => Err(libc::ENOSYS as u32),
+        }
+    }
+
+    fn sys_ioctl(&mut self, fd: i32, req: u64, arg: u64) -> Result<u64, u32> {
+        // the terminal structures are the same on hosts with the generic ABI
+        let mut buf = [0u8; 64];
+        match req {
+            TCGETS | TIOCGWINSZ => {
+                host(unsafe { libc::ioctl(fd, req as _, buf.as_mut_ptr()) } as i64)?;
+                let len = if req == TCGETS { TERMIOS_SIZE } else { 8 };
+                self.user_write(arg, &buf[..len])?;
+                Ok(0)
+            },
+            TCSETS..=TCSETSF => {
+                self.user_read(arg, &mut buf[..TERMIOS_SIZE])?;
+                host(unsafe { libc::ioctl(fd, req as _, buf.as_ptr()) } as i64)
+            },
+            _ => Err(libc::ENOTTY as u32),
+        }
+    }
+
+    fn sys_prlimit(&mut self, resource: u64, new: u64, old: u64) -> Result<u64, u32> {
+        let mut limits = [libc::rlimit64 { rlim_cur: 0, rlim_max: 0 }; 2];
+        if new != 0 {
+            limits[0] = libc::rlimit64 { rlim_cur: self.user_u64(new)?, rlim_max: self.user_u64(new + 8)? };
+        }
+
+        let new_ptr = if new != 0 { &limits[0] as *const _ } else { core::ptr::null() };
+        host(unsafe { libc::prlimit64(0, resource as _, new_ptr, &mut limits[1]) } as i64)?;
+        if old != 0 {
+            self.user_write(old, &pair_bytes(limits[1].rlim_cur, limits[1].rlim_max))?;
+        }
+        Ok(0)
+    }
+
+    /// Moves the break, which stays where it is when it cannot. Returns the new break.
+    fn sys_brk(&mut self, brk: u64) -> u64 {
+        let p = self.process();
+        let (old_end, cur) = (page_up(p.brk).unwrap(), p.brk);
+        let Some(end) = page_up(brk).filter(|_| brk >= p.heap) else { return cur };
+
+        if end > old_end {
+            if !p.is_free(old_end, end) {
+                return cur;
+            }
+            p.map(old_end, end, PERM_R | PERM_W);
+        } else {
+            self.unmap(end, old_end, true);
+        }
+
+        self.process().brk = brk;
+        brk
+    }
+
+    fn sys_mmap(&mut self, addr: u64, len: u64, prot: u64, flags: u64, fd: Option<i32>, off: u64) -> Result<u64, u32> {
+        let size = page_up(len).filter(|s| *s != 0 && off.is_multiple_of(PAGE_SIZE)).ok_or(libc::EINVAL as u32)?;
+        let p = self.process();
+
+        let start = if flags & (MAP_FIXED | MAP_FIXED_NOREPLACE) != 0 {
+            let end = addr.checked_add(size).filter(|e| addr.is_multiple_of(PAGE_SIZE) && *e <= STACK_TOP).ok_or(libc::EINVAL as u32)?;
+            if flags & MAP_FIXED_NOREPLACE != 0 && !p.is_free(addr, end) {
+                return Err(libc::EEXIST as u32);
+            }
+            addr
+        } else if addr.is_multiple_of(PAGE_SIZE) && addr >= p.heap && p.is_free(addr, addr.saturating_add(size)) {
+            addr
+        } else {
+            let start = p.mmap_next;
+            if !p.is_free(start, start + size) {
+                return Err(libc::ENOMEM as u32);
+            }
+            p.mmap_next += size;
+            start
+        };
+
+        // a file of its own, which stays open after the program closes the descriptor
+        let file = match (flags & MAP_ANONYMOUS, fd) {
+            (0, Some(fd)) => {
+                use std::os::fd::FromRawFd;
+
+                let dup = host(unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) } as i64)?;
+                Some((Arc::new(unsafe { std::fs::File::from_raw_fd(dup as i32) }), off))
+            },
+            (0, None) => return Err(libc::EBADF as u32),
+            _ => None,
+        };
+
+        self.unmap(start, start + size, true);
+        self.process().vmas.insert(start, Vma { end: start + size, perm: prot_perm(prot), file });
+        Ok(start)
+    }
+
+    /// The end of the pages of `len` bytes at the page aligned `a`
+    fn page_range(&self, a: u64, len: u64) -> Result<u64, u32> {
+        let end = page_up(len).and_then(|l| a.checked_add(l));
+        end.filter(|_| a.is_multiple_of(PAGE_SIZE)).ok_or(libc::EINVAL as u32)
+    }
+
+    /// Gives the RAM of the touched pages back, zeroed. The pages are dropped, or with `drop_pages`
+    /// false stay mapped and are fresh again when touched: zeros, or what the file has.
+    fn unmap(&mut self, start: u64, end: u64, drop_pages: bool) {
+        let p = self.process.as_mut().unwrap();
+        if drop_pages {
+            let starts = p.vmas_in(start, end).map(|(s, _)| *s).collect::<Vec<_>>();
+            for s in starts {
+                p.vmas.remove(&s);
+            }
+        }
+
+        let pages = p.frames.range(start..end).map(|(a, _)| *a).collect::<Vec<_>>();
+        for a in pages {
+            let frame = self.process().frames.remove(&a).unwrap();
+            _ = self.bus.dma_write(frame, &[0; PAGE_SIZE as usize]);
+            self.process().free.push(frame);
+        }
+    }
+}
+
+/// `PROT_READ`, `PROT_WRITE` and `PROT_EXEC` are the page permissions one bit down
+fn prot_perm(prot: u64) -> u64 {
+    (prot & 7) << 1
+}
+
+fn fd_arg(fd: u64) -> Option<i32> {
+    (fd as i32 >= 0).then_some(fd as i32)
+}
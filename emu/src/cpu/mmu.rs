@@ -1,151 +1,318 @@
 use super::*;
 
-const PERM_R: u64 = 0x02;
-const PERM_W: u64 = 0x04;
-const PERM_X: u64 = 0x08;
+const PTE_V: u64 = 0x01;
+pub(super) const PERM_R: u64 = 0x02;
+pub(super) const PERM_W: u64 = 0x04;
+pub(super) const PERM_X: u64 = 0x08;
 const PERM_U: u64 = 0x10;
+const PTE_A: u64 = 0x40;
+const PTE_D: u64 = 0x80;
+
+// htinst pseudoinstructions for faults on implicit vs-stage pte accesses
+const TINST_PTE_READ: u64 = 0x2000;
+const TINST_PTE_WRITE: u64 = 0x2020;
 
 impl<'a> Cpu<'a> {
     pub(crate) fn flush_mapping(&mut self) -> Result<(), Exception> {
+        if self.virt {
+            if (self.csr_read_cpu(csr::CSR_HSTATUS) >> 20) & 1 == 1 {
+                return Err(Exception::VirtualInst);
+            }
+
+            self.flush_vs_mapping();
+            return Ok(());
+        }
+
         let satp = self.csr_read(csr::CSR_SATP)?;
 
-        self.pages = match satp >> 60 {
-            0 => Paging::Bare,
-            8 => Paging::Sv39 { address: (satp & 0xfffffffffff) << 12 },
-            _ => return Ok(()),
-        };
+        if let Some(p) = Paging::from_atp(satp) {
+            self.pages = p;
+        }
 
         Ok(())
     }
 
-    pub(crate) fn resolve_paging(&mut self, a: u64, perm_mask: (u64, u64, bool)) -> Result<u64, ()> {
-        if perm_mask.2 || (self.mode == Mode::Machine && (perm_mask.0 & PERM_X) != 0) {
-            return Ok(a);
+    pub(crate) fn flush_vs_mapping(&mut self) {
+        if let Some(p) = Paging::from_atp(self.csr_read_cpu(csr::CSR_VSATP)) {
+            self.vs_pages = p;
         }
+    }
 
-        // println!("page");
+    pub(crate) fn flush_g_mapping(&mut self) {
+        if let Some(p) = Paging::from_atp(self.csr_read_cpu(csr::CSR_HGATP)) {
+            self.g_pages = p;
+        }
+    }
 
-        match self.pages {
-            Paging::Bare => Ok(a),
-            Paging::Sv39 { address } => {
-                self.resolve_sv39(a, perm_mask, address)
-            },
+    pub(crate) fn translate(&mut self, a: u64, acc: Access) -> Result<u64, Fault> {
+        if self.process.is_some() {
+            return self.user_translate(a, acc.perm);
+        }
+
+        if acc.mode == Mode::Machine {
+            return Ok(a);
         }
+
+        if !acc.virt {
+            return self.walk(a, self.pages, acc, Stage::Single);
+        }
+
+        let gpa = self.walk(a, self.vs_pages, acc, Stage::Vs)?;
+        self.g_translate(gpa, acc, 0)
     }
 
-    fn resolve_sv39(&mut self, a: u64, perm_mask: (u64, u64, bool), mut address: u64) -> Result<u64, ()> {
-        let offset = a & 0xfff;
-        let vpn0 = (a >> 12) & 0x1ff;
-        let vpn1 = (a >> 21) & 0x1ff;
-        let vpn2 = (a >> 30) & 0x1ff;
-        let vpn = [vpn0, vpn1, vpn2];
-        let store = (perm_mask.0 & PERM_W) != 0;
-
-        for i in (0..=2).rev() {
-            let pte_addr = address + (vpn[i] << 3);
-            let pte = self.bus.load_u64(pte_addr).map_err(|_| ())?;
-
-            // rv64 priv: If pte.v=0, or if pte.r=0 and pte.w=1
-            if (pte & 1 == 0) || (pte & 2 == 0 && pte & 4 == 4) {
-                // println!("a");
-                return Err(());
+    fn g_translate(&mut self, gpa: u64, acc: Access, tinst: u64) -> Result<u64, Fault> {
+        // all g-stage accesses are treated as u-mode accesses
+        let acc = Access { mode: Mode::User, ..acc };
+        self.walk(gpa, self.g_pages, acc, Stage::G).map_err(|f| match f {
+            Fault::GuestPage { .. } => Fault::GuestPage { gpa, tinst },
+            f => f,
+        })
+    }
+
+    fn walk(&mut self, a: u64, pages: Paging, acc: Access, stage: Stage) -> Result<u64, Fault> {
+        let (mut address, levels) = match pages {
+            Paging::Bare => return Ok(a),
+            Paging::Sv39 { address } => (address, 3),
+            Paging::Sv48 { address } => (address, 4),
+        };
+
+        // g-stage gets 2 extra bits on the root index (sv39x4 etc.)
+        let widen = if stage == Stage::G { 2 } else { 0 };
+        let bits = 12 + 9 * levels + widen;
+        let fault = match stage {
+            Stage::G => Fault::GuestPage { gpa: a, tinst: 0 },
+            _ => Fault::Page,
+        };
+
+        let in_range = match stage {
+            Stage::G => a >> bits == 0,
+            _ => ((a as i64) << (64 - bits) >> (64 - bits)) as u64 == a,
+        };
+
+        if !in_range {
+            return Err(fault);
+        }
+
+        let store = acc.perm == PERM_W;
+
+        for i in (0..levels).rev() {
+            let index_bits = if i == levels - 1 { 9 + widen } else { 9 };
+            let vpn = (a >> (12 + 9 * i)) & ((1 << index_bits) - 1);
+            let mut pte_addr = address + (vpn << 3);
+
+            // vs-stage page tables live in guest physical memory
+            if stage == Stage::Vs {
+                let acc = Access { perm: PERM_R, hlvx: false, ..acc };
+                pte_addr = self.g_translate(pte_addr, acc, TINST_PTE_READ)?;
+            }
+
+            let pte = self.bus.load_u64(pte_addr).map_err(|_| Fault::Access)?;
+
+            // rv64 priv: If pte.v=0, or if pte.r=0 and pte.w=1, or any reserved bits are set
+            if (pte & PTE_V == 0) || (pte & PERM_R == 0 && pte & PERM_W != 0) || pte >> 54 != 0 {
+                return Err(fault);
             }
 
             // rv64 priv: If pte.r=1 or pte.x=1, go to step 5
             if pte & (PERM_R | PERM_X) == 0 {
-                address = pte >> 10 << 12;
+                address = ((pte >> 10) & 0xfff_ffff_ffff) << 12;
                 continue;
             }
 
-            if pte & perm_mask.0 != perm_mask.0 || pte & perm_mask.1 != 0 {
-                return Err(());
+            if !self.leaf_allows(pte, acc, stage) {
+                return Err(fault);
             }
 
-            let pte_ppn0 = (pte >> 10) & 0x1ff;
-            let pte_ppn1 = (pte >> 19) & 0x1ff;
-            let pte_ppn2 = (pte >> 28) & 0x3ffffff;
-            let pte_ppn = [pte_ppn0, pte_ppn1, pte_ppn2];
+            let ppn = (pte >> 10) & 0xfff_ffff_ffff;
 
-            // misaligned
-            if i > 0 && pte_ppn[..i as usize].iter().any(|v| *v != 0) {
-                return Err(());
+            // misaligned superpage
+            if ppn & ((1 << (9 * i)) - 1) != 0 {
+                return Err(fault);
             }
 
-            if pte & 0x20 == 0 || (store && pte & 0x40 == 0) {
-                self.bus.store_u64(pte_addr, pte | 0x40 | ((store as u64) << 7)).map_err(|_| ())?;
-            }
+            if pte & PTE_A == 0 || (store && pte & PTE_D == 0) {
+                if stage == Stage::Vs {
+                    let acc = Access { perm: PERM_W, hlvx: false, ..acc };
+                    pte_addr = self.g_translate(address + (vpn << 3), acc, TINST_PTE_WRITE)?;
+                }
 
-            let mut ppn = [0; 3];
-            let (l, r) = ppn.split_at_mut(i);
-            if i > 0 {
-                l.copy_from_slice(&vpn[..i]);
+                self.bus.store_u64(pte_addr, pte | PTE_A | (store as u64 * PTE_D)).map_err(|_| Fault::Access)?;
+                self.bus.break_reservations(self.hartid, pte_addr, 8);
             }
 
-            r.copy_from_slice(&pte_ppn[i..]);
+            let mask = (1 << (12 + 9 * i)) - 1;
+            return Ok(((ppn << 12) & !mask) | (a & mask));
+        }
 
-            return Ok((ppn[2] << 30) | (ppn[1] << 21) | (ppn[0] << 12) | offset);
+        Err(fault)
+    }
+
+    fn leaf_allows(&self, pte: u64, acc: Access, stage: Stage) -> bool {
+        let mstat = self.csr_read_cpu(csr::CSR_MSTATUS);
+        let mut sum = (mstat >> 18) & 1 == 1;
+        let mut mxr = (mstat >> 19) & 1 == 1;
+
+        // vs-stage uses vsstatus, but hs-level mxr still applies
+        if stage == Stage::Vs {
+            let vsstat = self.csr_read_cpu(csr::CSR_VSSTATUS);
+            sum = (vsstat >> 18) & 1 == 1;
+            mxr |= (vsstat >> 19) & 1 == 1;
         }
 
-        Err(())
+        let user = pte & PERM_U != 0;
+        let priv_ok = match (stage, acc.mode) {
+            (Stage::G, _) | (_, Mode::User) => user,
+            _ => !user || (sum && acc.perm != PERM_X),
+        };
+
+        let perm_ok = match acc.perm {
+            PERM_X => pte & PERM_X != 0,
+            PERM_W => pte & PERM_W != 0,
+            _ if acc.hlvx => pte & PERM_X != 0,
+            _ => pte & PERM_R != 0 || (mxr && pte & PERM_X != 0),
+        };
+
+        priv_ok && perm_ok
+    }
+
+    /// Records the trap values of a failed memory access and returns the exception to raise
+    pub(crate) fn fault(&mut self, f: Fault, a: u64, acc: Access) -> Exception {
+        self.fault = TrapVal { tval: a, gva: acc.virt, ..Default::default() };
+
+        match (f, acc.perm) {
+            (Fault::Page, PERM_X) => Exception::InstPageFault,
+            (Fault::Page, PERM_W) => Exception::StorePageFault,
+            (Fault::Page, _) => Exception::LoadPageFault,
+            (Fault::Access, PERM_X) => Exception::InstAccessFault,
+            (Fault::Access, PERM_W) => Exception::StoreAccessFault,
+            (Fault::Access, _) => Exception::LoadAccessFault,
+            (Fault::GuestPage { gpa, tinst }, perm) => {
+                self.fault.tval2 = gpa >> 2;
+                self.fault.tinst = tinst;
+
+                match perm {
+                    PERM_X => Exception::InstGuestPageFault,
+                    PERM_W => Exception::StoreGuestPageFault,
+                    _ => Exception::LoadGuestPageFault,
+                }
+            },
+        }
     }
 
     pub(crate) fn mmu_load_xu32(&mut self, a: u64) -> Result<u32, Exception> {
-        self.resolve_paging(a, self.get_perm(PERM_X))
-            .map_err(|_| Exception::InstPageFault)
-            .and_then(|a| self.bus.load_u32(a).map_err(|_| Exception::InstAccessFault))
+        let acc = Access { perm: PERM_X, mode: self.mode, virt: self.virt, hlvx: false };
+        let pa = self.translate(a, acc).map_err(|f| self.fault(f, a, acc))?;
+        self.bus.load_u32(pa).map_err(|_| self.fault(Fault::Access, a, acc))
     }
 
-    fn get_perm(&self, p: u64) -> (u64, u64, bool) {
-        self.get_perm_mode(p, self.mode)
+    /// Translates the address of a load or store without accessing it
+    pub(crate) fn mmu_translate(&mut self, a: u64, store: bool) -> Result<u64, Exception> {
+        let acc = self.data_access(if store { PERM_W } else { PERM_R });
+        self.translate(a, acc).map_err(|f| self.fault(f, a, acc))
     }
 
-    fn get_perm_mode(&self, p: u64, mode: Mode) -> (u64, u64, bool) {
-        match mode {
-            Mode::User => (p | PERM_U, 0, false),
-            Mode::Supervisor => {
-                (p, (!(self.csr_read_cpu(csr::CSR_MSTATUS) >> 18) & 1) * PERM_U, false)
-            },
-            Mode::Hypervisor => (p, 0, false),
-            Mode::Machine => {
-                let mstat = self.csr_read_cpu(csr::CSR_MSTATUS);
-                let mpp = Mode::from_code((mstat >> 11) & 3);
-                if (mstat >> 17) & 1 != 0 && mpp != Mode::Machine {
-                    self.get_perm_mode(p, mpp)
-                } else {
-                    (p, 0, true)
-                }
-            },
+    /// Access with the effective privilege for loads and stores (with `mstatus.mprv` applied)
+    fn data_access(&self, perm: u64) -> Access {
+        let mstat = self.csr_read_cpu(csr::CSR_MSTATUS);
+        let mpp = Mode::from_code((mstat >> 11) & 3);
+
+        if self.mode == Mode::Machine && (mstat >> 17) & 1 != 0 && mpp != Mode::Machine {
+            Access { perm, mode: mpp, virt: (mstat >> 39) & 1 == 1, hlvx: false }
+        } else {
+            Access { perm, mode: self.mode, virt: self.virt, hlvx: false }
         }
     }
+
+    /// Access for hypervisor virtual-machine loads and stores (`hlv`, `hlvx` and `hsv`)
+    pub(crate) fn hyper_access(&self, store: bool, hlvx: bool) -> Access {
+        let spvp = (self.csr_read_cpu(csr::CSR_HSTATUS) >> 8) & 1;
+        let perm = if store { PERM_W } else { PERM_R };
+        Access { perm, mode: Mode::from_code(spvp), virt: true, hlvx }
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub(crate) enum Paging {
     Bare,
     Sv39 { address: u64 },
+    Sv48 { address: u64 },
+}
+
+impl Paging {
+    /// Decodes `satp`, `vsatp` or `hgatp`, returning `None` for unsupported modes
+    fn from_atp(atp: u64) -> Option<Self> {
+        let address = (atp & 0xfff_ffff_ffff) << 12;
+
+        match atp >> 60 {
+            0 => Some(Self::Bare),
+            8 => Some(Self::Sv39 { address }),
+            9 => Some(Self::Sv48 { address }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Access {
+    perm: u64,
+    mode: Mode,
+    virt: bool,
+    hlvx: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Fault {
+    Page,
+    GuestPage { gpa: u64, tinst: u64 },
+    Access,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    /// Single stage translation with `satp` (V=0)
+    Single,
+    /// First stage with `vsatp` (V=1)
+    Vs,
+    /// Second stage with `hgatp` (V=1)
+    G,
 }
 
 macro_rules! gen {
-    ($t: tt $l: tt $s: tt $r: tt $w: tt) => {
+    ($t: tt $l: tt $s: tt $r: tt $w: tt $ra: tt $wa: tt) => {
         impl Cpu<'_> {
             pub(crate) fn $r(&mut self, a: u64) -> Result<$t, Exception> {
                 // println!("{} {a:016x}", stringify!($t));
-                self.resolve_paging(a, self.get_perm(PERM_R))
-                    .map_err(|_| Exception::LoadPageFault)
-                    .and_then(|a| self.bus.$l(a))
+                self.$ra(a, self.data_access(PERM_R))
             }
 
             pub(crate) fn $w(&mut self, a: u64, d: $t) -> Result<(), Exception> {
                 // println!("{} {a:016x}", stringify!($t));
-                self.resolve_paging(a, self.get_perm(PERM_W))
-                    .map_err(|_| Exception::StorePageFault)
-                    .and_then(|a| self.bus.$s(a, d))
+                self.$wa(a, d, self.data_access(PERM_W))
+            }
+
+            pub(crate) fn $ra(&mut self, a: u64, acc: Access) -> Result<$t, Exception> {
+                let pa = self.translate(a, acc).map_err(|f| self.fault(f, a, acc))?;
+                self.before_load(pa);
+                let v = self.bus.$l(pa).map_err(|_| self.fault(Fault::Access, a, acc))?;
+                Ok(self.forward_load(pa, core::mem::size_of::<$t>() as _, v as u64) as $t)
+            }
+
+            pub(crate) fn $wa(&mut self, a: u64, d: $t, acc: Access) -> Result<(), Exception> {
+                let pa = self.translate(a, acc).map_err(|f| self.fault(f, a, acc))?;
+                if self.buffer_store(pa, core::mem::size_of::<$t>() as _, d as u64) {
+                    return Ok(());
+                }
+
+                self.bus.$s(pa, d).map_err(|_| self.fault(Fault::Access, a, acc))?;
+                self.bus.break_reservations(self.hartid, pa, core::mem::size_of::<$t>() as _);
+                Ok(())
             }
         }
     };
 }
 
-gen!(u8 load_u8 store_u8 mmu_load_u8 mmu_store_u8);
-gen!(u16 load_u16 store_u16 mmu_load_u16 mmu_store_u16);
-gen!(u32 load_u32 store_u32 mmu_load_u32 mmu_store_u32);
-gen!(u64 load_u64 store_u64 mmu_load_u64 mmu_store_u64);
+gen!(u8 load_u8 store_u8 mmu_load_u8 mmu_store_u8 mmu_load_u8_as mmu_store_u8_as);
+gen!(u16 load_u16 store_u16 mmu_load_u16 mmu_store_u16 mmu_load_u16_as mmu_store_u16_as);
+gen!(u32 load_u32 store_u32 mmu_load_u32 mmu_store_u32 mmu_load_u32_as mmu_store_u32_as);
+gen!(u64 load_u64 store_u64 mmu_load_u64 mmu_store_u64 mmu_load_u64_as mmu_store_u64_as);
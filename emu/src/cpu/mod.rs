@@ -5,42 +5,73 @@ mod atomic;
 mod csr;
 mod comp;
 mod float;
+mod hyper;
+mod linux;
 mod mmu;
+mod sbi;
+mod wmo;
+
+pub(crate) use csr::MISA;
+pub(crate) use sbi::Hsm;
 
 pub struct Cpu<'a> {
-    bus: &'a mut bus::Bus<'a>,
+    bus: &'a bus::Bus,
+    hartid: usize,
 
     regs: [u64; 31],
     float_regs: [u64; 32],
     pc: u64,
     mode: Mode,
+    virt: bool,
 
     csrs: Box<[u64; 4096]>,
     pages: mmu::Paging,
-    amo_rs: atomic::ReservationSet,
+    vs_pages: mmu::Paging,
+    g_pages: mmu::Paging,
+    fault: TrapVal,
+    reservation: Option<atomic::Reservation>,
+    store_buffer: Option<wmo::StoreBuffer>,
+    /// Set by a `wfi` that no interrupt can wake up
+    halted: bool,
+    /// Stopped through the SBI HSM extension, waiting to be started
+    sbi_stopped: bool,
+    /// Running a Linux program, with its address space and system calls emulated
+    process: Option<Box<linux::Process>>,
 
     inst_buffer: u32,
     inst_len: u64,
 }
 
 impl<'a> Cpu<'a> {
-    pub fn new(bus: &'a mut bus::Bus<'a>) -> Self {
+    pub fn new(bus: &'a bus::Bus, hartid: usize) -> Self {
         let mut cpu = Self {
             bus,
+            hartid,
 
             regs: [0; 31],
             float_regs: [0; 32],
-            pc: 0x80000000,
+            pc: bus.reset_vector(),
             mode: Mode::Machine,
+            virt: false,
 
             csrs: Box::new([0; 4096]),
             pages: mmu::Paging::Bare,
-            amo_rs: atomic::ReservationSet::new(),
+            vs_pages: mmu::Paging::Bare,
+            g_pages: mmu::Paging::Bare,
+            fault: TrapVal::default(),
+            reservation: None,
+            store_buffer: None,
+            halted: false,
+            sbi_stopped: false,
+            process: None,
 
             inst_buffer: 0,
             inst_len: 0,
         };
         cpu.csr_init();
+        if bus.hsm().is_some() {
+            cpu.sbi_init();
+        }
         cpu
     }
 
@@ -61,9 +92,19 @@ impl<'a> Cpu<'a> {
     }
 
     pub fn step(&mut self, testing: bool) {
-        if let Err(ex) = self.step_w_exception(testing) {
+        if let Some(r) = &mut self.reservation {
+            r.steps += 1;
+        }
+
+        if self.sbi_stopped {
+            self.sbi_poll_start();
+        } else if let Err(ex) = self.step_w_exception(testing) {
             self.exception(ex);
         }
+
+        if self.hartid == 0 {
+            self.bus.tick();
+        }
     }
 
     fn fetch(&mut self) -> Result<u32, Exception> {
@@ -192,7 +233,12 @@ impl<'a> Cpu<'a> {
                     $(
                         ($f3, $f7, $rds, $r1s, $r2s) => {
                             if self.mode < Mode::$priv {
-                                return Err(Exception::IllegalInst);
+                                // legal in hs-mode but not in vs/vu-mode
+                                return Err(if self.virt && Mode::$priv != Mode::Machine {
+                                    Exception::VirtualInst
+                                } else {
+                                    Exception::IllegalInst
+                                });
                             }
 
                             $exec(r1, r2, im)?
@@ -314,6 +360,7 @@ impl<'a> Cpu<'a> {
                 0x1 |a, b, c| self.mmu_store_u16(a + c, b as _),
                 0x2 |a, b, c| {
                     if testing && (a + c == 0x80001004 || a + c == 0x80002004 || a + c == 0x80003004) && b == 0 {
+                        self.drain_store_buffer();
                         std::process::exit((self.bus.load_u32(a + c - 4).unwrap() - 1).min(1) as _);
                     } else if testing {
                         println!("{:016x} {}", a + c, b);
@@ -399,13 +446,13 @@ impl<'a> Cpu<'a> {
                 }),
                 0x7 0x01 |a: u64, b| Ok((a as u32).checked_rem(b as u32).unwrap_or(a as u32) as i32 as u64),
             ]),
-            0x0f => {},
+            0x0f => self.fence(inst),
             0x2f => exec!(amo [
                 0x2 0x02 |a, _, aqrl| Ok(self.atomic_load_u32(a, aqrl)? as i32 as u64),
-                0x2 0x03 |a, b, aqrl| Ok(self.atomic_store_u32(a, b as _, aqrl).is_err() as u64),
+                0x2 0x03 |a, b, aqrl| Ok(!self.atomic_store_u32(a, b as _, aqrl)? as u64),
 
                 0x3 0x02 |a, _, aqrl| Ok(self.atomic_load_u64(a, aqrl)?),
-                0x3 0x03 |a, b, aqrl| Ok(self.atomic_store_u64(a, b, aqrl).is_err() as u64),
+                0x3 0x03 |a, b, aqrl| Ok(!self.atomic_store_u64(a, b, aqrl)? as u64),
 
                 0x2 0x01 |a, b, aqrl| Ok(self.atomic_mo_u32(a, aqrl, |_| b as u32)? as i32 as u64),
                 0x2 0x00 |a, b, aqrl| Ok(self.atomic_mo_u32(a, aqrl, |a| a + b as u32)? as i32 as u64),
@@ -584,14 +631,25 @@ impl<'a> Cpu<'a> {
                     Ok(Some(rv))
                 },
                 Supervisor 0x0 0x08 0x00 0x00 0x02 |_, _, _| { // sret
-                    if (self.csr_read_cpu(csr::CSR_MSTATUS) >> 22) & 1 == 1 {
-                        return Err(Exception::IllegalInst);
-                    }
+                    let (status, epc) = if self.virt {
+                        if (self.csr_read_cpu(csr::CSR_HSTATUS) >> 22) & 1 == 1 {
+                            return Err(Exception::VirtualInst);
+                        }
 
-                    let epc = self.csr_read_cpu(csr::CSR_SEPC);
+                        (csr::CSR_VSSTATUS, csr::CSR_VSEPC)
+                    } else {
+                        if self.mode == Mode::Supervisor && (self.csr_read_cpu(csr::CSR_MSTATUS) >> 22) & 1 == 1 {
+                            return Err(Exception::IllegalInst);
+                        }
+
+                        (csr::CSR_MSTATUS, csr::CSR_SEPC)
+                    };
+
+                    let epc = self.csr_read_cpu(epc);
                     self.write_pc(epc)?;
+                    self.clear_reservation();
 
-                    let mut mstat = self.csr_read_cpu(csr::CSR_MSTATUS);
+                    let mut mstat = self.csr_read_cpu(status);
                     mstat &= !2;
                     mstat |= (mstat >> 4) & 2; // sIE = sPIE
 
@@ -602,14 +660,22 @@ impl<'a> Cpu<'a> {
 
                     mstat &= !0x100; // sPP = user
 
+                    if !self.virt {
+                        let mut hstat = self.csr_read_cpu(csr::CSR_HSTATUS);
+                        self.virt = (hstat >> 7) & 1 == 1;
+                        hstat &= !0x80; // sPV = 0
+                        self.csr_write_cpu(csr::CSR_HSTATUS, hstat);
+                    }
+
                     self.mode = mode;
-                    println!("{:?}", self.mode);
-                    self.csr_write_cpu(csr::CSR_MSTATUS, mstat);
+                    // println!("{:?}", self.mode);
+                    self.csr_write_cpu(status, mstat);
                     Ok(None)
                 },
                 Machine 0x0 0x18 0x00 0x00 0x02 |_, _, _| { // mret
                     let epc = self.csr_read_cpu(csr::CSR_MEPC);
                     self.write_pc(epc)?;
+                    self.clear_reservation();
 
                     let mut mstat = self.csr_read_cpu(csr::CSR_MSTATUS);
                     mstat &= !8;
@@ -624,23 +690,54 @@ impl<'a> Cpu<'a> {
 
                     if mode != Mode::Machine {
                         mstat &= !0x20000; // mPRV = 0
+                        self.virt = (mstat >> 39) & 1 == 1;
                     }
 
+                    mstat &= !(1 << 39); // mPV = 0
+
                     self.mode = mode;
-                    println!("{:?}", self.mode);
+                    // println!("{:?}", self.mode);
                     self.csr_write_cpu(csr::CSR_MSTATUS, mstat);
                     Ok(None)
                 },
                 User 0x0 0x00 0x00 0x00 0x00 |_, _, _| { // ecall
-                    self.exception(self.mode.ecall_exception());
+                    let ex = if self.virt && self.mode == Mode::Supervisor {
+                        Exception::EcallFromVirtSupervisor
+                    } else {
+                        self.mode.ecall_exception()
+                    };
+
+                    self.exception(ex);
                     Ok(None)
                 },
                 User 0x0 0x00 0x00 0x00 0x01 |_, _, _| { // ebreak
                     self.exception(Exception::Breakpoint);
                     Ok(None)
                 },
-                User 0x0 0x08 0x00 0x00 0x05 |_, _, _| Ok(None), // wfi
+                User 0x0 0x08 0x00 0x00 0x05 |_, _, _| { // wfi
+                    if self.virt && (self.mode == Mode::User || (self.csr_read_cpu(csr::CSR_HSTATUS) >> 21) & 1 == 1) {
+                        return Err(Exception::VirtualInst);
+                    }
+
+                    self.halted = self.csr_read_cpu(csr::CSR_MIE) == 0;
+                    Ok(None)
+                },
                 Supervisor 0x0 0x09 0x00 _ _ |_, _, _| self.flush_mapping().map(|_| None), // sfence.vma
+                Supervisor 0x0 0x11 0x00 _ _ |_, _, _| self.hfence_vvma().map(|_| None), // hfence.vvma
+                Supervisor 0x0 0x31 0x00 _ _ |_, _, _| self.hfence_gvma().map(|_| None), // hfence.gvma
+                User 0x4 0x30 _ _ 0x00 |a, _, _| Ok(Some(self.hyper_load_u8(a, false)? as i8 as u64)), // hlv.b
+                User 0x4 0x30 _ _ 0x01 |a, _, _| Ok(Some(self.hyper_load_u8(a, false)? as u64)), // hlv.bu
+                User 0x4 0x32 _ _ 0x00 |a, _, _| Ok(Some(self.hyper_load_u16(a, false)? as i16 as u64)), // hlv.h
+                User 0x4 0x32 _ _ 0x01 |a, _, _| Ok(Some(self.hyper_load_u16(a, false)? as u64)), // hlv.hu
+                User 0x4 0x32 _ _ 0x03 |a, _, _| Ok(Some(self.hyper_load_u16(a, true)? as u64)), // hlvx.hu
+                User 0x4 0x34 _ _ 0x00 |a, _, _| Ok(Some(self.hyper_load_u32(a, false)? as i32 as u64)), // hlv.w
+                User 0x4 0x34 _ _ 0x01 |a, _, _| Ok(Some(self.hyper_load_u32(a, false)? as u64)), // hlv.wu
+                User 0x4 0x34 _ _ 0x03 |a, _, _| Ok(Some(self.hyper_load_u32(a, true)? as u64)), // hlvx.wu
+                User 0x4 0x36 _ _ 0x00 |a, _, _| Ok(Some(self.hyper_load_u64(a, false)?)), // hlv.d
+                User 0x4 0x31 0x00 _ _ |a, b, _| self.hyper_store_u8(a, b as _).map(|_| None), // hsv.b
+                User 0x4 0x33 0x00 _ _ |a, b, _| self.hyper_store_u16(a, b as _).map(|_| None), // hsv.h
+                User 0x4 0x35 0x00 _ _ |a, b, _| self.hyper_store_u32(a, b as _).map(|_| None), // hsv.w
+                User 0x4 0x37 0x00 _ _ |a, b, _| self.hyper_store_u64(a, b).map(|_| None), // hsv.d
             ]),
             _ => return Err(Exception::IllegalInst),
         }
@@ -649,40 +746,56 @@ impl<'a> Cpu<'a> {
     }
 
     fn exception(&mut self, cause: Exception) {
-        println!("{cause:?} {:016x}", self.pc);
-        let epc = self.pc;
-        let tval = if self.trap(cause as _, csr::CSR_MEDELEG) {
-            csr::CSR_MTVAL
-        } else {
-            csr::CSR_STVAL
-        };
-
-        let cause = match cause {
-            Exception::IllegalInst => {
-                let i = self.mmu_load_xu32(epc - self.inst_len).unwrap_or(0);
-                println!("{i:08x}");
-                i as _
+        // println!("{cause:?} {:016x}", self.pc);
+        let epc = self.pc - self.inst_len;
+
+        let tv = match cause {
+            Exception::IllegalInst | Exception::VirtualInst => {
+                let i = self.mmu_load_xu32(epc).unwrap_or(0);
+                // println!("{i:08x}");
+                TrapVal { tval: i as _, ..Default::default() }
             },
-            _ => 0,
+            Exception::LoadAddrMisalign | Exception::StoreAddrMisalign
+                | Exception::InstAccessFault | Exception::LoadAccessFault | Exception::StoreAccessFault
+                | Exception::InstPageFault | Exception::LoadPageFault | Exception::StorePageFault
+                | Exception::InstGuestPageFault | Exception::LoadGuestPageFault
+                | Exception::StoreGuestPageFault => self.fault,
+            _ => TrapVal::default(),
         };
-        self.csr_write_cpu(tval, cause);
+
+        self.trap(cause as _, epc, tv);
     }
 
     fn interrupt(&mut self, cause: u64) {
         // println!("{cause} {:016x}", self.pc);
-        self.trap(cause | (1 << 63), csr::CSR_MIDELEG);
+        self.trap(cause | (1 << 63), self.pc, TrapVal::default());
     }
 
-    fn trap(&mut self, cause: u64, deleg: u64) -> bool {
+    fn trap(&mut self, cause: u64, epc: u64, tv: TrapVal) {
+        self.clear_reservation();
+
         let cause_bit = cause & 0x3f;
-        let deleg = self.csr_read_cpu(deleg);
+        let (mdeleg, hdeleg) = if cause >> 63 == 1 {
+            (csr::CSR_MIDELEG, csr::CSR_HIDELEG)
+        } else {
+            (csr::CSR_MEDELEG, csr::CSR_HEDELEG)
+        };
 
-        if self.mode != Mode::Machine && (deleg >> cause_bit) & 1 == 1 {
-            self.supervisor_trap(cause);
-            false
+        let mdeleg = self.csr_read_cpu(mdeleg);
+        let hdeleg = self.csr_read_cpu(hdeleg);
+
+        if self.process.is_some() {
+            self.linux_trap(cause, epc, tv);
+        } else if self.mode != Mode::Machine && (mdeleg >> cause_bit) & 1 == 1 {
+            if self.virt && (hdeleg >> cause_bit) & 1 == 1 {
+                self.virtual_supervisor_trap(cause, epc, tv);
+            } else {
+                self.supervisor_trap(cause, epc, tv);
+            }
+        } else if self.bus.hsm().is_some() {
+            self.sbi_trap(cause, epc, tv);
         } else {
-            self.machine_trap(cause);
-            true
+            self.machine_trap(cause, epc, tv);
         }
     }
 
@@ -696,27 +809,36 @@ impl<'a> Cpu<'a> {
         _ = self.write_pc(pc);
     }
 
-    fn machine_trap(&mut self, cause: u64) {
+    fn machine_trap(&mut self, cause: u64, epc: u64, tv: TrapVal) {
         self.csr_write_cpu(csr::CSR_MCAUSE, cause);
-        self.csr_write_cpu(csr::CSR_MEPC, self.pc - self.inst_len);
+        self.csr_write_cpu(csr::CSR_MEPC, epc);
+        self.csr_write_cpu(csr::CSR_MTVAL, tv.tval);
+        self.csr_write_cpu(csr::CSR_MTVAL2, tv.tval2);
+        self.csr_write_cpu(csr::CSR_MTINST, tv.tinst);
 
         let mut mstat = self.csr_read_cpu(csr::CSR_MSTATUS);
         mstat &= !0x1880;
         mstat |= (mstat & 8) << 4;
         mstat &= !8;
         mstat |= (self.mode as u64) << 11;
+        mstat &= !(3 << 38);
+        mstat |= (tv.gva as u64) << 38 | (self.virt as u64) << 39; // gVA, mPV
         // mstat &= !0x20000; // HACK: mPRV = 0
         self.csr_write_cpu(csr::CSR_MSTATUS, mstat);
 
         let mtvec = self.csr_read_cpu(csr::CSR_MTVEC);
         self.mtvec_jump(mtvec, cause);
         self.mode = Mode::Machine;
-        println!("{:?}", self.mode);
+        self.virt = false;
+        // println!("{:?}", self.mode);
     }
 
-    fn supervisor_trap(&mut self, cause: u64) {
+    fn supervisor_trap(&mut self, cause: u64, epc: u64, tv: TrapVal) {
         self.csr_write_cpu(csr::CSR_SCAUSE, cause);
-        self.csr_write_cpu(csr::CSR_SEPC, self.pc - self.inst_len);
+        self.csr_write_cpu(csr::CSR_SEPC, epc);
+        self.csr_write_cpu(csr::CSR_STVAL, tv.tval);
+        self.csr_write_cpu(csr::CSR_HTVAL, tv.tval2);
+        self.csr_write_cpu(csr::CSR_HTINST, tv.tinst);
 
         let mut mstat = self.csr_read_cpu(csr::CSR_MSTATUS);
         mstat &= !0x120;
@@ -725,36 +847,97 @@ impl<'a> Cpu<'a> {
         mstat |= (self.mode as u64) << 8;
         self.csr_write_cpu(csr::CSR_MSTATUS, mstat);
 
+        let mut hstat = self.csr_read_cpu(csr::CSR_HSTATUS);
+        hstat &= !0xc0;
+        hstat |= (tv.gva as u64) << 6 | (self.virt as u64) << 7; // gVA, sPV
+        if self.virt {
+            hstat &= !0x100;
+            hstat |= (self.mode as u64) << 8; // sPVP
+        }
+        self.csr_write_cpu(csr::CSR_HSTATUS, hstat);
+
         let stvec = self.csr_read_cpu(csr::CSR_STVEC);
         self.mtvec_jump(stvec, cause);
         self.mode = Mode::Supervisor;
-        println!("{:?}", self.mode);
+        self.virt = false;
+        // println!("{:?}", self.mode);
+    }
+
+    fn virtual_supervisor_trap(&mut self, mut cause: u64, epc: u64, tv: TrapVal) {
+        // vs-level interrupts are seen as the corresponding s-level interrupts by the guest
+        if cause >> 63 == 1 {
+            cause -= 1;
+        }
+
+        self.csr_write_cpu(csr::CSR_VSCAUSE, cause);
+        self.csr_write_cpu(csr::CSR_VSEPC, epc);
+        self.csr_write_cpu(csr::CSR_VSTVAL, tv.tval);
+
+        let mut vsstat = self.csr_read_cpu(csr::CSR_VSSTATUS);
+        vsstat &= !0x120;
+        vsstat |= (vsstat & 2) << 4;
+        vsstat &= !2;
+        vsstat |= (self.mode as u64) << 8;
+        self.csr_write_cpu(csr::CSR_VSSTATUS, vsstat);
+
+        let vstvec = self.csr_read_cpu(csr::CSR_VSTVEC);
+        self.mtvec_jump(vstvec, cause);
+        self.mode = Mode::Supervisor;
     }
 
     fn check_interrupts(&mut self) {
-        const CHECK_LIST: &[usize] = &[13, 11, 7, 3, 9, 5, 1];
-
-        let mut mip = self.csr_read_cpu(csr::CSR_MIP);
-        let mut mie = self.csr_read_cpu(csr::CSR_MIE);
-        let mstat_mie = self.csr_read_cpu(csr::CSR_MSTATUS) & 0x8 != 0;
-        let delg = self.csr_read_cpu(csr::CSR_MIDELEG);
-
-        for b in CHECK_LIST.into_iter() {
-            let p = (mip >> b) & 1 == 1;
-            let e = (mie >> b) & 1 == 1;
-            let delg = (delg >> b) & 1 == 1;
-
-            if ((self.mode == Mode::Machine && mstat_mie) || self.mode < Mode::Machine) && (p && e) && !delg {
-                self.interrupt(*b as _);
-                mip ^= 1 << b;
-                mie = 0;
-                self.csr_write_cpu(csr::CSR_MIP, mip);
-                self.csr_write_cpu(csr::CSR_MIE, mie);
-                break;
-            }
+        use Interrupt::*;
+        const CHECK_LIST: &[Interrupt] = &[
+            MachineExternal, MachineSoftware, MachineTimer,
+            SupervisorExternal, SupervisorSoftware, SupervisorTimer, SupervisorGuestExternal,
+            VirtualSupervisorExternal, VirtualSupervisorSoftware, VirtualSupervisorTimer,
+            CounterOverflow,
+        ];
+
+        let hw = self.bus.pending_interrupts(self.hartid);
+        self.csrs[csr::CSR_MIP as usize] &= !bus::HW_INTS;
+        self.csrs[csr::CSR_MIP as usize] |= hw | self.bus.take_interrupts(self.hartid);
+        if self.csrs[csr::CSR_MENVCFG as usize] & csr::MENVCFG_STCE != 0 {
+            let stip = self.bus.timer_reached(self.csrs[csr::CSR_STIMECMP as usize]);
+            self.csrs[csr::CSR_MIP as usize] = self.csrs[csr::CSR_MIP as usize] & !0x20 | (stip as u64) << 5;
+        }
+
+        let mip = self.csr_read_cpu(csr::CSR_MIP);
+        let mie = self.csr_read_cpu(csr::CSR_MIE);
+        let mstat = self.csr_read_cpu(csr::CSR_MSTATUS);
+        let vsstat = self.csr_read_cpu(csr::CSR_VSSTATUS);
+        let mideleg = self.csr_read_cpu(csr::CSR_MIDELEG);
+        let hideleg = self.csr_read_cpu(csr::CSR_HIDELEG);
+        let pending = mip & mie;
+
+        let m_on = self.mode < Mode::Machine || mstat & 0x8 != 0;
+        let s_on = self.virt || self.mode < Mode::Supervisor || (self.mode == Mode::Supervisor && mstat & 0x2 != 0);
+        let vs_on = self.virt && (self.mode < Mode::Supervisor || vsstat & 0x2 != 0);
+
+        let mut enabled = 0;
+        if m_on {
+            enabled |= pending & !mideleg;
+        }
+        if s_on {
+            enabled |= pending & mideleg & !hideleg;
+        }
+        if vs_on {
+            enabled |= pending & mideleg & hideleg;
+        }
+
+        if let Some(&i) = CHECK_LIST.iter().find(|&&i| (enabled >> i as u64) & 1 == 1) {
+            self.interrupt(i as u64);
         }
     }
 
+    pub fn reg(&self, r: usize) -> u64 {
+        self.read_reg(r)
+    }
+
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
     fn read_reg(&self, r: usize) -> u64 {
         if r == 0 { 0 } else { self.regs[r - 1] }
     }
@@ -787,7 +970,7 @@ impl Mode {
         match self {
             Self::User => Exception::EcallFromUser,
             Self::Supervisor => Exception::EcallFromSupervisor,
-            Self::Hypervisor => Exception::EcallFromReserved,
+            Self::Hypervisor => Exception::EcallFromVirtSupervisor,
             Self::Machine => Exception::EcallFromMachine,
         }
     }
@@ -815,7 +998,7 @@ pub(crate) enum Exception {
     StoreAccessFault = 7,
     EcallFromUser = 8,
     EcallFromSupervisor = 9,
-    EcallFromReserved = 10,
+    EcallFromVirtSupervisor = 10,
     EcallFromMachine = 11,
     InstPageFault = 12,
     LoadPageFault = 13,
@@ -824,15 +1007,33 @@ pub(crate) enum Exception {
     // reserved
     SoftwareCheck = 18,
     HardwareError = 19,
+    InstGuestPageFault = 20,
+    LoadGuestPageFault = 21,
+    VirtualInst = 22,
+    StoreGuestPageFault = 23,
+}
+
+/// Values written to `xtval`, `mtval2`/`htval` and `mtinst`/`htinst` on a trap
+#[derive(Debug, Default, Clone, Copy)]
+struct TrapVal {
+    tval: u64,
+    tval2: u64,
+    tinst: u64,
+    /// `tval` holds a guest virtual address
+    gva: bool,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub(crate) enum Interrupt {
-    SupervisorSoftwareInt = 1,
-    MachineSoftwareInt = 3,
-    SupervisorTimerInt = 5,
-    MachineTimerInt = 7,
-    SupervisorExternalInt = 9,
-    MachineExternalInt = 11,
-    CounterOverflowInt = 13,
+    SupervisorSoftware = 1,
+    VirtualSupervisorSoftware = 2,
+    MachineSoftware = 3,
+    SupervisorTimer = 5,
+    VirtualSupervisorTimer = 6,
+    MachineTimer = 7,
+    SupervisorExternal = 9,
+    VirtualSupervisorExternal = 10,
+    MachineExternal = 11,
+    SupervisorGuestExternal = 12,
+    CounterOverflow = 13,
 }
@@ -0,0 +1,355 @@
+//! Built-in SBI firmware. The harts come out of reset in S-mode at the entry, and what would trap
+//! to M-mode is handled here instead: `ecall`s from S-mode are SBI calls, and the machine timer is
+//! passed on as the supervisor timer. IPIs go through the ACLINT SSWI, the debug console is the
+//! first UART.
+
+use super::*;
+use crate::syscon::Exit;
+use std::sync::Mutex;
+
+const EXT_LEGACY_SET_TIMER: u64 = 0x00;
+const EXT_LEGACY_PUTCHAR: u64 = 0x01;
+const EXT_LEGACY_GETCHAR: u64 = 0x02;
+const EXT_LEGACY_CLEAR_IPI: u64 = 0x03;
+const EXT_LEGACY_SEND_IPI: u64 = 0x04;
+const EXT_LEGACY_SHUTDOWN: u64 = 0x08;
+const EXT_BASE: u64 = 0x10;
+const EXT_TIME: u64 = 0x5449_4d45;
+const EXT_IPI: u64 = 0x0073_5049;
+const EXT_RFENCE: u64 = 0x5246_4e43;
+const EXT_HSM: u64 = 0x0048_534d;
+const EXT_SRST: u64 = 0x5352_5354;
+const EXT_DBCN: u64 = 0x4442_434e;
+
+const SUCCESS: i64 = 0;
+const ERR_NOT_SUPPORTED: i64 = -2;
+const ERR_INVALID_PARAM: i64 = -3;
+const ERR_ALREADY_AVAILABLE: i64 = -6;
+
+/// SBI 2.0
+const SPEC_VERSION: u64 = 2 << 24;
+/// Not one of the registered implementation IDs
+const IMPL_ID: u64 = 0x7276_3634;
+const IMPL_VERSION: u64 = 1;
+
+// hart states of HSM
+const HART_STARTED: u64 = 0;
+const HART_STOPPED: u64 = 1;
+const HART_START_PENDING: u64 = 2;
+
+const SUSPEND_RETENTIVE: u64 = 0;
+const SUSPEND_NON_RETENTIVE: u64 = 0x8000_0000;
+
+/// Exceptions handled by S-mode, all but `ecall`s from S and M-mode
+const MEDELEG: u64 = 0xf0_b5ff;
+const MIP_STIP: u64 = 1 << 5;
+const MIP_SSIP: u64 = 1 << 1;
+const MIE_MTIE: u64 = 1 << 7;
+
+const UART_LSR: u64 = 5;
+const UART_LSR_DR: u8 = 0x01;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HartState {
+    Started,
+    Stopped,
+    StartPending { addr: u64, opaque: u64 },
+}
+
+/// HSM states of every hart, shared through the bus since harts start each other
+pub(crate) struct Hsm {
+    harts: Box<[Mutex<HartState>]>,
+}
+
+impl Hsm {
+    pub(crate) fn new(harts: usize) -> Self {
+        Self { harts: (0..harts).map(|_| Mutex::new(HartState::Stopped)).collect() }
+    }
+}
+
+impl<'a> Cpu<'a> {
+    fn hsm(&self) -> &'a Hsm {
+        self.bus.hsm().unwrap()
+    }
+
+    /// Puts the hart in the state the firmware hands over in: hart 0 in S-mode at the entry with
+    /// its hart ID in a0 and the device tree in a1, the others stopped until started over HSM
+    pub(crate) fn sbi_init(&mut self) {
+        self.csrs[csr::CSR_MEDELEG as usize] = MEDELEG;
+        self.csrs[csr::CSR_MIDELEG as usize] = csr::S_INTS;
+        self.csrs[csr::CSR_MCOUNTEREN as usize] = 7;
+
+        if self.hartid == 0 {
+            *self.hsm().harts[0].lock().unwrap() = HartState::Started;
+            self.sbi_enter(self.bus.entry(), self.bus.dtb());
+        } else {
+            *self.hsm().harts[self.hartid].lock().unwrap() = HartState::Stopped;
+            self.sbi_stopped = true;
+        }
+    }
+
+    /// Jumps to S-mode at `addr`, with the hart ID in a0 and `arg` in a1
+    fn sbi_enter(&mut self, addr: u64, arg: u64) {
+        self.mode = Mode::Supervisor;
+        self.virt = false;
+        self.csrs[csr::CSR_SATP as usize] = 0;
+        _ = self.flush_mapping();
+        self.csrs[csr::CSR_MSTATUS as usize] &= !0x2; // sstatus.SIE
+        _ = self.write_reg(10, self.hartid as u64);
+        _ = self.write_reg(11, arg);
+        _ = self.write_pc(addr);
+    }
+
+    /// Called instead of executing while the hart is stopped, starts it once another hart asked to
+    pub(crate) fn sbi_poll_start(&mut self) {
+        let mut state = self.hsm().harts[self.hartid].lock().unwrap();
+        let HartState::StartPending { addr, opaque } = *state else { return };
+        *state = HartState::Started;
+        drop(state);
+
+        self.sbi_stopped = false;
+        self.sbi_enter(addr, opaque);
+    }
+
+    /// Handles a trap that would go to M-mode
+    pub(super) fn sbi_trap(&mut self, cause: u64, epc: u64, tv: TrapVal) {
+        match cause {
+            c if c == Exception::EcallFromSupervisor as u64 => {
+                _ = self.write_pc(epc + 4);
+                self.sbi_call();
+            },
+            c if c == 1 << 63 | Interrupt::MachineTimer as u64 => {
+                self.csrs[csr::CSR_MIP as usize] |= MIP_STIP;
+                self.csrs[csr::CSR_MIE as usize] &= !MIE_MTIE;
+            },
+            _ => self.supervisor_trap(cause, epc, tv),
+        }
+    }
+
+    fn sbi_call(&mut self) {
+        let ext = self.read_reg(17);
+        let fid = self.read_reg(16);
+        let args = [10, 11, 12, 13, 14, 15].map(|r| self.read_reg(r));
+
+        // legacy extensions only return a0
+        if ext < EXT_BASE {
+            let ret = self.sbi_legacy(ext, args);
+            _ = self.write_reg(10, ret as u64);
+            return;
+        }
+
+        let ret = match ext {
+            EXT_BASE => self.sbi_base(fid, args),
+            EXT_TIME if fid == 0 => {
+                self.sbi_set_timer(args[0]);
+                (SUCCESS, 0)
+            },
+            EXT_IPI if fid == 0 => (self.sbi_send_ipi(args[0], args[1]), 0),
+            EXT_RFENCE if fid <= 6 => (self.sbi_check_mask(args[0], args[1]).map_or(ERR_INVALID_PARAM, |_| SUCCESS), 0),
+            EXT_HSM => match self.sbi_hsm(fid, args) {
+                Some(ret) => ret,
+                // stopped, or resumed somewhere else
+                None => return,
+            },
+            EXT_SRST if fid == 0 => (self.sbi_reset(args[0], args[1]), 0),
+            EXT_DBCN => self.sbi_dbcn(fid, args),
+            _ => (ERR_NOT_SUPPORTED, 0),
+        };
+
+        _ = self.write_reg(10, ret.0 as u64);
+        _ = self.write_reg(11, ret.1);
+    }
+
+    fn sbi_legacy(&mut self, ext: u64, args: [u64; 6]) -> i64 {
+        match ext {
+            EXT_LEGACY_SET_TIMER => {
+                self.sbi_set_timer(args[0]);
+                SUCCESS
+            },
+            EXT_LEGACY_PUTCHAR => {
+                self.console_write(args[0] as u8);
+                SUCCESS
+            },
+            EXT_LEGACY_GETCHAR => self.console_read().map_or(-1, |b| b as i64),
+            EXT_LEGACY_CLEAR_IPI => {
+                self.csrs[csr::CSR_MIP as usize] &= !MIP_SSIP;
+                SUCCESS
+            },
+            // the mask is in S-mode memory, a null pointer means every hart
+            EXT_LEGACY_SEND_IPI => match args[0] {
+                0 => self.sbi_send_ipi(0, u64::MAX),
+                p => match self.mmu_load_u64(p) {
+                    Ok(mask) => self.sbi_send_ipi(mask, 0),
+                    Err(_) => ERR_INVALID_PARAM,
+                },
+            },
+            // there are no TLBs or instruction caches to flush on other harts
+            0x05..=0x07 => SUCCESS,
+            EXT_LEGACY_SHUTDOWN => self.sbi_reset(0, 0),
+            _ => ERR_NOT_SUPPORTED,
+        }
+    }
+
+    fn sbi_base(&mut self, fid: u64, args: [u64; 6]) -> (i64, u64) {
+        let value = match fid {
+            0 => SPEC_VERSION,
+            1 => IMPL_ID,
+            2 => IMPL_VERSION,
+            3 => match args[0] {
+                EXT_BASE | EXT_TIME | EXT_IPI | EXT_RFENCE | EXT_HSM | EXT_SRST => 1,
+                EXT_DBCN => (self.bus.uarts() != 0) as u64,
+                EXT_LEGACY_SET_TIMER..=EXT_LEGACY_SHUTDOWN => 1,
+                _ => 0,
+            },
+            4 => self.csr_read_cpu(csr::CSR_MVENDORID),
+            5 => self.csr_read_cpu(csr::CSR_MARCHID),
+            6 => self.csr_read_cpu(csr::CSR_MIMPID),
+            _ => return (ERR_NOT_SUPPORTED, 0),
+        };
+
+        (SUCCESS, value)
+    }
+
+    /// Arms the machine timer of the hart, the supervisor timer goes pending when it fires
+    fn sbi_set_timer(&mut self, t: u64) {
+        self.csrs[csr::CSR_MIP as usize] &= !MIP_STIP;
+        _ = self.bus.store_u64(bus::CLINT_BASE + 0x4000 + 8 * self.hartid as u64, t);
+        self.csrs[csr::CSR_MIE as usize] |= MIE_MTIE;
+    }
+
+    /// The harts in a hart mask, `base` of -1 meaning every hart
+    fn sbi_check_mask(&self, mask: u64, base: u64) -> Option<Vec<usize>> {
+        let harts = self.hsm().harts.len();
+        if base == u64::MAX {
+            return Some((0..harts).collect());
+        }
+
+        let targets = (0..64).filter(|b| mask >> b & 1 == 1).map(|b| base.checked_add(b)).collect::<Option<Vec<_>>>()?;
+        targets.iter().all(|h| *h < harts as u64).then(|| targets.iter().map(|h| *h as usize).collect())
+    }
+
+    fn sbi_send_ipi(&mut self, mask: u64, base: u64) -> i64 {
+        let Some(targets) = self.sbi_check_mask(mask, base) else { return ERR_INVALID_PARAM };
+
+        for h in targets {
+            _ = self.bus.store_u32(bus::SSWI_BASE + 4 * h as u64, 1);
+        }
+
+        SUCCESS
+    }
+
+    /// Returns `None` for the calls that do not return to the caller
+    fn sbi_hsm(&mut self, fid: u64, args: [u64; 6]) -> Option<(i64, u64)> {
+        let harts = &self.hsm().harts;
+
+        Some(match fid {
+            // hart_start
+            0 => {
+                let Some(hart) = harts.get(args[0] as usize) else { return Some((ERR_INVALID_PARAM, 0)) };
+                let mut state = hart.lock().unwrap();
+                if *state != HartState::Stopped {
+                    return Some((ERR_ALREADY_AVAILABLE, 0));
+                }
+
+                *state = HartState::StartPending { addr: args[1], opaque: args[2] };
+                (SUCCESS, 0)
+            },
+            // hart_stop
+            1 => {
+                *harts[self.hartid].lock().unwrap() = HartState::Stopped;
+                self.sbi_stopped = true;
+                return None;
+            },
+            // hart_get_status
+            2 => match harts.get(args[0] as usize).map(|h| *h.lock().unwrap()) {
+                Some(HartState::Started) => (SUCCESS, HART_STARTED),
+                Some(HartState::Stopped) => (SUCCESS, HART_STOPPED),
+                Some(HartState::StartPending { .. }) => (SUCCESS, HART_START_PENDING),
+                None => (ERR_INVALID_PARAM, 0),
+            },
+            // hart_suspend, waking up at once is allowed for a retentive suspend
+            3 => match args[0] as u32 as u64 {
+                SUSPEND_RETENTIVE => (SUCCESS, 0),
+                SUSPEND_NON_RETENTIVE => {
+                    self.sbi_enter(args[1], args[2]);
+                    return None;
+                },
+                _ => (ERR_INVALID_PARAM, 0),
+            },
+            _ => (ERR_NOT_SUPPORTED, 0),
+        })
+    }
+
+    fn sbi_reset(&mut self, ty: u64, reason: u64) -> i64 {
+        let exit = match (ty as u32, reason as u32) {
+            (0, 0) => Exit::Pass,
+            (0, _) => Exit::Fail(1),
+            (1 | 2, _) => Exit::Reset,
+            _ => return ERR_INVALID_PARAM,
+        };
+
+        self.bus.request_exit(exit);
+        SUCCESS
+    }
+
+    fn sbi_dbcn(&mut self, fid: u64, args: [u64; 6]) -> (i64, u64) {
+        if self.bus.uarts() == 0 {
+            return (ERR_NOT_SUPPORTED, 0);
+        }
+
+        let addr = args[1] | args[2] << 32;
+        match fid {
+            // console_write
+            0 => {
+                let mut buf = vec![0; args[0].min(4096) as usize];
+                if self.bus.dma_read(addr, &mut buf).is_err() {
+                    return (ERR_INVALID_PARAM, 0);
+                }
+
+                for b in buf.iter() {
+                    self.console_write(*b);
+                }
+
+                (SUCCESS, buf.len() as u64)
+            },
+            // console_read
+            1 => {
+                let mut buf = Vec::new();
+                while buf.len() < args[0].min(4096) as usize {
+                    let Some(b) = self.console_read() else { break };
+                    buf.push(b);
+                }
+
+                match self.bus.dma_write(addr, &buf) {
+                    Ok(()) => (SUCCESS, buf.len() as u64),
+                    Err(_) => (ERR_INVALID_PARAM, 0),
+                }
+            },
+            // console_write_byte
+            2 => {
+                self.console_write(args[0] as u8);
+                (SUCCESS, 0)
+            },
+            _ => (ERR_NOT_SUPPORTED, 0),
+        }
+    }
+
+    fn console_write(&mut self, b: u8) {
+        if self.bus.uarts() != 0 {
+            _ = self.bus.store_u8(bus::UART_BASE, b);
+        }
+    }
+
+    fn console_read(&mut self) -> Option<u8> {
+        if self.bus.uarts() == 0 {
+            return None;
+        }
+
+        let lsr = self.bus.load_u8(bus::UART_BASE + UART_LSR).ok()?;
+        if lsr & UART_LSR_DR == 0 {
+            return None;
+        }
+
+        self.bus.load_u8(bus::UART_BASE).ok()
+    }
+}
@@ -0,0 +1,164 @@
+//! Store buffers for exploring the RVWMO memory model
+//!
+//! With a store buffer, stores to RAM are not written to memory right away but queued, and the
+//! scheduler makes them visible to the other harts at random points. This allows a store to be
+//! reordered after later loads (W->R) and after later stores to other addresses (W->W), which are
+//! the reorderings that the usual litmus tests look for. Loads are still performed in program
+//! order, so load->load and load->store reorderings are never observed.
+
+use super::*;
+use std::collections::VecDeque;
+
+// fence predecessor and successor bits
+const FENCE_R: u32 = 2;
+const FENCE_W: u32 = 1;
+const FENCE_TSO: u32 = 8;
+
+#[derive(Debug, Clone, Copy)]
+struct Store {
+    addr: u64,
+    size: u64,
+    data: u64,
+    /// Number of `fence w,w` executed before the store
+    epoch: u64,
+}
+
+impl Store {
+    fn overlaps(&self, addr: u64, size: u64) -> bool {
+        self.addr < addr + size && addr < self.addr + self.size
+    }
+}
+
+pub(crate) struct StoreBuffer {
+    stores: VecDeque<Store>,
+    epoch: u64,
+}
+
+impl StoreBuffer {
+    pub(crate) fn new() -> Self {
+        Self {
+            stores: VecDeque::new(),
+            epoch: 0,
+        }
+    }
+
+    /// Whether the store at `i` may become visible now: every older store to the same bytes, and
+    /// every store before a fence, has to be visible first
+    fn can_drain(&self, i: usize) -> bool {
+        let s = &self.stores[i];
+        self.stores.iter().take(i).all(|o| o.epoch == s.epoch && !o.overlaps(s.addr, s.size))
+    }
+}
+
+impl<'a> Cpu<'a> {
+    pub fn enable_store_buffer(&mut self) {
+        self.store_buffer = Some(StoreBuffer::new());
+    }
+
+    pub fn store_buffer_len(&self) -> usize {
+        self.store_buffer.as_ref().map_or(0, |b| b.stores.len())
+    }
+
+    /// Makes one of the stores that may become visible now visible, chosen by `pick`
+    pub fn drain_store(&mut self, pick: usize) {
+        let Some(b) = &mut self.store_buffer else { return };
+        let ready = (0..b.stores.len()).filter(|i| b.can_drain(*i)).collect::<Vec<_>>();
+
+        if !ready.is_empty() {
+            let s = b.stores.remove(ready[pick % ready.len()]).unwrap();
+            self.commit_store(s);
+        }
+    }
+
+    /// Makes every buffered store visible, in program order
+    pub fn drain_store_buffer(&mut self) {
+        while let Some(s) = self.store_buffer.as_mut().and_then(|b| b.stores.pop_front()) {
+            self.commit_store(s);
+        }
+    }
+
+    fn commit_store(&self, s: Store) {
+        _ = match s.size {
+            1 => self.bus.store_u8(s.addr, s.data as _),
+            2 => self.bus.store_u16(s.addr, s.data as _),
+            4 => self.bus.store_u32(s.addr, s.data as _),
+            _ => self.bus.store_u64(s.addr, s.data),
+        };
+
+        self.bus.break_reservations(self.hartid, s.addr, s.size);
+    }
+
+    /// Queues a store, returns whether it was buffered. Device accesses are never buffered, but
+    /// they do wait for every buffered store.
+    pub(crate) fn buffer_store(&mut self, pa: u64, size: u64, data: u64) -> bool {
+        let Some(b) = &mut self.store_buffer else { return false };
+
+        if !bus::RAM_RANGE.contains(&pa) || !bus::RAM_RANGE.contains(&(pa + size - 1)) {
+            self.drain_store_buffer();
+            return false;
+        }
+
+        b.stores.push_back(Store { addr: pa, size, data, epoch: b.epoch });
+        true
+    }
+
+    /// Prepares a load: device loads wait for every buffered store
+    pub(crate) fn before_load(&mut self, pa: u64) {
+        if self.store_buffer.is_some() && !bus::RAM_RANGE.contains(&pa) {
+            self.drain_store_buffer();
+        }
+    }
+
+    /// Applies the buffered stores of this hart to a value loaded from memory
+    pub(crate) fn forward_load(&self, pa: u64, size: u64, mut v: u64) -> u64 {
+        let Some(b) = &self.store_buffer else { return v };
+
+        for s in b.stores.iter().filter(|s| s.overlaps(pa, size)) {
+            for i in 0..s.size {
+                let a = s.addr + i;
+                if (pa..pa + size).contains(&a) {
+                    let shift = (a - pa) * 8;
+                    v = (v & !(0xff << shift)) | (((s.data >> (i * 8)) & 0xff) << shift);
+                }
+            }
+        }
+
+        v
+    }
+
+    /// Orders the buffered stores before an atomic access: stores to the same address always
+    /// become visible first, every other store too with `.rl`
+    pub(crate) fn order_atomic(&mut self, pa: u64, size: u64, rl: bool) {
+        let Some(b) = &self.store_buffer else { return };
+
+        if rl {
+            self.drain_store_buffer();
+            return;
+        }
+
+        if let Some(last) = b.stores.iter().rposition(|s| s.overlaps(pa, size)) {
+            for _ in 0..=last {
+                let s = self.store_buffer.as_mut().unwrap().stores.pop_front().unwrap();
+                self.commit_store(s);
+            }
+        }
+    }
+
+    pub(crate) fn fence(&mut self, inst: u32) {
+        core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+
+        let Some(b) = &mut self.store_buffer else { return };
+        let fm = inst >> 28;
+        let pred = (inst >> 24) & 0xf;
+        let succ = (inst >> 20) & 0xf;
+
+        if (inst >> 12) & 7 == 1 {
+            // fence.i
+            self.drain_store_buffer();
+        } else if pred & FENCE_W != 0 && succ & FENCE_R != 0 && fm != FENCE_TSO {
+            self.drain_store_buffer();
+        } else if pred & FENCE_W != 0 && succ & FENCE_W != 0 {
+            b.epoch += 1;
+        }
+    }
+}
@@ -0,0 +1,399 @@
+//! Copy-on-write overlays: a sparse file on top of a base image that only holds the clusters
+//! written to it, reads of everything else fall through to the base
+//!
+//! The first cluster of the file is the header, followed by the cluster table with one entry per
+//! cluster of the disk: `IN_BASE`, `ZERO`, or the offset of the cluster data in the file. Data
+//! clusters are appended in the order they are first written.
+
+use super::*;
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8; 8] = b"RV64COW\0";
+const VERSION: u32 = 1;
+const CLUSTER_SIZE: u64 = 64 * 1024;
+
+const IN_BASE: u64 = 0;
+const ZERO: u64 = 1;
+
+/// The overlay holds a state something refers to, it can only be the base of another overlay
+const FLAG_FROZEN: u32 = 1;
+
+// header fields
+const H_VERSION: usize = 8;
+const H_FLAGS: usize = 12;
+const H_SIZE: usize = 16;
+const H_BASE_LEN: usize = 24;
+const H_BASE: usize = 28;
+
+pub struct Cow {
+    file: std::fs::File,
+    path: PathBuf,
+    base: Box<dyn Disk>,
+    base_path: PathBuf,
+    writable: bool,
+
+    size: u64,
+    flags: u32,
+    table: Vec<u64>,
+    /// Where the next data cluster goes
+    end: u64,
+}
+
+fn invalid(msg: &str) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Whether a file starts like an overlay
+pub fn is_overlay(path: impl AsRef<Path>) -> bool {
+    let mut magic = [0; 8];
+    std::fs::File::open(path).and_then(|f| f.read_exact_at(&mut magic, 0)).is_ok() && &magic == MAGIC
+}
+
+impl Cow {
+    /// Creates an empty overlay on top of `base`, a raw image or another overlay
+    pub fn create(path: impl AsRef<Path>, base: impl AsRef<Path>) -> std::io::Result<Self> {
+        let base_path = base.as_ref().canonicalize()?;
+        let bytes = base_path.as_os_str().as_encoded_bytes();
+        if H_BASE + bytes.len() > CLUSTER_SIZE as usize {
+            return Err(invalid("base path too long"));
+        }
+
+        let size = open_image(&base_path, false)?.size();
+        let mut header = vec![0; H_BASE + bytes.len()];
+        header[..8].copy_from_slice(MAGIC);
+        header[H_VERSION..H_VERSION + 4].copy_from_slice(&VERSION.to_le_bytes());
+        header[H_SIZE..H_SIZE + 8].copy_from_slice(&size.to_le_bytes());
+        header[H_BASE_LEN..H_BASE_LEN + 4].copy_from_slice(&(bytes.len() as u32).to_le_bytes());
+        header[H_BASE..].copy_from_slice(bytes);
+
+        let file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+        file.write_all_at(&header, 0)?;
+        // the table is all IN_BASE, which is a hole until something is written
+        file.set_len(data_start(size))?;
+        file.sync_all()?;
+
+        Self::open(path, true, false)
+    }
+
+    /// Opens an overlay, with its base opened read-write too if the overlay is going to be
+    /// committed
+    pub fn open(path: impl AsRef<Path>, writable: bool, base_writable: bool) -> std::io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(writable).open(&path)?;
+
+        let mut header = vec![0; H_BASE];
+        file.read_exact_at(&mut header, 0)?;
+        let field = |at: usize, n: usize| header[at..at + n].iter().rev().fold(0, |v, b| v << 8 | *b as u64);
+
+        if &header[..8] != MAGIC {
+            return Err(invalid("not an overlay"));
+        }
+        if field(H_VERSION, 4) != VERSION as u64 {
+            return Err(invalid("unsupported overlay version"));
+        }
+
+        let flags = field(H_FLAGS, 4) as u32;
+        if writable && flags & FLAG_FROZEN != 0 {
+            return Err(std::io::Error::new(ErrorKind::PermissionDenied, "overlay is frozen"));
+        }
+
+        let size = field(H_SIZE, 8);
+        let mut base = vec![0; field(H_BASE_LEN, 4) as usize];
+        file.read_exact_at(&mut base, H_BASE as u64)?;
+        let base_path = PathBuf::from(String::from_utf8(base).map_err(|_| invalid("bad base path"))?);
+
+        let mut raw = vec![0; clusters(size) as usize * 8];
+        file.read_exact_at(&mut raw, CLUSTER_SIZE)?;
+        let table = raw.chunks_exact(8).map(|e| u64::from_le_bytes(e.try_into().unwrap())).collect::<Vec<_>>();
+        let end = file.metadata()?.len().max(data_start(size)).next_multiple_of(CLUSTER_SIZE);
+
+        Ok(Self {
+            base: open_image(&base_path, base_writable)?,
+            path: path.as_ref().to_path_buf(),
+            file,
+            base_path,
+            writable,
+
+            size,
+            flags,
+            table,
+            end,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn base_path(&self) -> &Path {
+        &self.base_path
+    }
+
+    fn write_flags(&self) -> std::io::Result<()> {
+        self.file.write_all_at(&self.flags.to_le_bytes(), H_FLAGS as u64)
+    }
+
+    fn set_entry(&mut self, cluster: u64, entry: u64) -> std::io::Result<()> {
+        self.table[cluster as usize] = entry;
+        self.file.write_all_at(&entry.to_le_bytes(), CLUSTER_SIZE + cluster * 8)
+    }
+
+    /// Reads a whole cluster, the part past the end of the disk reads as zeros
+    fn read_cluster(&mut self, cluster: u64) -> std::io::Result<Vec<u8>> {
+        let mut data = vec![0; CLUSTER_SIZE as usize];
+        let len = (self.size - cluster * CLUSTER_SIZE).min(CLUSTER_SIZE) as usize;
+        self.read_at(cluster * CLUSTER_SIZE, &mut data[..len])?;
+        Ok(data)
+    }
+
+    fn check_writable(&self) -> std::io::Result<()> {
+        if self.writable {
+            Ok(())
+        } else {
+            Err(std::io::Error::new(ErrorKind::PermissionDenied, "overlay is read only"))
+        }
+    }
+
+    /// Writes every cluster of the overlay to the base, then empties the overlay
+    pub fn commit(&mut self) -> std::io::Result<()> {
+        self.check_writable()?;
+
+        for cluster in 0..self.table.len() as u64 {
+            if self.table[cluster as usize] == IN_BASE {
+                continue;
+            }
+
+            let data = self.read_cluster(cluster)?;
+            let len = (self.size - cluster * CLUSTER_SIZE).min(CLUSTER_SIZE) as usize;
+            self.base.write_at(cluster * CLUSTER_SIZE, &data[..len])?;
+        }
+
+        self.base.flush()?;
+        self.discard_all()
+    }
+
+    /// Drops everything written to the overlay
+    pub fn discard_all(&mut self) -> std::io::Result<()> {
+        self.check_writable()?;
+
+        // shrinking to the header and growing again turns the table back into a hole
+        self.file.set_len(CLUSTER_SIZE)?;
+        self.file.set_len(data_start(self.size))?;
+        self.table.fill(IN_BASE);
+        self.end = data_start(self.size);
+        self.file.sync_all()
+    }
+
+    /// Freezes the current state of the overlay, and continues in a new overlay at `path` on top of
+    /// it. Returns the path of the frozen overlay, which can no longer be written, committed or
+    /// discarded.
+    pub fn checkpoint(&mut self, path: impl AsRef<Path>) -> std::io::Result<PathBuf> {
+        self.check_writable()?;
+
+        self.flags |= FLAG_FROZEN;
+        self.write_flags()?;
+        self.file.sync_all()?;
+
+        let frozen = self.path.clone();
+        *self = Self::create(path, &frozen)?;
+        Ok(frozen)
+    }
+}
+
+fn clusters(size: u64) -> u64 {
+    size.div_ceil(CLUSTER_SIZE)
+}
+
+/// Offset of the first data cluster
+fn data_start(size: u64) -> u64 {
+    CLUSTER_SIZE + (clusters(size) * 8).next_multiple_of(CLUSTER_SIZE)
+}
+
+impl Disk for Cow {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&mut self, off: u64, buf: &mut [u8]) -> std::io::Result<()> {
+        for (cluster, start, r) in split(off, buf.len(), CLUSTER_SIZE) {
+            match self.table.get(cluster as usize) {
+                Some(&IN_BASE) => self.base.read_at(off + r.start as u64, &mut buf[r])?,
+                Some(&ZERO) => buf[r].fill(0),
+                Some(&data) => self.file.read_exact_at(&mut buf[r], data + start as u64)?,
+                None => return Err(ErrorKind::UnexpectedEof.into()),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_at(&mut self, off: u64, buf: &[u8]) -> std::io::Result<()> {
+        self.check_writable()?;
+
+        for (cluster, start, r) in split(off, buf.len(), CLUSTER_SIZE) {
+            match self.table.get(cluster as usize) {
+                None => return Err(ErrorKind::UnexpectedEof.into()),
+                Some(&data) if data != IN_BASE && data != ZERO => {
+                    self.file.write_all_at(&buf[r], data + start as u64)?;
+                },
+                Some(_) => {
+                    // copy the cluster up before changing it
+                    let mut data = if r.len() == CLUSTER_SIZE as usize {
+                        buf[r.clone()].to_vec()
+                    } else {
+                        self.read_cluster(cluster)?
+                    };
+
+                    data[start..start + r.len()].copy_from_slice(&buf[r]);
+                    let at = self.end;
+                    self.file.write_all_at(&data, at)?;
+                    self.end += CLUSTER_SIZE;
+                    self.set_entry(cluster, at)?;
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.sync_data()
+    }
+
+    fn discard(&mut self, off: u64, len: u64) -> std::io::Result<()> {
+        use std::os::fd::AsRawFd;
+        self.check_writable()?;
+
+        for (cluster, start, r) in split(off, len as usize, CLUSTER_SIZE) {
+            if start != 0 || r.len() != CLUSTER_SIZE as usize {
+                self.write_at(off + r.start as u64, &vec![0; r.len()])?;
+                continue;
+            }
+
+            let old = self.table[cluster as usize];
+            self.set_entry(cluster, ZERO)?;
+
+            if old != IN_BASE && old != ZERO {
+                // give the space of the old data back to the host, the file stays sparse
+                let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+                unsafe { libc::fallocate(self.file.as_raw_fd(), mode, old as _, CLUSTER_SIZE as _) };
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: u64 = 3 * CLUSTER_SIZE + 4096;
+
+    /// A fresh directory under the system temporary directory
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("emu-cow-{name}-{}", std::process::id()));
+        _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn pattern() -> Vec<u8> {
+        (0..SIZE).map(|i| (i % 251) as u8 + 1).collect()
+    }
+
+    fn contents(disk: &mut dyn Disk) -> Vec<u8> {
+        let mut buf = vec![0; disk.size() as usize];
+        disk.read_at(0, &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn overlay() {
+        let dir = temp_dir("overlay");
+        let (base, path) = (dir.join("base.img"), dir.join("overlay.cow"));
+        std::fs::write(&base, pattern()).unwrap();
+
+        // reads fall through to the base
+        let mut cow = Cow::create(&path, &base).unwrap();
+        assert!(is_overlay(&path) && !is_overlay(&base));
+        assert_eq!(cow.size(), SIZE);
+        assert_eq!(contents(&mut cow), pattern());
+
+        // a partial write copies the rest of the cluster up, the base is not touched
+        cow.write_at(1000, &[0xee; 100]).unwrap();
+        let mut expect = pattern();
+        expect[1000..1100].fill(0xee);
+        assert_eq!(contents(&mut cow), expect);
+        assert!(cow.table[0] >= data_start(SIZE));
+        assert_eq!(std::fs::read(&base).unwrap(), pattern());
+
+        // so does one in the last cluster, which is past the end of the disk in part
+        cow.write_at(SIZE - 8, &[0xdd; 8]).unwrap();
+        expect[SIZE as usize - 8..].fill(0xdd);
+
+        // whole clusters are discarded to ZERO entries, the rest are written with zeros
+        cow.discard(CLUSTER_SIZE, CLUSTER_SIZE + 512).unwrap();
+        expect[CLUSTER_SIZE as usize..2 * CLUSTER_SIZE as usize + 512].fill(0);
+        assert_eq!(cow.table[1], ZERO);
+        assert!(cow.table[2] >= data_start(SIZE));
+        assert!(cow.table[3] >= data_start(SIZE));
+
+        // and it all survives reopening
+        drop(cow);
+        let mut cow = Cow::open(&path, false, false).unwrap();
+        assert_eq!(contents(&mut cow), expect);
+        assert_eq!(cow.write_at(0, &[0]).unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert_eq!(cow.base_path(), base.canonicalize().unwrap());
+
+        // discarding the overlay goes back to the base
+        Cow::open(&path, true, false).unwrap().discard_all().unwrap();
+        let mut cow = Cow::open(&path, true, false).unwrap();
+        assert_eq!(contents(&mut cow), pattern());
+        assert!(cow.table.iter().all(|&e| e == IN_BASE));
+
+        // committing writes the overlay to the base and empties it
+        cow.write_at(1000, &[0xee; 100]).unwrap();
+        cow.discard(CLUSTER_SIZE, CLUSTER_SIZE).unwrap();
+        drop(cow);
+        Cow::open(&path, true, true).unwrap().commit().unwrap();
+        let mut expect = pattern();
+        expect[1000..1100].fill(0xee);
+        expect[CLUSTER_SIZE as usize..2 * CLUSTER_SIZE as usize].fill(0);
+        assert_eq!(std::fs::read(&base).unwrap(), expect);
+        let mut cow = Cow::open(&path, true, false).unwrap();
+        assert!(cow.table.iter().all(|&e| e == IN_BASE));
+        assert_eq!(contents(&mut cow), expect);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn checkpoint() {
+        let dir = temp_dir("checkpoint");
+        let (base, path, next) = (dir.join("base.img"), dir.join("first.cow"), dir.join("next.cow"));
+        std::fs::write(&base, pattern()).unwrap();
+
+        let mut cow = Cow::create(&path, &base).unwrap();
+        cow.write_at(0, &[1; 16]).unwrap();
+        assert_eq!(cow.checkpoint(&next).unwrap(), path);
+
+        // the new overlay starts from the frozen state and changes go to it only
+        assert_eq!(cow.path(), next);
+        assert_eq!(cow.base_path(), path.canonicalize().unwrap());
+        cow.write_at(16, &[2; 16]).unwrap();
+        let mut expect = pattern();
+        expect[..16].fill(1);
+        let mut frozen = Cow::open(&path, false, false).unwrap();
+        assert_eq!(contents(&mut frozen), expect);
+        expect[16..32].fill(2);
+        assert_eq!(contents(&mut cow), expect);
+
+        // the frozen overlay cannot be written, committed or discarded any more
+        for base_writable in [false, true] {
+            let e = Cow::open(&path, true, base_writable).err().unwrap();
+            assert_eq!(e.kind(), ErrorKind::PermissionDenied);
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,227 @@
+//! Host side of block devices
+
+mod cow;
+
+pub use cow::{is_overlay, Cow};
+
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::ErrorKind;
+use std::os::unix::fs::FileExt;
+
+pub const SECTOR_SIZE: u64 = 512;
+
+/// Zeros are written this many bytes at a time, whatever the length of the range
+const ZERO_CHUNK: u64 = 64 * 1024;
+
+pub trait Disk: Send {
+    /// Size in bytes
+    fn size(&self) -> u64;
+    fn read_at(&mut self, off: u64, buf: &mut [u8]) -> std::io::Result<()>;
+    fn write_at(&mut self, off: u64, buf: &[u8]) -> std::io::Result<()>;
+    fn flush(&mut self) -> std::io::Result<()>;
+    /// Tells the disk that a range is no longer used, after which it reads as zeros
+    fn discard(&mut self, off: u64, len: u64) -> std::io::Result<()> {
+        write_zeros(self, off, len)
+    }
+}
+
+/// Writes zeros over a range of a disk
+fn write_zeros<D: Disk + ?Sized>(disk: &mut D, off: u64, len: u64) -> std::io::Result<()> {
+    let zeros = [0; ZERO_CHUNK as usize];
+    let end = off.checked_add(len).ok_or(ErrorKind::InvalidInput)?;
+
+    for at in (off..end).step_by(ZERO_CHUNK as usize) {
+        disk.write_at(at, &zeros[..(end - at).min(ZERO_CHUNK) as usize])?;
+    }
+
+    Ok(())
+}
+
+/// A disk with its options, as given by `--drive`
+pub struct Drive {
+    pub disk: Box<dyn Disk>,
+    pub read_only: bool,
+    /// Returned by the get-id request of virtio-blk
+    pub serial: String,
+}
+
+/// Parses a drive spec, a comma separated list of `file=PATH`, `format=raw|cow`, `overlay=PATH`,
+/// `readonly=on` and `snapshot=on`. With `overlay=PATH` writes go to a copy-on-write overlay on
+/// top of the file, created if it does not exist yet. With `snapshot=on` writes stay in memory and
+/// are lost at exit.
+pub fn open(spec: &str) -> std::io::Result<Drive> {
+    let invalid = |msg: String| std::io::Error::new(ErrorKind::InvalidInput, msg);
+    let (mut file, mut format, mut overlay, mut read_only, mut snapshot) = (None, None, None, false, false);
+
+    for opt in spec.split(',') {
+        let (key, val) = opt.split_once('=').unwrap_or((opt, "on"));
+        let on = matches!(val, "on" | "true" | "yes" | "1");
+
+        match key {
+            "file" => file = Some(val),
+            "format" if val == "raw" || val == "cow" => format = Some(val),
+            "overlay" => overlay = Some(val),
+            "readonly" => read_only = on,
+            "snapshot" => snapshot = on,
+            _ => return Err(invalid(format!("unknown drive option `{opt}`"))),
+        }
+    }
+
+    let path = file.ok_or_else(|| invalid("drive without a file".into()))?;
+    let writable = !read_only && !snapshot;
+
+    let mut disk: Box<dyn Disk> = match (overlay, format) {
+        (Some(o), _) if std::path::Path::new(o).exists() => {
+            let cow = Cow::open(o, writable, false)?;
+            if cow.base_path() != std::path::Path::new(path).canonicalize()? {
+                return Err(invalid(format!("overlay `{o}` is not on top of `{path}`")));
+            }
+
+            Box::new(cow)
+        },
+        (Some(o), _) => Box::new(Cow::create(o, path)?),
+        (None, Some("raw")) => Box::new(Raw(OpenOptions::new().read(true).write(writable).open(path)?)),
+        (None, Some(_)) => Box::new(Cow::open(path, writable, false)?),
+        (None, None) => open_image(path, writable)?,
+    };
+
+    if snapshot {
+        disk = Box::new(Throwaway::new(disk));
+    }
+
+    let serial = std::path::Path::new(path).file_name().map_or(String::new(), |n| n.to_string_lossy().into());
+    Ok(Drive { disk, read_only, serial })
+}
+
+/// Opens a raw image or an overlay, telling them apart by the magic of overlays
+pub fn open_image(path: impl AsRef<std::path::Path>, writable: bool) -> std::io::Result<Box<dyn Disk>> {
+    if is_overlay(&path) {
+        return Ok(Box::new(Cow::open(path, writable, false)?));
+    }
+
+    Ok(Box::new(Raw(OpenOptions::new().read(true).write(writable).open(path)?)))
+}
+
+/// A raw image file
+pub struct Raw(pub std::fs::File);
+
+impl Disk for Raw {
+    fn size(&self) -> u64 {
+        self.0.metadata().map_or(0, |m| m.len())
+    }
+
+    fn read_at(&mut self, off: u64, buf: &mut [u8]) -> std::io::Result<()> {
+        self.0.read_exact_at(buf, off)
+    }
+
+    fn write_at(&mut self, off: u64, buf: &[u8]) -> std::io::Result<()> {
+        self.0.write_all_at(buf, off)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.sync_data()
+    }
+
+    fn discard(&mut self, off: u64, len: u64) -> std::io::Result<()> {
+        use std::os::fd::AsRawFd;
+
+        let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+        if unsafe { libc::fallocate(self.0.as_raw_fd(), mode, off as _, len as _) } == 0 {
+            return Ok(());
+        }
+
+        // not every filesystem can punch holes
+        write_zeros(self, off, len)
+    }
+}
+
+/// Keeps the writes to another disk in memory, so that it is never modified
+pub struct Throwaway {
+    base: Box<dyn Disk>,
+    sectors: HashMap<u64, Box<[u8]>>,
+}
+
+impl Throwaway {
+    pub fn new(base: Box<dyn Disk>) -> Self {
+        Self { base, sectors: HashMap::new() }
+    }
+}
+
+/// Splits a byte range into the parts that fall in each `unit` sized block: the block, the offset
+/// in it and the range in the buffer
+fn split(off: u64, len: usize, unit: u64) -> impl Iterator<Item = (u64, usize, core::ops::Range<usize>)> {
+    let mut done = 0;
+
+    core::iter::from_fn(move || {
+        if done == len {
+            return None;
+        }
+
+        let addr = off + done as u64;
+        let start = (addr % unit) as usize;
+        let n = (unit as usize - start).min(len - done);
+        done += n;
+        Some((addr / unit, start, done - n..done))
+    })
+}
+
+impl Disk for Throwaway {
+    fn size(&self) -> u64 {
+        self.base.size()
+    }
+
+    fn read_at(&mut self, off: u64, buf: &mut [u8]) -> std::io::Result<()> {
+        for (sector, start, r) in split(off, buf.len(), SECTOR_SIZE) {
+            match self.sectors.get(&sector) {
+                Some(s) => buf[r.clone()].copy_from_slice(&s[start..start + r.len()]),
+                None => self.base.read_at(sector * SECTOR_SIZE + start as u64, &mut buf[r])?,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_at(&mut self, off: u64, buf: &[u8]) -> std::io::Result<()> {
+        for (sector, start, r) in split(off, buf.len(), SECTOR_SIZE) {
+            if !self.sectors.contains_key(&sector) {
+                let mut s = vec![0; SECTOR_SIZE as usize];
+                self.base.read_at(sector * SECTOR_SIZE, &mut s)?;
+                self.sectors.insert(sector, s.into());
+            }
+
+            self.sectors.get_mut(&sector).unwrap()[start..start + r.len()].copy_from_slice(&buf[r]);
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn discard_reads_as_zeros() {
+        let path = std::env::temp_dir().join(format!("emu-disk-raw-{}", std::process::id()));
+        std::fs::write(&path, vec![0xaa; 1 << 20]).unwrap();
+        let raw = Raw(OpenOptions::new().read(true).write(true).open(&path).unwrap());
+        let mut disk = Throwaway::new(Box::new(raw));
+
+        // more than one chunk of zeros, not aligned to one
+        disk.discard(1000, 3 * ZERO_CHUNK + 24).unwrap();
+        let mut buf = vec![0; 1 << 20];
+        disk.read_at(0, &mut buf).unwrap();
+        assert!(buf[..1000].iter().all(|&b| b == 0xaa));
+        assert!(buf[1000..1024 + 3 * ZERO_CHUNK as usize].iter().all(|&b| b == 0));
+        assert!(buf[1024 + 3 * ZERO_CHUNK as usize..].iter().all(|&b| b == 0xaa));
+
+        // the base is not touched
+        assert!(std::fs::read(&path).unwrap().iter().all(|&b| b == 0xaa));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
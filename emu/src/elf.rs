@@ -0,0 +1,130 @@
+//! Loader of RISC-V ELF64 executables, like the xv6 kernel, which go into RAM at the physical
+//! addresses of their segments
+
+use crate::bus::*;
+use std::io::{Error, ErrorKind, Result};
+
+pub const MAGIC: [u8; 4] = *b"\x7fELF";
+const CLASS_64: u8 = 2;
+const DATA_LE: u8 = 1;
+const TYPE_EXEC: u16 = 2;
+const MACHINE_RISCV: u16 = 0xf3;
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+const PT_LOAD: u32 = 1;
+const PT_INTERP: u32 = 3;
+
+fn invalid(msg: impl Into<String>) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.into())
+}
+
+/// A loadable segment
+#[derive(Debug, Clone)]
+pub struct Segment<'a> {
+    /// Offset in the file
+    pub offset: u64,
+    pub vaddr: u64,
+    pub paddr: u64,
+    /// Size in memory, past the end of `data` is zeroed
+    pub memsz: u64,
+    /// `PF_X`, `PF_W` and `PF_R`
+    pub flags: u32,
+    pub data: &'a [u8],
+}
+
+pub struct Elf<'a> {
+    pub entry: u64,
+    pub segments: Vec<Segment<'a>>,
+    /// Offset in the file, size and number of the program headers, which Linux programs are told
+    pub phoff: u64,
+    pub phentsize: u16,
+    pub phnum: u16,
+    /// Has a `PT_INTERP`, so it needs a dynamic linker to run
+    pub interp: bool,
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self> {
+        let ehdr = data.get(..EHDR_SIZE).filter(|h| h[..4] == MAGIC).ok_or_else(|| invalid("not an ELF file"))?;
+        let u16_at = |b: &[u8], off: usize| u16::from_le_bytes(b[off..off + 2].try_into().unwrap());
+        let u32_at = |b: &[u8], off: usize| u32::from_le_bytes(b[off..off + 4].try_into().unwrap());
+        let u64_at = |b: &[u8], off: usize| u64::from_le_bytes(b[off..off + 8].try_into().unwrap());
+
+        if ehdr[4] != CLASS_64 || ehdr[5] != DATA_LE {
+            return Err(invalid("not a little-endian ELF64 file"));
+        }
+        if u16_at(ehdr, 18) != MACHINE_RISCV {
+            return Err(invalid("not a RISC-V ELF file"));
+        }
+        if u16_at(ehdr, 16) != TYPE_EXEC {
+            return Err(invalid("not an executable"));
+        }
+
+        let phoff = u64_at(ehdr, 32) as usize;
+        let phentsize = u16_at(ehdr, 54) as usize;
+        let phnum = u16_at(ehdr, 56) as usize;
+        if phentsize < PHDR_SIZE {
+            return Err(invalid("bad program header size"));
+        }
+
+        let mut segments = Vec::new();
+        let mut interp = false;
+        for i in 0..phnum {
+            let phdr = i
+                .checked_mul(phentsize)
+                .and_then(|off| off.checked_add(phoff))
+                .and_then(|off| data.get(off..off.checked_add(PHDR_SIZE)?))
+                .ok_or_else(|| invalid("program header out of the file"))?;
+            interp |= u32_at(phdr, 0) == PT_INTERP;
+            if u32_at(phdr, 0) != PT_LOAD {
+                continue;
+            }
+
+            let (offset, filesz, memsz) = (u64_at(phdr, 8) as usize, u64_at(phdr, 32) as usize, u64_at(phdr, 40));
+            if filesz as u64 > memsz {
+                return Err(invalid("segment larger in the file than in memory"));
+            }
+
+            segments.push(Segment {
+                offset: offset as u64,
+                vaddr: u64_at(phdr, 16),
+                paddr: u64_at(phdr, 24),
+                memsz,
+                flags: u32_at(phdr, 4),
+                data: offset
+                    .checked_add(filesz)
+                    .and_then(|end| data.get(offset..end))
+                    .ok_or_else(|| invalid("segment out of the file"))?,
+            });
+        }
+
+        Ok(Self {
+            entry: u64_at(ehdr, 24),
+            segments,
+            phoff: phoff as u64,
+            phentsize: phentsize as u16,
+            phnum: phnum as u16,
+            interp,
+        })
+    }
+
+    /// End of the highest segment, in physical addresses
+    pub fn end(&self) -> u64 {
+        self.segments.iter().map(|s| s.paddr.saturating_add(s.memsz)).max().unwrap_or(0)
+    }
+
+    /// Copies the segments to RAM at their physical addresses
+    pub fn load(&self, bus: &Bus) -> Result<()> {
+        for s in self.segments.iter() {
+            let out_of_ram = || invalid(format!("segment at {:#x} is not in RAM", s.paddr));
+            if s.paddr < RAM_BASE || s.paddr.checked_add(s.memsz).is_none_or(|end| end > RAM_BASE + RAM_SIZE) {
+                return Err(out_of_ram());
+            }
+
+            bus.load(s.paddr, s.data).map_err(|_| out_of_ram())?;
+            bus.dma_write(s.paddr + s.data.len() as u64, &vec![0; (s.memsz - s.data.len() as u64) as usize]).map_err(|_| out_of_ram())?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,303 @@
+//! Flattened device tree of the machine, built from what is actually on the bus so that guests can
+//! find their devices
+
+use crate::bus::*;
+use core::ops::Range;
+use std::collections::HashMap;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_END: u32 = 9;
+/// Size of the header, the memory reservation block follows it
+const HEADER_SIZE: usize = 40;
+
+/// Clock of the UARTs, as on QEMU's virt machine
+const UART_CLOCK: u32 = 3_686_400;
+
+// phandles, the interrupt controllers of the harts come after these
+const PHANDLE_PLIC: u32 = 1;
+const PHANDLE_SYSCON: u32 = 2;
+const PHANDLE_CPU_INTC: u32 = 3;
+
+/// What the tree describes that the bus does not know
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// Frequency of `mtime` in Hz
+    pub timebase_freq: u64,
+    /// Kernel command line, in /chosen
+    pub bootargs: Option<String>,
+    /// Where the initrd was loaded
+    pub initrd: Option<Range<u64>>,
+}
+
+/// Writes the structure and strings blocks of a tree
+struct Writer {
+    structs: Vec<u8>,
+    strings: Vec<u8>,
+    names: HashMap<&'static str, u32>,
+}
+
+impl Writer {
+    fn new() -> Self {
+        Self { structs: Vec::new(), strings: Vec::new(), names: HashMap::new() }
+    }
+
+    fn token(&mut self, t: u32) {
+        self.structs.extend_from_slice(&t.to_be_bytes());
+    }
+
+    fn pad(&mut self) {
+        while !self.structs.len().is_multiple_of(4) {
+            self.structs.push(0);
+        }
+    }
+
+    fn begin(&mut self, name: &str) {
+        self.token(FDT_BEGIN_NODE);
+        self.structs.extend_from_slice(name.as_bytes());
+        self.structs.push(0);
+        self.pad();
+    }
+
+    fn end(&mut self) {
+        self.token(FDT_END_NODE);
+    }
+
+    fn prop(&mut self, name: &'static str, value: &[u8]) {
+        let strings = &mut self.strings;
+        let off = *self.names.entry(name).or_insert_with(|| {
+            let off = strings.len() as u32;
+            strings.extend_from_slice(name.as_bytes());
+            strings.push(0);
+            off
+        });
+
+        self.token(FDT_PROP);
+        self.token(value.len() as u32);
+        self.token(off);
+        self.structs.extend_from_slice(value);
+        self.pad();
+    }
+
+    fn prop_empty(&mut self, name: &'static str) {
+        self.prop(name, &[]);
+    }
+
+    fn prop_cells(&mut self, name: &'static str, cells: &[u32]) {
+        self.prop(name, &cells.iter().flat_map(|c| c.to_be_bytes()).collect::<Vec<_>>());
+    }
+
+    fn prop_u32(&mut self, name: &'static str, v: u32) {
+        self.prop_cells(name, &[v]);
+    }
+
+    fn prop_u64(&mut self, name: &'static str, v: u64) {
+        self.prop_cells(name, &[(v >> 32) as u32, v as u32]);
+    }
+
+    /// A `reg` with 2 address and 2 size cells
+    fn prop_reg(&mut self, base: u64, size: u64) {
+        self.prop_cells("reg", &[(base >> 32) as u32, base as u32, (size >> 32) as u32, size as u32]);
+    }
+
+    fn prop_strs(&mut self, name: &'static str, strs: &[&str]) {
+        self.prop(name, &strs.iter().flat_map(|s| s.bytes().chain([0])).collect::<Vec<_>>());
+    }
+
+    fn prop_str(&mut self, name: &'static str, s: &str) {
+        self.prop_strs(name, &[s]);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.token(FDT_END);
+
+        let rsvmap = HEADER_SIZE;
+        let structs = rsvmap + 16;
+        let strings = structs + self.structs.len();
+        let total = strings + self.strings.len();
+
+        let header = [
+            FDT_MAGIC,
+            total as u32,
+            structs as u32,
+            strings as u32,
+            rsvmap as u32,
+            17, // version
+            16, // last compatible version
+            0,  // boot cpu
+            self.strings.len() as u32,
+            self.structs.len() as u32,
+        ];
+
+        let mut out = header.iter().flat_map(|v| v.to_be_bytes()).collect::<Vec<_>>();
+        // an empty memory reservation block
+        out.extend_from_slice(&[0; 16]);
+        out.extend_from_slice(&self.structs);
+        out.extend_from_slice(&self.strings);
+        out
+    }
+}
+
+/// The base ISA and the extensions in `riscv,isa-extensions` order, from `misa` and the Z and S
+/// extensions that are always there
+fn isa_extensions() -> Vec<String> {
+    let letters = "imafdqlcbjtpvh".chars().filter(|c| crate::cpu::MISA & 1 << (*c as u8 - b'a') != 0);
+    letters.map(String::from).chain(["zicsr", "zifencei", "sstc"].map(String::from)).collect()
+}
+
+/// Builds the tree of the machine on `bus`
+pub fn generate(bus: &Bus, config: &Config) -> Vec<u8> {
+    let mut w = Writer::new();
+    let harts = bus.harts();
+    let intc = |h: usize| PHANDLE_CPU_INTC + h as u32;
+
+    w.begin("");
+    w.prop_u32("#address-cells", 2);
+    w.prop_u32("#size-cells", 2);
+    w.prop_str("compatible", "riscv-virtio");
+    w.prop_str("model", "rv64,virt");
+
+    w.begin("chosen");
+    if let Some(args) = &config.bootargs {
+        w.prop_str("bootargs", args);
+    }
+    if bus.uarts() != 0 {
+        w.prop_str("stdout-path", &format!("/soc/serial@{UART_BASE:x}"));
+    }
+    if let Some(initrd) = &config.initrd {
+        w.prop_u64("linux,initrd-start", initrd.start);
+        w.prop_u64("linux,initrd-end", initrd.end);
+    }
+    w.end();
+
+    w.begin(&format!("memory@{RAM_BASE:x}"));
+    w.prop_str("device_type", "memory");
+    w.prop_reg(RAM_BASE, RAM_SIZE);
+    w.end();
+
+    let ext = isa_extensions();
+    let isa = format!("rv64{}", ext.iter().map(|e| if e.len() == 1 { e.clone() } else { format!("_{e}") }).collect::<String>());
+    let ext = ext.iter().map(String::as_str).collect::<Vec<_>>();
+
+    w.begin("cpus");
+    w.prop_u32("#address-cells", 1);
+    w.prop_u32("#size-cells", 0);
+    w.prop_u32("timebase-frequency", config.timebase_freq as u32);
+
+    for h in 0..harts {
+        w.begin(&format!("cpu@{h}"));
+        w.prop_str("device_type", "cpu");
+        w.prop_u32("reg", h as u32);
+        w.prop_str("status", "okay");
+        w.prop_str("compatible", "riscv");
+        w.prop_str("riscv,isa", &isa);
+        w.prop_str("riscv,isa-base", "rv64i");
+        w.prop_strs("riscv,isa-extensions", &ext);
+        w.prop_str("mmu-type", "riscv,sv48");
+
+        w.begin("interrupt-controller");
+        w.prop_u32("#interrupt-cells", 1);
+        w.prop_empty("interrupt-controller");
+        w.prop_str("compatible", "riscv,cpu-intc");
+        w.prop_u32("phandle", intc(h));
+        w.end();
+
+        w.end();
+    }
+    w.end();
+
+    w.begin("soc");
+    w.prop_u32("#address-cells", 2);
+    w.prop_u32("#size-cells", 2);
+    w.prop_str("compatible", "simple-bus");
+    w.prop_empty("ranges");
+
+    w.begin(&format!("clint@{CLINT_BASE:x}"));
+    w.prop_strs("compatible", &["sifive,clint0", "riscv,clint0"]);
+    w.prop_reg(CLINT_BASE, CLINT_SIZE);
+    w.prop_cells("interrupts-extended", &(0..harts).flat_map(|h| [intc(h), 3, intc(h), 7]).collect::<Vec<_>>());
+    w.end();
+
+    w.begin(&format!("interrupt-controller@{SSWI_BASE:x}"));
+    w.prop_str("compatible", "riscv,aclint-sswi");
+    w.prop_reg(SSWI_BASE, SSWI_SIZE);
+    w.prop_cells("interrupts-extended", &(0..harts).flat_map(|h| [intc(h), 1]).collect::<Vec<_>>());
+    w.prop_u32("#interrupt-cells", 0);
+    w.prop_empty("interrupt-controller");
+    w.end();
+
+    // contexts are M then S mode of each hart
+    w.begin(&format!("plic@{PLIC_BASE:x}"));
+    w.prop_strs("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+    w.prop_reg(PLIC_BASE, PLIC_SIZE);
+    w.prop_cells("interrupts-extended", &(0..harts).flat_map(|h| [intc(h), 11, intc(h), 9]).collect::<Vec<_>>());
+    w.prop_u32("#address-cells", 0);
+    w.prop_u32("#interrupt-cells", 1);
+    w.prop_empty("interrupt-controller");
+    w.prop_u32("riscv,ndev", crate::plic::SOURCES as u32 - 1);
+    w.prop_u32("phandle", PHANDLE_PLIC);
+    w.end();
+
+    w.begin(&format!("test@{SYSCON_BASE:x}"));
+    w.prop_strs("compatible", &["sifive,test1", "sifive,test0", "syscon"]);
+    w.prop_reg(SYSCON_BASE, SYSCON_SIZE);
+    w.prop_u32("phandle", PHANDLE_SYSCON);
+    w.end();
+
+    for (name, compatible, value) in [("poweroff", "syscon-poweroff", 0x5555), ("reboot", "syscon-reboot", 0x7777)] {
+        w.begin(name);
+        w.prop_str("compatible", compatible);
+        w.prop_u32("regmap", PHANDLE_SYSCON);
+        w.prop_u32("offset", 0);
+        w.prop_u32("value", value);
+        w.end();
+    }
+
+    if bus.has_rtc() {
+        w.begin(&format!("rtc@{RTC_BASE:x}"));
+        w.prop_str("compatible", "google,goldfish-rtc");
+        w.prop_reg(RTC_BASE, RTC_SIZE);
+        w.prop_u32("interrupt-parent", PHANDLE_PLIC);
+        w.prop_u32("interrupts", crate::rtc::RTC_IRQ as u32);
+        w.end();
+    }
+
+    for n in 0..bus.uarts() {
+        let base = UART_BASE + UART_SIZE * n as u64;
+        w.begin(&format!("serial@{base:x}"));
+        w.prop_str("compatible", "ns16550a");
+        w.prop_reg(base, UART_SIZE);
+        w.prop_u32("clock-frequency", UART_CLOCK);
+        w.prop_u32("interrupt-parent", PHANDLE_PLIC);
+        w.prop_u32("interrupts", crate::uart::UART_IRQS[n] as u32);
+        w.end();
+    }
+
+    for n in 0..bus.virtio_slots() {
+        let base = VIRTIO_BASE + VIRTIO_SIZE * n as u64;
+        w.begin(&format!("virtio_mmio@{base:x}"));
+        w.prop_str("compatible", "virtio,mmio");
+        w.prop_reg(base, VIRTIO_SIZE);
+        w.prop_u32("interrupt-parent", PHANDLE_PLIC);
+        w.prop_u32("interrupts", (VIRTIO_IRQ + n) as u32);
+        w.end();
+    }
+
+    if let Some(screen) = bus.screen() {
+        let mode = screen.mode();
+        w.begin(&format!("framebuffer@{FB_BASE:x}"));
+        w.prop_str("compatible", "simple-framebuffer");
+        w.prop_reg(FB_BASE, mode.size() as u64);
+        w.prop_u32("width", mode.width as u32);
+        w.prop_u32("height", mode.height as u32);
+        w.prop_u32("stride", mode.stride() as u32);
+        w.prop_str("format", mode.format.name());
+        w.end();
+    }
+
+    w.end();
+    w.end();
+    w.finish()
+}
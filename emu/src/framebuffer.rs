@@ -0,0 +1,294 @@
+//! Linear framebuffer in the style of Linux's simple-framebuffer: the guest draws straight into
+//! the pixel memory, and the host can save what is on the screen as a PPM or PNG
+
+use crate::bus::*;
+use crate::cpu::Exception;
+use std::io::{ErrorKind, Write};
+use std::sync::{Arc, Mutex};
+
+/// Frames per second that `dump_every` counts in
+pub const FRAME_RATE: u64 = 60;
+
+/// Pixel formats, named the way the simple-framebuffer binding names them
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Format {
+    R5G6B5,
+    R8G8B8,
+    X8R8G8B8,
+    A8R8G8B8,
+    X8B8G8R8,
+    A8B8G8R8,
+}
+
+const FORMATS: [(Format, &str); 6] = [
+    (Format::R5G6B5, "r5g6b5"),
+    (Format::R8G8B8, "r8g8b8"),
+    (Format::X8R8G8B8, "x8r8g8b8"),
+    (Format::A8R8G8B8, "a8r8g8b8"),
+    (Format::X8B8G8R8, "x8b8g8r8"),
+    (Format::A8B8G8R8, "a8b8g8r8"),
+];
+
+impl Format {
+    pub fn name(self) -> &'static str {
+        FORMATS.iter().find(|f| f.0 == self).unwrap().1
+    }
+
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            Format::R5G6B5 => 2,
+            Format::R8G8B8 => 3,
+            _ => 4,
+        }
+    }
+
+    /// The 8 bit red, green and blue of a pixel, which is stored little endian
+    fn rgb(self, px: &[u8]) -> [u8; 3] {
+        match self {
+            Format::R5G6B5 => {
+                let v = u16::from_le_bytes([px[0], px[1]]);
+                let (r, g, b) = ((v >> 11) as u8, (v >> 5) as u8 & 0x3f, v as u8 & 0x1f);
+                [r << 3 | r >> 2, g << 2 | g >> 4, b << 3 | b >> 2]
+            },
+            Format::R8G8B8 | Format::X8R8G8B8 | Format::A8R8G8B8 => [px[2], px[1], px[0]],
+            Format::X8B8G8R8 | Format::A8B8G8R8 => [px[0], px[1], px[2]],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Mode {
+    pub width: usize,
+    pub height: usize,
+    pub format: Format,
+}
+
+impl Mode {
+    /// Bytes from one line to the next
+    pub fn stride(&self) -> usize {
+        self.width * self.format.bytes_per_pixel()
+    }
+
+    pub fn size(&self) -> usize {
+        self.stride() * self.height
+    }
+}
+
+/// The host's view of a framebuffer, which stays valid while the machine runs
+#[derive(Clone)]
+pub struct Screen {
+    mode: Mode,
+    pixels: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Screen {
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// The current frame as rows of 8 bit RGB
+    pub fn rgb(&self) -> Vec<u8> {
+        let bpp = self.mode.format.bytes_per_pixel();
+        let pixels = self.pixels.lock().unwrap();
+        pixels.chunks(bpp).flat_map(|px| self.mode.format.rgb(px)).collect()
+    }
+
+    /// Saves the current frame, as a PNG if `path` ends in `.png` and as a PPM otherwise
+    pub fn dump(&self, path: &str) -> std::io::Result<()> {
+        let (w, h) = (self.mode.width, self.mode.height);
+        let data = if path.ends_with(".png") { png(w, h, &self.rgb()) } else { ppm(w, h, &self.rgb()) };
+        std::fs::File::create(path)?.write_all(&data)
+    }
+}
+
+fn ppm(width: usize, height: usize, rgb: &[u8]) -> Vec<u8> {
+    let mut out = format!("P6\n{width} {height}\n255\n").into_bytes();
+    out.extend_from_slice(rgb);
+    out
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &b| {
+        (0..8).fold(crc ^ b as u32, |c, _| if c & 1 != 0 { c >> 1 ^ 0xedb8_8320 } else { c >> 1 })
+    })
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (a, b) = data.iter().fold((1u32, 0u32), |(a, b), &d| {
+        let a = (a + d as u32) % 65521;
+        (a, (b + a) % 65521)
+    });
+    b << 16 | a
+}
+
+fn png_chunk(out: &mut Vec<u8>, ty: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(ty);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// An RGB PNG whose image data is stored uncompressed, screenshots are for comparing rather than
+/// for keeping
+fn png(width: usize, height: usize, rgb: &[u8]) -> Vec<u8> {
+    let mut raw = Vec::with_capacity((width * 3 + 1) * height);
+    for row in rgb.chunks(width * 3) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+
+    // zlib stream of stored deflate blocks
+    let mut z = vec![0x78, 0x01];
+    let blocks = raw.chunks(u16::MAX as usize);
+    let last = blocks.len() - 1;
+    for (i, block) in blocks.enumerate() {
+        z.push((i == last) as u8);
+        z.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        z.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        z.extend_from_slice(block);
+    }
+    z.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+    let mut ihdr = Vec::new();
+    ihdr.extend_from_slice(&(width as u32).to_be_bytes());
+    ihdr.extend_from_slice(&(height as u32).to_be_bytes());
+    ihdr.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    let mut out = b"\x89PNG\r\n\x1a\n".to_vec();
+    png_chunk(&mut out, b"IHDR", &ihdr);
+    png_chunk(&mut out, b"IDAT", &z);
+    png_chunk(&mut out, b"IEND", &[]);
+    out
+}
+
+pub struct Framebuffer {
+    screen: Screen,
+    /// mtime ticks per frame
+    frame_ticks: u64,
+    frame: u64,
+    next_frame: u64,
+    /// Path and period in frames of the periodic dumps
+    dump: Option<(String, u64)>,
+}
+
+impl Framebuffer {
+    /// A black screen, with frames counted off an mtime of `timebase_freq`
+    pub fn new(mode: Mode, timebase_freq: u64) -> Self {
+        let screen = Screen { mode, pixels: Arc::new(Mutex::new(vec![0; mode.size()])) };
+        let frame_ticks = (timebase_freq / FRAME_RATE).max(1);
+        Self { screen, frame_ticks, frame: 0, next_frame: frame_ticks, dump: None }
+    }
+
+    /// Dumps the screen every `frames` frames, to `path` with the frame number put before the
+    /// extension: `shot.png` gives `shot-000060.png`, `shot-000120.png` and so on
+    pub fn dump_every(&mut self, path: &str, frames: u64) {
+        self.dump = Some((path.to_string(), frames.max(1)));
+    }
+
+    /// Parses a framebuffer spec: `WIDTHxHEIGHT[,format=FORMAT][,dump=PATH,every=N]`, the format
+    /// defaults to `x8r8g8b8`
+    pub fn open(spec: &str, timebase_freq: u64) -> std::io::Result<Self> {
+        let invalid = |msg: String| std::io::Error::new(ErrorKind::InvalidInput, msg);
+        let mut opts = spec.split(',');
+
+        let res = opts.next().unwrap();
+        let (width, height) = res
+            .split_once('x')
+            .and_then(|(w, h)| Some((w.parse::<usize>().ok()?, h.parse::<usize>().ok()?)))
+            .filter(|&(w, h)| w > 0 && h > 0)
+            .ok_or_else(|| invalid(format!("bad resolution `{res}`")))?;
+
+        let mut format = Format::X8R8G8B8;
+        let mut dump = None;
+        let mut every = None;
+
+        for opt in opts {
+            match opt.split_once('=') {
+                Some(("format", f)) => {
+                    format = FORMATS.iter().find(|k| k.1 == f).ok_or_else(|| invalid(format!("unknown format `{f}`")))?.0;
+                },
+                Some(("dump", p)) => dump = Some(p),
+                Some(("every", n)) => every = Some(n.parse().map_err(|_| invalid(format!("bad frame count `{n}`")))?),
+                _ => return Err(invalid(format!("unknown framebuffer option `{opt}`"))),
+            }
+        }
+
+        let mode = Mode { width, height, format };
+        if mode.size() as u64 > FB_SIZE {
+            return Err(invalid(format!("`{res}` does not fit in {FB_SIZE} bytes")));
+        }
+
+        let mut fb = Self::new(mode, timebase_freq);
+        match (dump, every) {
+            (Some(path), Some(n)) => fb.dump_every(path, n),
+            (None, None) => {},
+            _ => return Err(invalid("dump= and every= go together".into())),
+        }
+
+        Ok(fb)
+    }
+
+    pub fn screen(&self) -> Screen {
+        self.screen.clone()
+    }
+
+    /// Moves on to the frame shown at `mtime`, dumping the screen if a dump fell due. A dump that
+    /// fell due more than once since the last call is only made once.
+    fn advance(&mut self, mtime: u64) {
+        if mtime < self.next_frame {
+            return;
+        }
+
+        let frames = (mtime - self.next_frame) / self.frame_ticks + 1;
+        let last = self.frame;
+        self.frame += frames;
+        self.next_frame += frames * self.frame_ticks;
+
+        let Some((path, every)) = &self.dump else { return };
+        if self.frame / every == last / every {
+            return;
+        }
+
+        let (stem, ext) = match path.rsplit_once('.') {
+            Some((stem, ext)) if !ext.contains('/') => (stem, format!(".{ext}")),
+            _ => (path.as_str(), String::new()),
+        };
+
+        let file = format!("{stem}-{:06}{ext}", self.frame / every * every);
+        if let Err(e) = self.screen.dump(&file) {
+            eprintln!("framebuffer: {file}: {e}");
+        }
+    }
+}
+
+macro_rules! gen {
+    ($l: tt $s: tt $t: tt $sz: tt) => {
+        fn $l(&mut self, addr: u64) -> Result<$t, Exception> {
+            let off = (addr - FB_BASE) as usize;
+            let pixels = self.screen.pixels.lock().unwrap();
+            let bytes = pixels.get(off..off + $sz).ok_or(Exception::LoadAccessFault)?;
+            Ok($t::from_le_bytes(bytes.try_into().unwrap()))
+        }
+
+        fn $s(&mut self, addr: u64, val: $t) -> Result<(), Exception> {
+            let off = (addr - FB_BASE) as usize;
+            let mut pixels = self.screen.pixels.lock().unwrap();
+            let bytes = pixels.get_mut(off..off + $sz).ok_or(Exception::StoreAccessFault)?;
+            bytes.copy_from_slice(&val.to_le_bytes());
+            Ok(())
+        }
+    };
+}
+
+impl Device for Framebuffer {
+    gen!(load_u8 store_u8 u8 1);
+    gen!(load_u16 store_u16 u16 2);
+    gen!(load_u32 store_u32 u32 4);
+    gen!(load_u64 store_u64 u64 8);
+
+    fn run(&mut self, bus: &Bus) {
+        self.advance(bus.mtime());
+    }
+}
@@ -0,0 +1,91 @@
+//! Loader of RISC-V Linux `Image` files, the flat kernel image with a 64 byte header in front that
+//! says where in RAM it goes and how much room it takes
+
+use crate::bus::*;
+use core::ops::Range;
+use std::io::{Error, ErrorKind, Result};
+
+const HEADER_SIZE: usize = 64;
+/// "RISCV\0\0\0", deprecated by `MAGIC2` but still written
+const MAGIC: u64 = 0x0000_0056_4353_4952;
+/// "RSC\x05"
+const MAGIC2: u32 = 0x0543_5352;
+/// Flags bit set for a big-endian kernel
+const FLAG_BE: u64 = 1;
+
+/// Room kept free at the top of RAM for the device tree, which is made after the initrd is placed
+/// since it says where the initrd is
+pub const DTB_RESERVE: u64 = 0x1_0000;
+
+const PAGE_SIZE: u64 = 0x1000;
+
+fn invalid(msg: impl Into<String>) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.into())
+}
+
+/// A kernel image
+pub struct Image<'a> {
+    data: &'a [u8],
+    /// Offset of the kernel from the start of RAM
+    pub text_offset: u64,
+    /// Room the kernel takes, with its bss
+    pub image_size: u64,
+    /// Version of the header, major in the upper half
+    pub version: u32,
+}
+
+impl<'a> Image<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self> {
+        let header = data.get(..HEADER_SIZE).ok_or_else(|| invalid("too short for an Image header"))?;
+        let u32_at = |off: usize| u32::from_le_bytes(header[off..off + 4].try_into().unwrap());
+        let u64_at = |off: usize| u64::from_le_bytes(header[off..off + 8].try_into().unwrap());
+
+        if u32_at(56) != MAGIC2 && u64_at(48) != MAGIC {
+            return Err(invalid("no RISC-V Image magic"));
+        }
+        if u64_at(24) & FLAG_BE != 0 {
+            return Err(invalid("big-endian kernel"));
+        }
+
+        Ok(Self {
+            data,
+            text_offset: u64_at(8),
+            // 0 in images older than the field
+            image_size: u64_at(16).max(data.len() as u64),
+            version: u32_at(32),
+        })
+    }
+
+    /// Where the kernel starts, and where it gets jumped to
+    pub fn entry(&self) -> u64 {
+        RAM_BASE + self.text_offset
+    }
+}
+
+/// What was loaded where
+#[derive(Debug, Clone)]
+pub struct Boot {
+    pub entry: u64,
+    pub initrd: Option<Range<u64>>,
+}
+
+/// Copies the kernel to its place in RAM and the initrd, if any, as high as it goes below
+/// `DTB_RESERVE`
+pub fn load(bus: &Bus, kernel: &Image, initrd: Option<&[u8]>) -> Result<Boot> {
+    let end = kernel.entry().checked_add(kernel.image_size).filter(|e| *e <= RAM_BASE + RAM_SIZE - DTB_RESERVE);
+    let end = end.ok_or_else(|| invalid(format!("kernel of {:#x} bytes does not fit in RAM", kernel.image_size)))?;
+    bus.load(kernel.entry(), kernel.data).map_err(|_| invalid("kernel does not fit in RAM"))?;
+
+    let initrd = match initrd {
+        Some(data) => {
+            let top = (RAM_BASE + RAM_SIZE - DTB_RESERVE) & !(PAGE_SIZE - 1);
+            let start = top.checked_sub(data.len() as u64).map(|s| s & !(PAGE_SIZE - 1)).filter(|s| *s >= end);
+            let start = start.ok_or_else(|| invalid(format!("initrd of {:#x} bytes does not fit in RAM", data.len())))?;
+            bus.load(start, data).map_err(|_| invalid("initrd does not fit in RAM"))?;
+            Some(start..start + data.len() as u64)
+        },
+        None => None,
+    };
+
+    Ok(Boot { entry: kernel.entry(), initrd })
+}
@@ -1,7 +1,21 @@
 pub mod bus;
+pub mod chardev;
 pub mod cpu;
+pub mod disk;
+pub mod elf;
+pub mod fdt;
+pub mod framebuffer;
+pub mod kernel;
+pub mod litmus;
+pub mod machine;
+pub mod monitor;
+pub mod net;
+pub mod p9;
 pub mod ram;
+pub mod rom;
+pub mod rtc;
 pub(crate) mod plic;
-pub(crate) mod clint;
-pub(crate) mod uart;
+pub mod syscon;
+pub mod clint;
+pub mod uart;
 pub mod virtio;
@@ -0,0 +1,58 @@
+//! Runs a litmus test many times with weak memory and counts the outcomes
+//!
+//! A litmus test is a bare program run by every hart, which branches on `mhartid`. A hart is done
+//! when it executes `wfi` with `mie` clear, the outcome of a run is the final value of the observed
+//! registers of every hart.
+
+use crate::bus::Bus;
+use crate::clint::Timebase;
+use crate::machine::Machine;
+use crate::ram::Ram;
+use std::collections::BTreeMap;
+
+/// Steps a run may take before it is counted as timed out
+const STEP_LIMIT: usize = 1_000_000;
+
+pub const REG_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2",
+    "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+    "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7",
+    "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+/// Parses an abi (`a0`) or numeric (`x10`) register name
+pub fn reg_index(name: &str) -> Option<usize> {
+    if name == "fp" {
+        return Some(8);
+    }
+
+    REG_NAMES.iter().position(|r| *r == name).or_else(|| {
+        name.strip_prefix('x')?.parse().ok().filter(|r| *r < 32)
+    })
+}
+
+/// Runs `image` `runs` times, each time with a new seed, and returns how often each outcome
+/// happened
+pub fn explore(image: &[u8], harts: usize, runs: usize, seed: u64, observe: &[usize]) -> BTreeMap<String, usize> {
+    let mut outcomes = BTreeMap::new();
+
+    for run in 0..runs {
+        let bus = Bus::new(Ram::new(image), harts, Timebase::Instructions(1));
+        let mut machine = Machine::new(&bus, harts, 1, false);
+        machine.weak_memory(seed.wrapping_add(run as u64));
+
+        let outcome = if machine.run_to_halt(false, STEP_LIMIT) {
+            (0..harts)
+                .flat_map(|h| observe.iter().map(move |r| (h, *r)))
+                .map(|(h, r)| format!("{h}:{}={}", REG_NAMES[r], machine.hart(h).reg(r) as i64))
+                .collect::<Vec<_>>()
+                .join(" ")
+        } else {
+            "timeout".to_string()
+        };
+
+        *outcomes.entry(outcome).or_insert(0) += 1;
+    }
+
+    outcomes
+}
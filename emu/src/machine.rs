@@ -0,0 +1,169 @@
+//! Multi-hart machine, either with a deterministic round-robin scheduler or with one host thread
+//! per hart
+
+use crate::bus::Bus;
+use crate::cpu::Cpu;
+use crate::syscon::Exit;
+
+pub struct Machine<'a> {
+    bus: &'a Bus,
+    harts: Vec<Cpu<'a>>,
+    /// Number of instructions a hart runs before the next one is scheduled
+    quantum: usize,
+    /// Keep running a hart past its quantum while it is inside a constrained lr/sc loop, so that
+    /// its sc is not failed by other harts forever
+    forward_progress: bool,
+    /// Random scheduling of the harts and of when their buffered stores become visible, for
+    /// exploring weak memory orderings
+    weak: Option<Rng>,
+}
+
+impl<'a> Machine<'a> {
+    pub fn new(bus: &'a Bus, harts: usize, quantum: usize, forward_progress: bool) -> Self {
+        Self {
+            bus,
+            harts: (0..harts).map(|h| Cpu::new(bus, h)).collect(),
+            quantum: quantum.max(1),
+            forward_progress,
+            weak: None,
+        }
+    }
+
+    /// A machine of one hart that runs the static Linux program `elf` in U-mode, see `Cpu::user`
+    pub fn user(bus: &'a Bus, elf: &crate::elf::Elf, argv: &[String], envp: &[String]) -> std::io::Result<Self> {
+        Ok(Self { bus, harts: vec![Cpu::user(bus, elf, argv, envp)?], quantum: 1000, forward_progress: false, weak: None })
+    }
+
+    /// Gives every hart a store buffer and schedules them randomly, with `seed` deciding the
+    /// interleaving
+    pub fn weak_memory(&mut self, seed: u64) {
+        for hart in self.harts.iter_mut() {
+            hart.enable_store_buffer();
+        }
+
+        self.weak = Some(Rng::new(seed));
+    }
+
+    /// Resets the machine, for when the guest asked for it: RAM and the devices go back to the
+    /// state they were in before the harts first ran, see `Bus::reset`, and so do the harts
+    pub fn reset(&mut self) {
+        self.bus.reset();
+
+        let weak = self.weak.is_some();
+        for (h, hart) in self.harts.iter_mut().enumerate() {
+            *hart = Cpu::new(self.bus, h);
+            if weak {
+                hart.enable_store_buffer();
+            }
+        }
+    }
+
+    pub fn hart(&self, hart: usize) -> &Cpu<'a> {
+        &self.harts[hart]
+    }
+
+    /// Either runs one instruction of a hart or makes one of its buffered stores visible. Returns
+    /// false once every hart is halted and every store is visible.
+    fn weak_step(&mut self, testing: bool) -> bool {
+        let Some(rng) = &mut self.weak else { return false };
+
+        let steps = self.harts.iter().enumerate().filter(|(_, h)| !h.halted()).map(|(i, _)| (i, false));
+        let drains = self.harts.iter().enumerate().filter(|(_, h)| h.store_buffer_len() != 0).map(|(i, _)| (i, true));
+        let choices = steps.chain(drains).collect::<Vec<_>>();
+
+        if choices.is_empty() {
+            return false;
+        }
+
+        let (hart, drain) = choices[rng.next() as usize % choices.len()];
+        if drain {
+            let pick = rng.next() as usize;
+            self.harts[hart].drain_store(pick);
+        } else {
+            self.harts[hart].step(testing);
+        }
+
+        true
+    }
+
+    /// Runs the weak memory scheduler until every hart is halted (`wfi` with `mie` clear) and
+    /// every store is visible, or for at most `limit` steps. Returns whether the harts halted.
+    pub fn run_to_halt(&mut self, testing: bool, limit: usize) -> bool {
+        (0..limit).any(|_| !self.weak_step(testing))
+    }
+
+    /// Runs every hart for one quantum, in order of their hart ID. Stops early when the guest
+    /// powers off or resets the machine.
+    pub fn step(&mut self, testing: bool) {
+        if self.weak.is_some() {
+            for _ in 0..self.quantum * self.harts.len() {
+                if self.bus.exit().is_some() {
+                    return;
+                }
+
+                self.weak_step(testing);
+            }
+
+            return;
+        }
+
+        for hart in self.harts.iter_mut() {
+            for _ in 0..self.quantum {
+                if self.bus.exit().is_some() {
+                    return;
+                }
+
+                hart.step(testing);
+            }
+
+            while self.forward_progress && hart.in_lrsc_loop() && self.bus.exit().is_none() {
+                hart.step(testing);
+            }
+        }
+    }
+
+    /// Runs until the guest powers off or resets the machine through the syscon
+    pub fn run(&mut self, testing: bool) -> Exit {
+        loop {
+            self.step(testing);
+
+            if let Some(exit) = self.bus.take_exit() {
+                return exit;
+            }
+        }
+    }
+
+    /// Runs each hart on its own host thread. Harts only ever communicate through the bus: RAM and
+    /// the CLINT are host atomics, other devices are behind locks.
+    pub fn run_parallel(&mut self, testing: bool) -> Exit {
+        let bus = self.bus;
+
+        std::thread::scope(|s| {
+            for hart in self.harts.iter_mut() {
+                s.spawn(move || {
+                    while bus.exit().is_none() {
+                        hart.step(testing);
+                    }
+                });
+            }
+        });
+
+        bus.take_exit().unwrap()
+    }
+}
+
+/// xorshift64*, good enough for picking interleavings and for deterministic entropy
+pub(crate) struct Rng(u64);
+
+impl Rng {
+    pub(crate) fn new(seed: u64) -> Self {
+        Self(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    pub(crate) fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+}
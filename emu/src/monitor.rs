@@ -0,0 +1,77 @@
+//! Line based monitor on a `chardev::Chardev`, for controlling the machine from the host while it
+//! runs
+
+use crate::bus::Bus;
+use crate::chardev::Chardev;
+use crate::syscon::Exit;
+
+const PROMPT: &str = "(rv64) ";
+/// Longest command line, the rest of a longer one is dropped
+const MAX_LINE: usize = 1024;
+
+const HELP: &str = "\
+help               show this
+screendump FILE    save the framebuffer, as a PNG if FILE ends in .png and a PPM otherwise
+quit               exit the emulator
+";
+
+pub struct Monitor {
+    backend: Box<dyn Chardev>,
+    line: Vec<u8>,
+}
+
+impl Monitor {
+    pub fn new(backend: Box<dyn Chardev>) -> Self {
+        let mut m = Self { backend, line: Vec::new() };
+        m.print(PROMPT);
+        m
+    }
+
+    fn print(&mut self, s: &str) {
+        for b in s.bytes() {
+            if b == b'\n' {
+                self.backend.write(b'\r');
+            }
+            self.backend.write(b);
+        }
+    }
+
+    /// Takes in host input, running each command as its line completes
+    pub(crate) fn poll(&mut self, bus: &Bus) {
+        while let Some(b) = self.backend.read() {
+            match b {
+                b'\r' | b'\n' => {
+                    let line = String::from_utf8_lossy(&std::mem::take(&mut self.line)).into_owned();
+                    let reply = self.command(line.trim(), bus);
+                    self.print(&reply);
+                    self.print(PROMPT);
+                },
+                _ if self.line.len() < MAX_LINE => self.line.push(b),
+                _ => {},
+            }
+        }
+    }
+
+    fn command(&mut self, line: &str, bus: &Bus) -> String {
+        let (cmd, arg) = line.split_once(' ').map(|(c, a)| (c, a.trim())).unwrap_or((line, ""));
+
+        match cmd {
+            "" => String::new(),
+            "help" => HELP.to_string(),
+            "screendump" if arg.is_empty() => "usage: screendump FILE\n".to_string(),
+            "screendump" => match bus.screen() {
+                Some(screen) => match screen.dump(arg) {
+                    Ok(()) => String::new(),
+                    Err(e) => format!("{arg}: {e}\n"),
+                },
+                None => "no framebuffer\n".to_string(),
+            },
+            // stops the run like a guest power off, so the caller still gets to clean up
+            "quit" => {
+                bus.request_exit(Exit::Pass);
+                String::new()
+            },
+            _ => format!("unknown command `{cmd}`, try help\n"),
+        }
+    }
+}
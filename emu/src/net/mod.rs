@@ -0,0 +1,152 @@
+//! Host side of network devices: where the Ethernet frames of the guest go
+
+mod user;
+
+pub use user::User;
+
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Write};
+use std::os::unix::net::UnixDatagram;
+
+/// Largest frame, without the FCS
+pub const MAX_FRAME: usize = 1514;
+
+pub trait NetBackend: Send {
+    fn send(&mut self, frame: &[u8]);
+    /// Returns the next frame for the guest, without blocking
+    fn recv(&mut self) -> Option<Vec<u8>>;
+}
+
+/// A backend with the options of its card, as given by `--netdev`
+pub struct Netdev {
+    pub backend: Box<dyn NetBackend>,
+    pub mac: Option<[u8; 6]>,
+}
+
+/// Parses a netdev spec: `user`, `unix:LOCAL:PEER`, `pcap:PATH` or `null`, optionally followed by
+/// `,mac=52:54:00:12:34:56` and by `,pcap=PATH` to also capture the traffic of the backend
+pub fn open(spec: &str) -> std::io::Result<Netdev> {
+    let invalid = |msg: String| std::io::Error::new(ErrorKind::InvalidInput, msg);
+    let mut opts = spec.split(',');
+    let first = opts.next().unwrap_or("");
+    let (kind, arg) = first.split_once(':').unwrap_or((first, ""));
+
+    let mut backend: Box<dyn NetBackend> = match kind {
+        "user" => Box::new(User::new()),
+        "unix" => {
+            let (local, peer) = arg.split_once(':').ok_or_else(|| invalid("expected unix:LOCAL:PEER".into()))?;
+            Box::new(Datagram::bind(local, peer)?)
+        },
+        "pcap" => Box::new(Pcap::create(arg, Box::new(Null))?),
+        "null" => Box::new(Null),
+        _ => return Err(invalid(format!("unknown netdev `{kind}`"))),
+    };
+    let mut mac = None;
+
+    for opt in opts {
+        match opt.split_once('=') {
+            Some(("pcap", path)) => backend = Box::new(Pcap::create(path, backend)?),
+            Some(("mac", m)) => {
+                let bytes = m.split(':').map(|b| u8::from_str_radix(b, 16)).collect::<Result<Vec<_>, _>>();
+                mac = Some(bytes.ok().and_then(|b| b.try_into().ok()).ok_or_else(|| invalid(format!("bad mac `{m}`")))?);
+            },
+            _ => return Err(invalid(format!("unknown netdev option `{opt}`"))),
+        }
+    }
+
+    Ok(Netdev { backend, mac })
+}
+
+/// Drops every frame
+pub struct Null;
+
+impl NetBackend for Null {
+    fn send(&mut self, _frame: &[u8]) {}
+    fn recv(&mut self) -> Option<Vec<u8>> { None }
+}
+
+/// One end of a pair of Unix datagram sockets, one frame per datagram. Two emulators pointed at
+/// each other's socket are on the same link.
+pub struct Datagram {
+    socket: UnixDatagram,
+    peer: String,
+}
+
+impl Datagram {
+    pub fn bind(local: &str, peer: &str) -> std::io::Result<Self> {
+        crate::chardev::remove_socket(local)?;
+        let socket = UnixDatagram::bind(local)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self { socket, peer: peer.to_string() })
+    }
+}
+
+impl NetBackend for Datagram {
+    fn send(&mut self, frame: &[u8]) {
+        // the peer might not be up yet, the frame is lost like on a real link
+        _ = self.socket.send_to(frame, &self.peer);
+    }
+
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        let mut buf = vec![0; MAX_FRAME];
+        let n = self.socket.recv(&mut buf).ok()?;
+        buf.truncate(n);
+        Some(buf)
+    }
+}
+
+/// Writes every frame going through another backend to a pcap file
+pub struct Pcap {
+    file: std::io::BufWriter<std::fs::File>,
+    inner: Box<dyn NetBackend>,
+}
+
+impl Pcap {
+    pub fn create(path: &str, inner: Box<dyn NetBackend>) -> std::io::Result<Self> {
+        let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+
+        // version 2.4, ethernet, no snapshot length limit worth speaking of
+        file.write_all(&0xa1b2_c3d4u32.to_le_bytes())?;
+        file.write_all(&2u16.to_le_bytes())?;
+        file.write_all(&4u16.to_le_bytes())?;
+        file.write_all(&[0; 8])?;
+        file.write_all(&65535u32.to_le_bytes())?;
+        file.write_all(&1u32.to_le_bytes())?;
+        file.flush()?;
+
+        Ok(Self { file, inner })
+    }
+
+    fn record(&mut self, frame: &[u8]) {
+        let t = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+        let mut header = [0; 16];
+        header[0..4].copy_from_slice(&(t.as_secs() as u32).to_le_bytes());
+        header[4..8].copy_from_slice(&t.subsec_micros().to_le_bytes());
+        header[8..12].copy_from_slice(&(frame.len() as u32).to_le_bytes());
+        header[12..16].copy_from_slice(&(frame.len() as u32).to_le_bytes());
+
+        _ = self.file.write_all(&header);
+        _ = self.file.write_all(frame);
+        _ = self.file.flush();
+    }
+}
+
+impl NetBackend for Pcap {
+    fn send(&mut self, frame: &[u8]) {
+        self.record(frame);
+        self.inner.send(frame);
+    }
+
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        let frame = self.inner.recv()?;
+        self.record(&frame);
+        Some(frame)
+    }
+}
+
+/// Frames waiting for the guest, dropped once there are too many like on a congested link
+pub(crate) fn enqueue(queue: &mut VecDeque<Vec<u8>>, frame: Vec<u8>) {
+    if queue.len() < 256 {
+        queue.push_back(frame);
+    }
+}
@@ -0,0 +1,221 @@
+//! A tiny network on the host side of the link, answering the guest without real network access:
+//! ARP for the gateway, DHCP handing out the guest address, and pings of the gateway. Everything
+//! else is dropped.
+//!
+//! The addresses are the same as QEMU's user networking: the network is 10.0.2.0/24, the gateway
+//! 10.0.2.2 and the guest gets 10.0.2.15.
+
+use super::*;
+
+const GATEWAY_MAC: [u8; 6] = [0x52, 0x55, 0x0a, 0x00, 0x02, 0x02];
+const GATEWAY_IP: [u8; 4] = [10, 0, 2, 2];
+const GUEST_IP: [u8; 4] = [10, 0, 2, 15];
+const NETMASK: [u8; 4] = [255, 255, 255, 0];
+const BROADCAST_MAC: [u8; 6] = [0xff; 6];
+const LEASE_SECS: u32 = 86400;
+
+const ETH_HEADER: usize = 14;
+const IP_HEADER: usize = 20;
+const UDP_HEADER: usize = 8;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+const IPPROTO_ICMP: u8 = 1;
+const IPPROTO_UDP: u8 = 17;
+
+const ARP_REQUEST: u16 = 1;
+const ARP_REPLY: u16 = 2;
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+
+const DHCP_SERVER_PORT: u16 = 67;
+const DHCP_CLIENT_PORT: u16 = 68;
+const DHCP_MAGIC: [u8; 4] = [0x63, 0x82, 0x53, 0x63];
+const DHCP_DISCOVER: u8 = 1;
+const DHCP_OFFER: u8 = 2;
+const DHCP_REQUEST: u8 = 3;
+const DHCP_ACK: u8 = 5;
+/// Offset of the options in a DHCP message, after the fixed fields and the magic cookie
+const DHCP_OPTIONS: usize = 240;
+
+#[derive(Default)]
+pub struct User {
+    replies: VecDeque<Vec<u8>>,
+}
+
+fn be16(b: &[u8]) -> u16 {
+    u16::from_be_bytes([b[0], b[1]])
+}
+
+/// The internet checksum, over `data` as 16 bit big endian words
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum = data.chunks(2).map(|c| (c[0] as u32) << 8 | *c.get(1).unwrap_or(&0) as u32).sum::<u32>();
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    !sum as u16
+}
+
+fn ethernet(dst: &[u8], ethertype: u16, payload: &[u8]) -> Vec<u8> {
+    let mut f = Vec::with_capacity(ETH_HEADER + payload.len());
+    f.extend_from_slice(dst);
+    f.extend_from_slice(&GATEWAY_MAC);
+    f.extend_from_slice(&ethertype.to_be_bytes());
+    f.extend_from_slice(payload);
+    f
+}
+
+fn ipv4(src: [u8; 4], dst: [u8; 4], proto: u8, payload: &[u8]) -> Vec<u8> {
+    let mut p = vec![0; IP_HEADER];
+    p[0] = 0x45;
+    p[2..4].copy_from_slice(&((IP_HEADER + payload.len()) as u16).to_be_bytes());
+    p[8] = 64;
+    p[9] = proto;
+    p[12..16].copy_from_slice(&src);
+    p[16..20].copy_from_slice(&dst);
+    let sum = checksum(&p);
+    p[10..12].copy_from_slice(&sum.to_be_bytes());
+    p.extend_from_slice(payload);
+    p
+}
+
+impl User {
+    pub fn new() -> Self {
+        Self { replies: VecDeque::new() }
+    }
+
+    fn arp(&mut self, frame: &[u8]) {
+        let arp = &frame[ETH_HEADER..];
+        if arp.len() < 28 || be16(&arp[6..]) != ARP_REQUEST || arp[24..28] != GATEWAY_IP {
+            return;
+        }
+
+        let mut reply = vec![0; 28];
+        reply[0..6].copy_from_slice(&arp[0..6]);
+        reply[6..8].copy_from_slice(&ARP_REPLY.to_be_bytes());
+        reply[8..14].copy_from_slice(&GATEWAY_MAC);
+        reply[14..18].copy_from_slice(&GATEWAY_IP);
+        reply[18..28].copy_from_slice(&arp[8..18]);
+
+        enqueue(&mut self.replies, ethernet(&arp[8..14], ETHERTYPE_ARP, &reply));
+    }
+
+    fn ipv4(&mut self, frame: &[u8]) {
+        let ip = &frame[ETH_HEADER..];
+        if ip.len() < IP_HEADER || ip[0] >> 4 != 4 {
+            return;
+        }
+
+        let ihl = (ip[0] & 0xf) as usize * 4;
+        let len = (be16(&ip[2..]) as usize).min(ip.len());
+        if ihl < IP_HEADER || len < ihl {
+            return;
+        }
+
+        let src: [u8; 4] = ip[12..16].try_into().unwrap();
+        let dst: [u8; 4] = ip[16..20].try_into().unwrap();
+        let payload = &ip[ihl..len];
+
+        match ip[9] {
+            IPPROTO_ICMP if dst == GATEWAY_IP => self.icmp(&frame[6..12], src, payload),
+            IPPROTO_UDP if payload.len() >= UDP_HEADER && be16(&payload[2..]) == DHCP_SERVER_PORT => {
+                self.dhcp(&frame[6..12], &payload[UDP_HEADER..]);
+            },
+            _ => {},
+        }
+    }
+
+    fn icmp(&mut self, mac: &[u8], src: [u8; 4], icmp: &[u8]) {
+        if icmp.len() < 8 || icmp[0] != ICMP_ECHO_REQUEST {
+            return;
+        }
+
+        let mut reply = icmp.to_vec();
+        reply[0] = ICMP_ECHO_REPLY;
+        reply[2..4].fill(0);
+        let sum = checksum(&reply);
+        reply[2..4].copy_from_slice(&sum.to_be_bytes());
+
+        enqueue(&mut self.replies, ethernet(mac, ETHERTYPE_IPV4, &ipv4(GATEWAY_IP, src, IPPROTO_ICMP, &reply)));
+    }
+
+    fn dhcp(&mut self, mac: &[u8], msg: &[u8]) {
+        if msg.len() < DHCP_OPTIONS || msg[0] != 1 || msg[236..240] != DHCP_MAGIC {
+            return;
+        }
+
+        // find the message type among the options
+        let mut ty = None;
+        let mut i = DHCP_OPTIONS;
+        while i + 1 < msg.len() && msg[i] != 255 {
+            if msg[i] == 0 {
+                i += 1;
+                continue;
+            }
+
+            let len = msg[i + 1] as usize;
+            if msg[i] == 53 && len >= 1 && i + 2 < msg.len() {
+                ty = Some(msg[i + 2]);
+            }
+            i += 2 + len;
+        }
+
+        let reply_ty = match ty {
+            Some(DHCP_DISCOVER) => DHCP_OFFER,
+            Some(DHCP_REQUEST) => DHCP_ACK,
+            _ => return,
+        };
+
+        let mut reply = vec![0; DHCP_OPTIONS];
+        reply[0] = 2;
+        reply[1] = 1;
+        reply[2] = 6;
+        // xid, and the flags so the broadcast bit is kept
+        reply[4..8].copy_from_slice(&msg[4..8]);
+        reply[10..12].copy_from_slice(&msg[10..12]);
+        reply[16..20].copy_from_slice(&GUEST_IP);
+        reply[20..24].copy_from_slice(&GATEWAY_IP);
+        reply[28..44].copy_from_slice(&msg[28..44]);
+        reply[236..240].copy_from_slice(&DHCP_MAGIC);
+
+        reply.extend_from_slice(&[53, 1, reply_ty]);
+        reply.extend_from_slice(&[54, 4]);
+        reply.extend_from_slice(&GATEWAY_IP);
+        reply.extend_from_slice(&[1, 4]);
+        reply.extend_from_slice(&NETMASK);
+        reply.extend_from_slice(&[3, 4]);
+        reply.extend_from_slice(&GATEWAY_IP);
+        reply.extend_from_slice(&[51, 4]);
+        reply.extend_from_slice(&LEASE_SECS.to_be_bytes());
+        reply.push(255);
+
+        let mut udp = vec![0; UDP_HEADER];
+        udp[0..2].copy_from_slice(&DHCP_SERVER_PORT.to_be_bytes());
+        udp[2..4].copy_from_slice(&DHCP_CLIENT_PORT.to_be_bytes());
+        udp[4..6].copy_from_slice(&((UDP_HEADER + reply.len()) as u16).to_be_bytes());
+        udp.extend_from_slice(&reply);
+
+        let ip = ipv4(GATEWAY_IP, [255; 4], IPPROTO_UDP, &udp);
+        let dst = if msg[10] & 0x80 != 0 { &BROADCAST_MAC[..] } else { mac };
+        enqueue(&mut self.replies, ethernet(dst, ETHERTYPE_IPV4, &ip));
+    }
+}
+
+impl NetBackend for User {
+    fn send(&mut self, frame: &[u8]) {
+        if frame.len() < ETH_HEADER {
+            return;
+        }
+
+        match be16(&frame[12..]) {
+            ETHERTYPE_ARP => self.arp(frame),
+            ETHERTYPE_IPV4 => self.ipv4(frame),
+            _ => {},
+        }
+    }
+
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        self.replies.pop_front()
+    }
+}
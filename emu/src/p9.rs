@@ -0,0 +1,687 @@
+//! A 9P2000.L server exporting a host directory, the host side of virtio-9p
+//!
+//! Names are resolved one component at a time, `..` stops at the root of the export and symlinks
+//! are never followed on the host: the client resolves them itself with Treadlink, and following
+//! one here could lead out of the export. A path through a symlink fails with ELOOP, and files are
+//! opened with `O_NOFOLLOW`.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::ErrorKind;
+use std::os::unix::fs::{DirBuilderExt, FileExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+const RLERROR: u8 = 7;
+const TSTATFS: u8 = 8;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TSYMLINK: u8 = 16;
+const TMKNOD: u8 = 18;
+const TRENAME: u8 = 20;
+const TREADLINK: u8 = 22;
+const TGETATTR: u8 = 24;
+const TSETATTR: u8 = 26;
+const TXATTRWALK: u8 = 30;
+const TREADDIR: u8 = 40;
+const TFSYNC: u8 = 50;
+const TLOCK: u8 = 52;
+const TGETLOCK: u8 = 54;
+const TLINK: u8 = 70;
+const TMKDIR: u8 = 72;
+const TRENAMEAT: u8 = 74;
+const TUNLINKAT: u8 = 76;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TFLUSH: u8 = 108;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+const TREMOVE: u8 = 122;
+
+const QTDIR: u8 = 0x80;
+const QTSYMLINK: u8 = 0x02;
+
+const P9_GETATTR_BASIC: u64 = 0x7ff;
+
+const P9_SETATTR_MODE: u32 = 1 << 0;
+const P9_SETATTR_UID: u32 = 1 << 1;
+const P9_SETATTR_GID: u32 = 1 << 2;
+const P9_SETATTR_SIZE: u32 = 1 << 3;
+const P9_SETATTR_ATIME: u32 = 1 << 4;
+const P9_SETATTR_MTIME: u32 = 1 << 5;
+const P9_SETATTR_ATIME_SET: u32 = 1 << 7;
+const P9_SETATTR_MTIME_SET: u32 = 1 << 8;
+
+// open flags, as Linux has them
+const L_O_WRONLY: u32 = 1;
+const L_O_RDWR: u32 = 2;
+const L_O_ACCMODE: u32 = 3;
+const L_O_EXCL: u32 = 0o200;
+const L_O_TRUNC: u32 = 0o1000;
+const L_O_APPEND: u32 = 0o2000;
+
+const AT_REMOVEDIR: u32 = 0x200;
+const F_UNLCK: u8 = 2;
+
+/// Largest message, whatever the client asks for
+const MAX_MSIZE: u32 = 512 * 1024;
+/// Size of the header of Rread and Rreaddir: size, type, tag and count
+const IO_HEADER: u32 = 11;
+
+/// Errors are Linux errnos, which the host has too
+type Result<T> = std::result::Result<T, u32>;
+
+fn errno(e: std::io::Error) -> u32 {
+    e.raw_os_error().unwrap_or(libc::EIO) as u32
+}
+
+/// Reads the fields of a request
+struct Req<'a> {
+    b: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Req<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        let b = self.b.get(self.at..self.at + n).ok_or(libc::EPROTO as u32)?;
+        self.at += n;
+        Ok(b)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn str(&mut self) -> Result<&'a str> {
+        let n = self.u16()? as usize;
+        std::str::from_utf8(self.bytes(n)?).map_err(|_| libc::EINVAL as u32)
+    }
+}
+
+/// Builds the body of a reply
+#[derive(Default)]
+struct Reply(Vec<u8>);
+
+impl Reply {
+    fn u8(&mut self, v: u8) -> &mut Self {
+        self.0.push(v);
+        self
+    }
+
+    fn u16(&mut self, v: u16) -> &mut Self {
+        self.0.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    fn u32(&mut self, v: u32) -> &mut Self {
+        self.0.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    fn u64(&mut self, v: u64) -> &mut Self {
+        self.0.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    fn str(&mut self, s: &str) -> &mut Self {
+        self.u16(s.len() as u16);
+        self.0.extend_from_slice(s.as_bytes());
+        self
+    }
+
+    fn qid(&mut self, m: &std::fs::Metadata) -> &mut Self {
+        let ty = if m.is_dir() {
+            QTDIR
+        } else if m.file_type().is_symlink() {
+            QTSYMLINK
+        } else {
+            0
+        };
+
+        self.u8(ty).u32(m.mtime() as u32 ^ m.size() as u32).u64(m.ino())
+    }
+}
+
+struct Fid {
+    path: PathBuf,
+    file: Option<File>,
+    /// Entries of an opened directory, as `Treaddir` goes through them
+    dir: Option<Vec<(String, std::fs::Metadata)>>,
+}
+
+impl Fid {
+    fn new(path: PathBuf) -> Self {
+        Self { path, file: None, dir: None }
+    }
+}
+
+pub struct Server {
+    root: PathBuf,
+    read_only: bool,
+    msize: u32,
+    fids: HashMap<u32, Fid>,
+}
+
+impl Server {
+    pub fn new(root: impl AsRef<Path>, read_only: bool) -> std::io::Result<Self> {
+        let root = root.as_ref().canonicalize()?;
+        if !root.is_dir() {
+            return Err(std::io::Error::new(ErrorKind::InvalidInput, format!("`{}` is not a directory", root.display())));
+        }
+
+        Ok(Self { root, read_only, msize: MAX_MSIZE, fids: HashMap::new() })
+    }
+
+    /// Handles one message and returns the reply
+    pub fn handle(&mut self, msg: &[u8]) -> Vec<u8> {
+        let mut req = Req { b: msg, at: 0 };
+        let header = (|| Ok::<_, u32>((req.u32()?, req.u8()?, req.u16()?)))();
+        let Ok((_, ty, tag)) = header else { return Vec::new() };
+
+        let (ty, body) = match self.dispatch(ty, &mut req) {
+            Ok(body) => (ty + 1, body.0),
+            Err(e) => (RLERROR, e.to_le_bytes().to_vec()),
+        };
+
+        let mut reply = Vec::with_capacity(7 + body.len());
+        reply.extend_from_slice(&(7 + body.len() as u32).to_le_bytes());
+        reply.push(ty);
+        reply.extend_from_slice(&tag.to_le_bytes());
+        reply.extend_from_slice(&body);
+        reply
+    }
+
+    fn fid(&mut self, fid: u32) -> Result<&mut Fid> {
+        self.fids.get_mut(&fid).ok_or(libc::EBADF as u32)
+    }
+
+    fn path(&self, fid: u32) -> Result<PathBuf> {
+        let path = self.fids.get(&fid).map(|f| f.path.clone()).ok_or(libc::EBADF as u32)?;
+        self.beneath(&path)?;
+        Ok(path)
+    }
+
+    /// Fails unless every directory between the root and `path` is a real directory and not a
+    /// symlink, which the host would follow. `path` itself may be a symlink.
+    fn beneath(&self, path: &Path) -> Result<()> {
+        let rel = path.strip_prefix(&self.root).map_err(|_| libc::EACCES as u32)?;
+        let mut dir = self.root.clone();
+        let mut components = rel.components().peekable();
+
+        while let Some(c) = components.next() {
+            if components.peek().is_none() {
+                break;
+            }
+
+            dir.push(c);
+            if std::fs::symlink_metadata(&dir).is_ok_and(|m| m.file_type().is_symlink()) {
+                return Err(libc::ELOOP as u32);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn writable(&self) -> Result<()> {
+        if self.read_only { Err(libc::EROFS as u32) } else { Ok(()) }
+    }
+
+    /// The path of `name` in the directory `dir`, which never leaves the export
+    fn child(&self, dir: &Path, name: &str) -> Result<PathBuf> {
+        let path = match name {
+            "" => return Err(libc::EINVAL as u32),
+            _ if name.contains('/') => return Err(libc::EINVAL as u32),
+            "." => dir.to_path_buf(),
+            ".." if dir == self.root => dir.to_path_buf(),
+            ".." => dir.parent().unwrap_or(&self.root).to_path_buf(),
+            _ => dir.join(name),
+        };
+
+        self.beneath(&path)?;
+        Ok(path)
+    }
+
+    fn dispatch(&mut self, ty: u8, req: &mut Req) -> Result<Reply> {
+        let mut r = Reply::default();
+
+        match ty {
+            TVERSION => {
+                let msize = req.u32()?;
+                let version = req.str()?;
+                self.msize = msize.min(MAX_MSIZE);
+                self.fids.clear();
+                r.u32(self.msize).str(if version == "9P2000.L" { version } else { "unknown" });
+            },
+            TATTACH => {
+                let fid = req.u32()?;
+                let m = std::fs::metadata(&self.root).map_err(errno)?;
+                self.fids.insert(fid, Fid::new(self.root.clone()));
+                r.qid(&m);
+            },
+            TFLUSH => {},
+            TWALK => {
+                let fid = req.u32()?;
+                let newfid = req.u32()?;
+                let mut path = self.path(fid)?;
+                let n = req.u16()?;
+
+                r.u16(0);
+                for i in 0..n {
+                    // a walk that fails part way returns the qids up to there
+                    let next = match self.child(&path, req.str()?) {
+                        Ok(next) => next,
+                        Err(e) if i == 0 => return Err(e),
+                        Err(_) => break,
+                    };
+                    match std::fs::symlink_metadata(&next) {
+                        Ok(m) => r.qid(&m),
+                        Err(e) if i == 0 => return Err(errno(e)),
+                        Err(_) => break,
+                    };
+                    path = next;
+                    r.0[0..2].copy_from_slice(&(i + 1).to_le_bytes());
+                }
+
+                if u16::from_le_bytes([r.0[0], r.0[1]]) == n {
+                    self.fids.insert(newfid, Fid::new(path));
+                }
+            },
+            TCLUNK => {
+                self.fids.remove(&req.u32()?).ok_or(libc::EBADF as u32)?;
+            },
+            TREMOVE => {
+                let fid = req.u32()?;
+                let path = self.path(fid)?;
+                self.fids.remove(&fid);
+                self.writable()?;
+
+                let m = std::fs::symlink_metadata(&path).map_err(errno)?;
+                if m.is_dir() { std::fs::remove_dir(&path) } else { std::fs::remove_file(&path) }.map_err(errno)?;
+            },
+            TGETATTR => {
+                let path = self.path(req.u32()?)?;
+                let m = std::fs::symlink_metadata(&path).map_err(errno)?;
+
+                r.u64(P9_GETATTR_BASIC).qid(&m).u32(m.mode()).u32(m.uid()).u32(m.gid()).u64(m.nlink());
+                r.u64(m.rdev()).u64(m.size()).u64(m.blksize()).u64(m.blocks());
+                r.u64(m.atime() as u64).u64(m.atime_nsec() as u64);
+                r.u64(m.mtime() as u64).u64(m.mtime_nsec() as u64);
+                r.u64(m.ctime() as u64).u64(m.ctime_nsec() as u64);
+                // btime, gen and data_version are not in the basic set
+                r.u64(0).u64(0).u64(0).u64(0);
+            },
+            TSETATTR => self.setattr(req)?,
+            TLOPEN => {
+                let fid = req.u32()?;
+                let flags = req.u32()?;
+                let path = self.path(fid)?;
+                let m = std::fs::symlink_metadata(&path).map_err(errno)?;
+
+                if m.is_dir() {
+                    let entries = self.read_dir(&path)?;
+                    self.fid(fid)?.dir = Some(entries);
+                } else {
+                    if flags & L_O_ACCMODE != 0 || flags & L_O_TRUNC != 0 {
+                        self.writable()?;
+                    }
+
+                    let file = open_options(flags).open(&path).map_err(errno)?;
+                    self.fid(fid)?.file = Some(file);
+                }
+
+                r.qid(&m).u32(0);
+            },
+            TLCREATE => {
+                let fid = req.u32()?;
+                let name = req.str()?;
+                let flags = req.u32()?;
+                let mode = req.u32()?;
+                self.writable()?;
+
+                let path = self.child(&self.path(fid)?, name)?;
+                let mut options = open_options(flags);
+                options.create(true).mode(mode & 0o7777);
+                if flags & L_O_EXCL != 0 {
+                    options.create_new(true);
+                }
+
+                let file = options.open(&path).map_err(errno)?;
+                let m = file.metadata().map_err(errno)?;
+                *self.fid(fid)? = Fid { path, file: Some(file), dir: None };
+                r.qid(&m).u32(0);
+            },
+            TREAD => {
+                let fid = req.u32()?;
+                let offset = req.u64()?;
+                let count = req.u32()?.min(self.msize.saturating_sub(IO_HEADER));
+                let file = self.fid(fid)?.file.as_ref().ok_or(libc::EBADF as u32)?;
+
+                let mut data = vec![0; count as usize];
+                let n = loop {
+                    match file.read_at(&mut data, offset) {
+                        Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                        n => break n.map_err(errno)?,
+                    }
+                };
+                r.u32(n as u32);
+                r.0.extend_from_slice(&data[..n]);
+            },
+            TWRITE => {
+                let fid = req.u32()?;
+                let offset = req.u64()?;
+                let count = req.u32()?;
+                let data = req.bytes(count as usize)?;
+                self.writable()?;
+
+                let file = self.fid(fid)?.file.as_ref().ok_or(libc::EBADF as u32)?;
+                r.u32(file.write_at(data, offset).map_err(errno)? as u32);
+            },
+            TREADDIR => {
+                let fid = req.u32()?;
+                let offset = req.u64()?;
+                let count = req.u32()?.min(self.msize.saturating_sub(IO_HEADER)) as usize;
+                let entries = self.fid(fid)?.dir.as_ref().ok_or(libc::EBADF as u32)?;
+
+                // offsets are indices into the entries, the one of an entry leads to the next
+                let mut data = Reply::default();
+                for (i, (name, m)) in entries.iter().enumerate().skip(offset as usize) {
+                    if data.0.len() + 13 + 8 + 1 + 2 + name.len() > count {
+                        break;
+                    }
+                    data.qid(m).u64(i as u64 + 1).u8(dirent_type(m)).str(name);
+                }
+
+                r.u32(data.0.len() as u32);
+                r.0.extend_from_slice(&data.0);
+            },
+            TFSYNC => {
+                let fid = req.u32()?;
+                if let Some(file) = &self.fid(fid)?.file {
+                    file.sync_all().map_err(errno)?;
+                }
+            },
+            TSTATFS => {
+                let path = self.path(req.u32()?)?;
+                let c = std::ffi::CString::new(path.into_os_string().into_encoded_bytes()).map_err(|_| libc::EINVAL as u32)?;
+                let mut st = unsafe { core::mem::zeroed::<libc::statvfs>() };
+                if unsafe { libc::statvfs(c.as_ptr(), &mut st) } != 0 {
+                    return Err(errno(std::io::Error::last_os_error()));
+                }
+
+                // V9FS_MAGIC as the type
+                r.u32(0x01021997).u32(st.f_bsize as u32).u64(st.f_blocks).u64(st.f_bfree).u64(st.f_bavail);
+                r.u64(st.f_files).u64(st.f_ffree).u64(st.f_fsid).u32(st.f_namemax as u32);
+            },
+            TMKDIR => {
+                let dfid = req.u32()?;
+                let name = req.str()?;
+                let mode = req.u32()?;
+                self.writable()?;
+
+                let path = self.child(&self.path(dfid)?, name)?;
+                std::fs::DirBuilder::new().mode(mode & 0o7777).create(&path).map_err(errno)?;
+                r.qid(&std::fs::symlink_metadata(&path).map_err(errno)?);
+            },
+            TSYMLINK => {
+                let fid = req.u32()?;
+                let name = req.str()?;
+                let target = req.str()?;
+                self.writable()?;
+
+                let path = self.child(&self.path(fid)?, name)?;
+                std::os::unix::fs::symlink(target, &path).map_err(errno)?;
+                r.qid(&std::fs::symlink_metadata(&path).map_err(errno)?);
+            },
+            TLINK => {
+                let dfid = req.u32()?;
+                let fid = req.u32()?;
+                let name = req.str()?;
+                self.writable()?;
+
+                let path = self.child(&self.path(dfid)?, name)?;
+                std::fs::hard_link(self.path(fid)?, path).map_err(errno)?;
+            },
+            TREADLINK => {
+                let path = self.path(req.u32()?)?;
+                let target = std::fs::read_link(path).map_err(errno)?;
+                r.str(target.to_str().ok_or(libc::EINVAL as u32)?);
+            },
+            TRENAME => {
+                let fid = req.u32()?;
+                let dfid = req.u32()?;
+                let name = req.str()?;
+                self.writable()?;
+
+                let to = self.child(&self.path(dfid)?, name)?;
+                std::fs::rename(self.path(fid)?, &to).map_err(errno)?;
+                self.fid(fid)?.path = to;
+            },
+            TRENAMEAT => {
+                let olddir = req.u32()?;
+                let oldname = req.str()?;
+                let newdir = req.u32()?;
+                let newname = req.str()?;
+                self.writable()?;
+
+                let from = self.child(&self.path(olddir)?, oldname)?;
+                let to = self.child(&self.path(newdir)?, newname)?;
+                std::fs::rename(from, to).map_err(errno)?;
+            },
+            TUNLINKAT => {
+                let dfid = req.u32()?;
+                let name = req.str()?;
+                let flags = req.u32()?;
+                self.writable()?;
+
+                let path = self.child(&self.path(dfid)?, name)?;
+                if flags & AT_REMOVEDIR != 0 { std::fs::remove_dir(path) } else { std::fs::remove_file(path) }.map_err(errno)?;
+            },
+            TLOCK => {
+                // locks only matter between clients, and there is one
+                r.u8(0);
+            },
+            TGETLOCK => {
+                let _fid = req.u32()?;
+                let _ty = req.u8()?;
+                let start = req.u64()?;
+                let length = req.u64()?;
+                let proc_id = req.u32()?;
+                let client_id = req.str()?;
+                r.u8(F_UNLCK).u64(start).u64(length).u32(proc_id).str(client_id);
+            },
+            TXATTRWALK | TMKNOD => return Err(libc::EOPNOTSUPP as u32),
+            _ => return Err(libc::ENOSYS as u32),
+        }
+
+        Ok(r)
+    }
+
+    fn setattr(&mut self, req: &mut Req) -> Result<()> {
+        let fid = req.u32()?;
+        let valid = req.u32()?;
+        let mode = req.u32()?;
+        let uid = req.u32()?;
+        let gid = req.u32()?;
+        let size = req.u64()?;
+        let atime = (req.u64()?, req.u64()?);
+        let mtime = (req.u64()?, req.u64()?);
+        self.writable()?;
+
+        let path = self.path(fid)?;
+        // chmod and truncate would follow a symlink
+        let symlink = std::fs::symlink_metadata(&path).is_ok_and(|m| m.file_type().is_symlink());
+        if symlink && valid & (P9_SETATTR_MODE | P9_SETATTR_SIZE) != 0 {
+            return Err(libc::ELOOP as u32);
+        }
+
+        if valid & P9_SETATTR_MODE != 0 {
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode & 0o7777)).map_err(errno)?;
+        }
+
+        if valid & (P9_SETATTR_UID | P9_SETATTR_GID) != 0 {
+            let uid = (valid & P9_SETATTR_UID != 0).then_some(uid);
+            let gid = (valid & P9_SETATTR_GID != 0).then_some(gid);
+            std::os::unix::fs::lchown(&path, uid, gid).map_err(errno)?;
+        }
+
+        if valid & P9_SETATTR_SIZE != 0 {
+            match &self.fid(fid)?.file {
+                Some(file) => file.set_len(size),
+                None => OpenOptions::new().write(true).custom_flags(libc::O_NOFOLLOW).open(&path).and_then(|f| f.set_len(size)),
+            }
+            .map_err(errno)?;
+        }
+
+        if valid & (P9_SETATTR_ATIME | P9_SETATTR_MTIME) != 0 {
+            let time = |set: u32, given: u32, (sec, nsec): (u64, u64)| match () {
+                _ if valid & set == 0 => libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_OMIT },
+                _ if valid & given == 0 => libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_NOW },
+                _ => libc::timespec { tv_sec: sec as _, tv_nsec: nsec as _ },
+            };
+            let times = [
+                time(P9_SETATTR_ATIME, P9_SETATTR_ATIME_SET, atime),
+                time(P9_SETATTR_MTIME, P9_SETATTR_MTIME_SET, mtime),
+            ];
+
+            let c = std::ffi::CString::new(path.into_os_string().into_encoded_bytes()).map_err(|_| libc::EINVAL as u32)?;
+            if unsafe { libc::utimensat(libc::AT_FDCWD, c.as_ptr(), times.as_ptr(), libc::AT_SYMLINK_NOFOLLOW) } != 0 {
+                return Err(errno(std::io::Error::last_os_error()));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Entries of a directory, with `.` and `..` first, in a stable order
+    fn read_dir(&self, path: &Path) -> Result<Vec<(String, std::fs::Metadata)>> {
+        let mut entries = Vec::new();
+        for name in [".", ".."] {
+            entries.push((name.to_string(), std::fs::metadata(self.child(path, name)?).map_err(errno)?));
+        }
+
+        let mut rest = std::fs::read_dir(path)
+            .map_err(errno)?
+            .filter_map(|e| {
+                let e = e.ok()?;
+                Some((e.file_name().into_string().ok()?, e.path().symlink_metadata().ok()?))
+            })
+            .collect::<Vec<_>>();
+        rest.sort_by(|a, b| a.0.cmp(&b.0));
+
+        entries.extend(rest);
+        Ok(entries)
+    }
+}
+
+fn open_options(flags: u32) -> OpenOptions {
+    let mut options = OpenOptions::new();
+    match flags & L_O_ACCMODE {
+        L_O_WRONLY => options.write(true),
+        L_O_RDWR => options.read(true).write(true),
+        _ => options.read(true),
+    };
+
+    options.truncate(flags & L_O_TRUNC != 0).append(flags & L_O_APPEND != 0).custom_flags(libc::O_NOFOLLOW);
+    options
+}
+
+fn dirent_type(m: &std::fs::Metadata) -> u8 {
+    match m.file_type() {
+        t if t.is_dir() => libc::DT_DIR,
+        t if t.is_symlink() => libc::DT_LNK,
+        t if t.is_file() => libc::DT_REG,
+        _ => libc::DT_UNKNOWN,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory under the system temporary directory
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("emu-p9-{name}-{}", std::process::id()));
+        _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Sends a request and returns the type and body of the reply
+    fn call(server: &mut Server, ty: u8, body: &mut Reply) -> (u8, Vec<u8>) {
+        let mut msg = Reply::default();
+        msg.u32(7 + body.0.len() as u32).u8(ty).u16(1);
+        msg.0.append(&mut body.0);
+
+        let reply = server.handle(&msg.0);
+        (reply[4], reply[7..].to_vec())
+    }
+
+    fn error(reply: (u8, Vec<u8>)) -> u32 {
+        assert_eq!(reply.0, RLERROR, "request succeeded");
+        u32::from_le_bytes(reply.1[..4].try_into().unwrap())
+    }
+
+    fn walk(server: &mut Server, fid: u32, newfid: u32, names: &[&str]) -> (u8, Vec<u8>) {
+        let mut body = Reply::default();
+        body.u32(fid).u32(newfid).u16(names.len() as u16);
+        for name in names {
+            body.str(name);
+        }
+
+        call(server, TWALK, &mut body)
+    }
+
+    #[test]
+    fn symlinks_do_not_leave_the_export() {
+        let outside = temp_dir("outside");
+        std::fs::write(outside.join("secret"), "secret").unwrap();
+        let share = temp_dir("share");
+        let mut server = Server::new(&share, false).unwrap();
+
+        assert_eq!(call(&mut server, TATTACH, Reply::default().u32(0).u32(!0).str("").str("").u32(0)).0, TATTACH + 1);
+        let target = outside.to_str().unwrap();
+        assert_eq!(call(&mut server, TSYMLINK, Reply::default().u32(0).str("x").str(target).u32(0)).0, TSYMLINK + 1);
+
+        // the symlink itself can be walked to and read, but not opened or walked through
+        assert_eq!(walk(&mut server, 0, 1, &["x"]).0, TWALK + 1);
+        assert_eq!(call(&mut server, TREADLINK, Reply::default().u32(1)).1[2..], *target.as_bytes());
+        assert_eq!(error(call(&mut server, TLOPEN, Reply::default().u32(1).u32(L_O_RDWR))), libc::ELOOP as u32);
+        assert_eq!(walk(&mut server, 0, 2, &["x", "secret"]).1[..2], 1u16.to_le_bytes());
+        assert_eq!(error(call(&mut server, TGETATTR, Reply::default().u32(2).u64(P9_GETATTR_BASIC))), libc::EBADF as u32);
+        assert_eq!(error(walk(&mut server, 1, 2, &["secret"])), libc::ELOOP as u32);
+        assert_eq!(error(call(&mut server, TLCREATE, Reply::default().u32(1).str("new").u32(L_O_RDWR).u32(0o644).u32(0))), libc::ELOOP as u32);
+        let size = P9_SETATTR_SIZE;
+        assert_eq!(error(call(&mut server, TSETATTR, Reply::default().u32(1).u32(size).u32(0).u32(0).u32(0).u64(0).u64(0).u64(0).u64(0).u64(0))), libc::ELOOP as u32);
+
+        // a symlink to a file outside is not followed by a create either
+        let file = outside.join("secret");
+        assert_eq!(call(&mut server, TSYMLINK, Reply::default().u32(0).str("y").str(file.to_str().unwrap()).u32(0)).0, TSYMLINK + 1);
+        assert_eq!(walk(&mut server, 0, 3, &[]).0, TWALK + 1);
+        assert_eq!(error(call(&mut server, TLCREATE, Reply::default().u32(3).str("y").u32(L_O_RDWR | L_O_TRUNC).u32(0o644).u32(0))), libc::ELOOP as u32);
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "secret");
+
+        // files in the export itself still work
+        assert_eq!(walk(&mut server, 0, 4, &[]).0, TWALK + 1);
+        assert_eq!(call(&mut server, TLCREATE, Reply::default().u32(4).str("file").u32(L_O_RDWR).u32(0o644).u32(0)).0, TLCREATE + 1);
+        let mut body = Reply::default();
+        body.u32(4).u64(0).u32(5).0.extend_from_slice(b"hello");
+        assert_eq!(call(&mut server, TWRITE, &mut body).0, TWRITE + 1);
+        assert_eq!(std::fs::read_to_string(share.join("file")).unwrap(), "hello");
+
+        std::fs::remove_dir_all(&outside).unwrap();
+        std::fs::remove_dir_all(&share).unwrap();
+    }
+}
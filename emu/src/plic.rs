@@ -1,50 +1,192 @@
-// basically https://github.com/d0iasm/rvemu-for-book/blob/main/step10/src/plic.rs but with my
-// bus interface
+//! Platform-level interrupt controller
+//!
+//! Sources are level triggered: a device asserts its line, the gateway makes the source pending,
+//! and the source is not made pending again until the claim is completed.
 
 use crate::bus::*;
 use crate::cpu::Exception;
+use core::sync::atomic::*;
+use std::sync::Arc;
+
+/// Number of interrupt sources, including the reserved source 0
+pub const SOURCES: usize = 1024;
+const WORDS: usize = SOURCES / 32;
+const MAX_PRIORITY: u32 = 7;
+
+const PLIC_PRIORITY: u64 = PLIC_BASE;
+const PLIC_PENDING: u64 = PLIC_BASE + 0x1000;
+const PLIC_ENABLE: u64 = PLIC_BASE + 0x2000;
+const PLIC_CONTEXT: u64 = PLIC_BASE + 0x200000;
 
 pub struct Plic {
-    pending: u32,
-    senable: u32,
-    spriority: u32,
-    sclaim: u32,
+    priority: Box<[u32; SOURCES]>,
+    pending: [u32; WORDS],
+    /// Claimed and not yet completed
+    in_service: [u32; WORDS],
+    /// Lines asserted by devices
+    level: [u32; WORDS],
+    /// M-mode and S-mode context of each hart, in that order
+    contexts: Vec<Context>,
+    /// Whether each context has an interrupt to take, read by the harts without locking the PLIC
+    eip: Arc<[AtomicBool]>,
 }
 
-const PLIC_PENDING: u64 = PLIC_BASE + 0x1000;
-const PLIC_SENABLE: u64 = PLIC_BASE + 0x2080;
-const PLIC_SPRIORITY: u64 = PLIC_BASE + 0x201000;
-const PLIC_SCLAIM: u64 = PLIC_BASE + 0x201004;
+#[derive(Clone)]
+struct Context {
+    enable: [u32; WORDS],
+    threshold: u32,
+}
+
+fn bit(words: &[u32; WORDS], irq: usize) -> bool {
+    (words[irq / 32] >> (irq % 32)) & 1 == 1
+}
+
+fn set_bit(words: &mut [u32; WORDS], irq: usize, v: bool) {
+    words[irq / 32] &= !(1 << (irq % 32));
+    words[irq / 32] |= (v as u32) << (irq % 32);
+}
 
 impl Plic {
-    pub fn new() -> Self {
+    pub fn new(harts: usize) -> Self {
         Self {
-            pending: 0,
-            senable: 0,
-            spriority: 0,
-            sclaim: 0,
+            priority: Box::new([0; SOURCES]),
+            pending: [0; WORDS],
+            in_service: [0; WORDS],
+            level: [0; WORDS],
+            contexts: vec![Context { enable: [0; WORDS], threshold: 0 }; harts * 2],
+            eip: (0..harts * 2).map(|_| AtomicBool::new(false)).collect(),
+        }
+    }
+
+    pub(crate) fn outputs(&self) -> Arc<[AtomicBool]> {
+        Arc::clone(&self.eip)
+    }
+
+    /// Sets the level of an interrupt line
+    pub(crate) fn set_irq(&mut self, irq: usize, level: bool) {
+        if irq == 0 || irq >= SOURCES {
+            return;
         }
+
+        set_bit(&mut self.level, irq, level);
+        if level && !bit(&self.in_service, irq) {
+            set_bit(&mut self.pending, irq, true);
+        }
+
+        self.update();
+    }
+
+    /// The pending and enabled source of a context with the highest priority above its threshold,
+    /// lowest ID first on ties
+    fn best(&self, ctx: usize) -> Option<usize> {
+        let c = &self.contexts[ctx];
+        let mut best = None;
+        let mut best_priority = c.threshold;
+
+        for w in 0..WORDS {
+            let mut bits = self.pending[w] & c.enable[w];
+            while bits != 0 {
+                let irq = w * 32 + bits.trailing_zeros() as usize;
+                bits &= bits - 1;
+
+                if self.priority[irq] > best_priority {
+                    best = Some(irq);
+                    best_priority = self.priority[irq];
+                }
+            }
+        }
+
+        best
+    }
+
+    fn update(&self) {
+        for (ctx, eip) in self.eip.iter().enumerate() {
+            eip.store(self.best(ctx).is_some(), Ordering::SeqCst);
+        }
+    }
+
+    fn claim(&mut self, ctx: usize) -> u32 {
+        let Some(irq) = self.best(ctx) else { return 0 };
+
+        set_bit(&mut self.pending, irq, false);
+        set_bit(&mut self.in_service, irq, true);
+        self.update();
+        irq as _
+    }
+
+    fn complete(&mut self, ctx: usize, irq: u32) {
+        let irq = irq as usize;
+        if irq >= SOURCES || !bit(&self.contexts[ctx].enable, irq) {
+            return;
+        }
+
+        set_bit(&mut self.in_service, irq, false);
+        if bit(&self.level, irq) {
+            set_bit(&mut self.pending, irq, true);
+        }
+
+        self.update();
+    }
+
+    /// Splits a context register address into the context and the offset in its block
+    fn context_of(&self, addr: u64, base: u64, stride: u64) -> Option<(usize, u64)> {
+        let off = addr.checked_sub(base)?;
+        let ctx = (off / stride) as usize;
+        (ctx < self.contexts.len()).then_some((ctx, off % stride))
     }
 }
 
 impl Device for Plic {
     fn load_u32(&mut self, addr: u64) -> Result<u32, Exception> {
         Ok(match addr {
-            PLIC_PENDING => self.pending,
-            PLIC_SENABLE => self.senable,
-            PLIC_SPRIORITY => self.spriority,
-            PLIC_SCLAIM => self.sclaim,
-            _ => 0,
+            PLIC_PRIORITY..PLIC_PENDING => self.priority.get(((addr - PLIC_PRIORITY) / 4) as usize).copied().unwrap_or(0),
+            PLIC_PENDING..PLIC_ENABLE => self.pending.get(((addr - PLIC_PENDING) / 4) as usize).copied().unwrap_or(0),
+            PLIC_ENABLE..PLIC_CONTEXT => match self.context_of(addr, PLIC_ENABLE, 0x80) {
+                Some((ctx, off)) => self.contexts[ctx].enable.get(off as usize / 4).copied().unwrap_or(0),
+                None => 0,
+            },
+            _ => match self.context_of(addr, PLIC_CONTEXT, 0x1000) {
+                Some((ctx, 0)) => self.contexts[ctx].threshold,
+                Some((ctx, 4)) => self.claim(ctx),
+                _ => 0,
+            },
         })
     }
 
     fn store_u32(&mut self, addr: u64, val: u32) -> Result<(), Exception> {
-        Ok(match addr {
-            PLIC_PENDING => self.pending = val,
-            PLIC_SENABLE => self.senable = val,
-            PLIC_SPRIORITY => self.spriority = val,
-            PLIC_SCLAIM => self.sclaim = val,
-            _ => {}
-        })
+        match addr {
+            PLIC_PRIORITY..PLIC_PENDING => {
+                let irq = ((addr - PLIC_PRIORITY) / 4) as usize;
+                if irq != 0 && irq < SOURCES {
+                    self.priority[irq] = val.min(MAX_PRIORITY);
+                }
+            },
+            // pending bits are read only
+            PLIC_PENDING..PLIC_ENABLE => {},
+            PLIC_ENABLE..PLIC_CONTEXT => if let Some((ctx, off)) = self.context_of(addr, PLIC_ENABLE, 0x80) {
+                if let Some(w) = self.contexts[ctx].enable.get_mut(off as usize / 4) {
+                    // source 0 does not exist
+                    *w = if off == 0 { val & !1 } else { val };
+                }
+            },
+            _ => match self.context_of(addr, PLIC_CONTEXT, 0x1000) {
+                Some((ctx, 0)) => self.contexts[ctx].threshold = val.min(MAX_PRIORITY),
+                Some((ctx, 4)) => self.complete(ctx, val),
+                _ => {},
+            },
+        }
+
+        self.update();
+        Ok(())
+    }
+
+    /// Clears the priorities, enables, thresholds and claims. The lines keep their levels, and the
+    /// asserted ones are pending again.
+    fn reset(&mut self) {
+        self.priority.fill(0);
+        self.pending = self.level;
+        self.in_service = [0; WORDS];
+        self.contexts.iter_mut().for_each(|c| *c = Context { enable: [0; WORDS], threshold: 0 });
+        self.update();
     }
 }
@@ -1,42 +1,160 @@
 use crate::bus::*;
 use crate::cpu::Exception;
+use core::sync::atomic::*;
 
-pub struct Ram<'a> {
-    ram: &'a mut [u8],
+/// Main memory, backed by host atomics so that harts on different threads can share it
+pub struct Ram {
+    ram: Box<[AtomicU64]>,
 }
 
-impl<'a> Ram<'a> {
-    pub fn new(ram: &'a mut [u8]) -> Self {
-        Self { ram }
+impl Ram {
+    pub fn new(image: &[u8]) -> Self {
+        // zeroed by the allocator, so that the host only backs the pages the guest touches
+        let zeroed = vec![0u64; (RAM_SIZE / 8) as usize].into_boxed_slice();
+        let ram = Self {
+            // AtomicU64 has the same in-memory representation as u64
+            ram: unsafe { Box::from_raw(Box::into_raw(zeroed) as *mut [AtomicU64]) },
+        };
+
+        for (i, b) in image.iter().enumerate() {
+            _ = ram.store_u8(RAM_BASE + i as u64, *b);
+        }
+
+        ram
+    }
+
+    /// Zeroes all of RAM. Words that are already zero are only read, so that the host does not
+    /// have to back the pages the guest never touched.
+    pub(crate) fn clear(&self) {
+        for w in self.ram.iter() {
+            if w.load(Ordering::Relaxed) != 0 {
+                w.store(0, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn len(&self) -> u64 {
+        self.ram.len() as u64 * 8
+    }
+
+    /// A pointer to byte `start` of RAM, derived from the whole slice so that it may be used for
+    /// any byte in it
+    fn ptr(&self, start: u64) -> *mut u8 {
+        self.ram.as_ptr().cast::<u8>().cast_mut().wrapping_add(start as usize)
+    }
+
+    fn byte(&self, start: u64) -> &AtomicU8 {
+        unsafe { AtomicU8::from_ptr(self.ptr(start)) }
+    }
+
+    pub(crate) fn read(&self, addr: u64, buf: &mut [u8]) -> Result<(), Exception> {
+        let start = addr.wrapping_sub(RAM_BASE);
+        if start.checked_add(buf.len() as u64).is_none_or(|e| e > self.len()) {
+            return Err(Exception::LoadAccessFault);
+        }
+
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = self.byte(start + i as u64).load(Ordering::Relaxed);
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn write(&self, addr: u64, buf: &[u8]) -> Result<(), Exception> {
+        let start = addr.wrapping_sub(RAM_BASE);
+        if start.checked_add(buf.len() as u64).is_none_or(|e| e > self.len()) {
+            return Err(Exception::StoreAccessFault);
+        }
+
+        for (i, b) in buf.iter().enumerate() {
+            self.byte(start + i as u64).store(*b, Ordering::Relaxed);
+        }
+
+        Ok(())
     }
 }
 
 macro_rules! gen {
-    ($l: tt $s: tt $t: tt $sz: tt) => {
-        fn $l(&mut self, addr: u64) -> Result<$t, Exception> {
+    ($l: tt $s: tt $t: tt $get: tt $sz: tt) => {
+        fn $l(&self, addr: u64) -> Result<$t, Exception> {
             let start = addr - RAM_BASE;
-            if start + $sz <= self.ram.len() as u64 {
-                Ok($t::from_le_bytes(self.ram[start as usize..start as usize + $sz].try_into().unwrap()))
-            } else {
-                Err(Exception::LoadAccessFault)
+            if start + $sz > self.len() {
+                return Err(Exception::LoadAccessFault);
             }
+
+            Ok(match self.$get(start) {
+                Some(a) => a.load(Ordering::Relaxed),
+                None => {
+                    let mut b = [0; $sz];
+                    for (i, b) in b.iter_mut().enumerate() {
+                        *b = self.byte(start + i as u64).load(Ordering::Relaxed);
+                    }
+
+                    $t::from_le_bytes(b)
+                },
+            })
         }
 
-        fn $s(&mut self, addr: u64, val: $t) -> Result<(), Exception> {
+        fn $s(&self, addr: u64, val: $t) -> Result<(), Exception> {
             let start = addr - RAM_BASE;
-            if start + $sz <= self.ram.len() as u64 {
-                self.ram[start as usize..start as usize + $sz].copy_from_slice(&val.to_le_bytes());
-                Ok(())
-            } else {
-                Err(Exception::StoreAccessFault)
+            if start + $sz > self.len() {
+                return Err(Exception::StoreAccessFault);
+            }
+
+            match self.$get(start) {
+                Some(a) => a.store(val, Ordering::Relaxed),
+                None => for (i, b) in val.to_le_bytes().into_iter().enumerate() {
+                    self.byte(start + i as u64).store(b, Ordering::Relaxed);
+                },
             }
+
+            Ok(())
+        }
+    };
+}
+
+macro_rules! gen_atomic {
+    ($get: tt $at: tt $sz: tt) => {
+        fn $get(&self, start: u64) -> Option<&$at> {
+            // SAFETY: every atomic type has the same layout as its integer, and the access is
+            // naturally aligned and in bounds
+            (start % $sz == 0).then(|| unsafe { $at::from_ptr(self.ptr(start).cast()) })
         }
     };
 }
 
-impl Device for Ram<'_> {
-    gen!(load_u8 store_u8 u8 1);
-    gen!(load_u16 store_u16 u16 2);
-    gen!(load_u32 store_u32 u32 4);
-    gen!(load_u64 store_u64 u64 8);
+macro_rules! gen_amo {
+    ($amo: tt $t: tt $get: tt $sz: tt) => {
+        /// Atomically replaces the value with `f(old)` and returns the old value. Stores nothing if
+        /// `f` returns `None`.
+        pub(crate) fn $amo<F: FnMut($t) -> Option<$t>>(&self, addr: u64, order: Ordering, f: F) -> Result<$t, Exception> {
+            let start = addr - RAM_BASE;
+            if start + $sz > self.len() {
+                return Err(Exception::StoreAccessFault);
+            }
+
+            let a = self.$get(start).ok_or(Exception::StoreAddrMisalign)?;
+            Ok(a.fetch_update(order, Ordering::Relaxed, f).unwrap_or_else(|v| v))
+        }
+    };
+}
+
+impl Ram {
+    gen_atomic!(atomic_u16 AtomicU16 2);
+    gen_atomic!(atomic_u32 AtomicU32 4);
+    gen_atomic!(atomic_u64 AtomicU64 8);
+
+    gen_amo!(amo_u32 u32 atomic_u32 4);
+    gen_amo!(amo_u64 u64 atomic_u64 8);
+
+    fn atomic_u8(&self, start: u64) -> Option<&AtomicU8> {
+        Some(self.byte(start))
+    }
+}
+
+impl SharedDevice for Ram {
+    gen!(load_u8 store_u8 u8 atomic_u8 1);
+    gen!(load_u16 store_u16 u16 atomic_u16 2);
+    gen!(load_u32 store_u32 u32 atomic_u32 4);
+    gen!(load_u64 store_u64 u64 atomic_u64 8);
 }
@@ -0,0 +1,89 @@
+//! Boot ROM, like the MROM of QEMU's virt machine. It holds a reset stub that passes the hart ID in
+//! a0 and the device tree in a1 to the firmware, which is the boot flow firmware for real boards
+//! expects, and OpenSBI's `fw_dynamic` info in a2, which tells the firmware where the kernel is.
+
+use crate::bus::*;
+use crate::cpu::Exception;
+
+/// The reset stub, with the firmware entry and the device tree address in the two doublewords
+/// after it and the `fw_dynamic` info after those
+const RESET_STUB: [u32; 6] = [
+    0x0000_0297, // auipc t0, 0
+    0x0282_8613, // addi a2, t0, 40
+    0xf140_2573, // csrr a0, mhartid
+    0x0202_b583, // ld a1, 32(t0)
+    0x0182_b283, // ld t0, 24(t0)
+    0x0002_8067, // jr t0
+];
+const ENTRY: usize = 24;
+const DTB: usize = 32;
+
+// struct fw_dynamic_info, in doublewords
+const FW_DYNAMIC: usize = 40;
+const FW_DYNAMIC_MAGIC: u64 = 0x4942_534f; // "OSBI"
+const FW_DYNAMIC_VERSION: u64 = 2;
+const NEXT_ADDR: usize = FW_DYNAMIC + 16;
+/// Mode of the next stage, S-mode
+const NEXT_MODE_S: u64 = 1;
+
+pub struct Rom {
+    data: Box<[u8]>,
+}
+
+impl Rom {
+    /// A ROM that jumps to `entry`, with a1 set to `dtb`
+    pub fn new(entry: u64, dtb: u64) -> Self {
+        let mut data = vec![0; ROM_SIZE as usize].into_boxed_slice();
+        for (i, inst) in RESET_STUB.iter().enumerate() {
+            data[i * 4..i * 4 + 4].copy_from_slice(&inst.to_le_bytes());
+        }
+
+        let mut rom = Self { data };
+        rom.set_entry(entry);
+        rom.set_dtb(dtb);
+        // then the next address, options of 0 and a boot hart of 0
+        rom.data[FW_DYNAMIC..FW_DYNAMIC + 8].copy_from_slice(&FW_DYNAMIC_MAGIC.to_le_bytes());
+        rom.data[FW_DYNAMIC + 8..FW_DYNAMIC + 16].copy_from_slice(&FW_DYNAMIC_VERSION.to_le_bytes());
+        rom.data[NEXT_ADDR + 8..NEXT_ADDR + 16].copy_from_slice(&NEXT_MODE_S.to_le_bytes());
+        rom
+    }
+
+    pub fn set_entry(&mut self, entry: u64) {
+        self.data[ENTRY..ENTRY + 8].copy_from_slice(&entry.to_le_bytes());
+    }
+
+    /// Where `fw_dynamic` firmware goes on to in S-mode, like a kernel
+    pub fn set_next_addr(&mut self, addr: u64) {
+        self.data[NEXT_ADDR..NEXT_ADDR + 8].copy_from_slice(&addr.to_le_bytes());
+    }
+
+    pub(crate) fn entry(&self) -> u64 {
+        u64::from_le_bytes(self.data[ENTRY..ENTRY + 8].try_into().unwrap())
+    }
+
+    pub(crate) fn dtb(&self) -> u64 {
+        u64::from_le_bytes(self.data[DTB..DTB + 8].try_into().unwrap())
+    }
+
+    pub fn set_dtb(&mut self, dtb: u64) {
+        self.data[DTB..DTB + 8].copy_from_slice(&dtb.to_le_bytes());
+    }
+}
+
+macro_rules! gen {
+    ($l: tt $t: tt $sz: tt) => {
+        fn $l(&self, addr: u64) -> Result<$t, Exception> {
+            let off = (addr - ROM_BASE) as usize;
+            let bytes = self.data.get(off..off + $sz).ok_or(Exception::LoadAccessFault)?;
+            Ok($t::from_le_bytes(bytes.try_into().unwrap()))
+        }
+    };
+}
+
+/// Read only, stores fault
+impl SharedDevice for Rom {
+    gen!(load_u8 u8 1);
+    gen!(load_u16 u16 2);
+    gen!(load_u32 u32 4);
+    gen!(load_u64 u64 8);
+}
@@ -0,0 +1,158 @@
+//! Goldfish RTC, the wall clock of QEMU's virt machine: nanoseconds since the Unix epoch, with one
+//! alarm. The time is either the host's or counted off `mtime` from a fixed epoch, which keeps runs
+//! reproducible.
+
+use crate::bus::*;
+use crate::cpu::Exception;
+use std::io::ErrorKind;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// PLIC source of the RTC, the same as on QEMU's virt machine
+pub const RTC_IRQ: usize = 11;
+
+// register offsets
+const TIME_LOW: u64 = 0x00;
+const TIME_HIGH: u64 = 0x04;
+const ALARM_LOW: u64 = 0x08;
+const ALARM_HIGH: u64 = 0x0c;
+const IRQ_ENABLED: u64 = 0x10;
+const CLEAR_ALARM: u64 = 0x14;
+const ALARM_STATUS: u64 = 0x18;
+const CLEAR_INTERRUPT: u64 = 0x1c;
+
+const NANOS: u128 = 1_000_000_000;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Clock {
+    /// The host's wall clock
+    Host,
+    /// Starts at this many seconds since the Unix epoch and advances with `mtime`
+    Epoch(u64),
+}
+
+pub struct Rtc {
+    clock: Clock,
+    timebase_freq: u64,
+    /// `mtime` as of the last access or poll, for `Clock::Epoch`
+    mtime: u64,
+    /// Added to the clock, set when the guest sets the time
+    offset: u64,
+
+    /// Upper half of the time, latched by reading the lower half
+    time_high: u32,
+    /// Upper half of a time or alarm being written, the lower half completes the write
+    pending_high: u32,
+    alarm: u64,
+    armed: bool,
+    irq_enabled: bool,
+    interrupt: bool,
+}
+
+impl Rtc {
+    /// An RTC whose `Clock::Epoch` advances with an `mtime` of `timebase_freq`
+    pub fn new(clock: Clock, timebase_freq: u64) -> Self {
+        Self {
+            clock,
+            timebase_freq: timebase_freq.max(1),
+            mtime: 0,
+            offset: 0,
+
+            time_high: 0,
+            pending_high: 0,
+            alarm: 0,
+            armed: false,
+            irq_enabled: false,
+            interrupt: false,
+        }
+    }
+
+    /// Parses an RTC spec: `host` or `epoch=SECONDS`
+    pub fn open(spec: &str, timebase_freq: u64) -> std::io::Result<Self> {
+        let clock = match spec.split_once('=') {
+            None if spec == "host" => Clock::Host,
+            Some(("epoch", s)) => Clock::Epoch(s.parse().map_err(|_| {
+                std::io::Error::new(ErrorKind::InvalidInput, format!("bad epoch `{s}`"))
+            })?),
+            _ => return Err(std::io::Error::new(ErrorKind::InvalidInput, format!("unknown rtc `{spec}`"))),
+        };
+
+        Ok(Self::new(clock, timebase_freq))
+    }
+
+    /// Nanoseconds since the Unix epoch
+    fn now(&self) -> u64 {
+        let clock = match self.clock {
+            Clock::Host => SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64),
+            Clock::Epoch(secs) => (secs as u128 * NANOS + self.mtime as u128 * NANOS / self.timebase_freq as u128) as u64,
+        };
+
+        clock.wrapping_add(self.offset)
+    }
+
+    fn set_time(&mut self, t: u64) {
+        self.offset = self.offset.wrapping_add(t.wrapping_sub(self.now()));
+    }
+
+    fn check_alarm(&mut self) {
+        if self.armed && self.now() >= self.alarm {
+            self.armed = false;
+            self.interrupt |= self.irq_enabled;
+        }
+    }
+}
+
+impl Device for Rtc {
+    fn load_u32(&mut self, addr: u64) -> Result<u32, Exception> {
+        Ok(match addr - RTC_BASE {
+            TIME_LOW => {
+                let t = self.now();
+                self.time_high = (t >> 32) as u32;
+                t as u32
+            },
+            TIME_HIGH => self.time_high,
+            ALARM_LOW => self.alarm as u32,
+            ALARM_HIGH => (self.alarm >> 32) as u32,
+            IRQ_ENABLED => self.irq_enabled as u32,
+            ALARM_STATUS => self.armed as u32,
+            _ => 0,
+        })
+    }
+
+    fn store_u32(&mut self, addr: u64, val: u32) -> Result<(), Exception> {
+        match addr - RTC_BASE {
+            TIME_LOW => self.set_time((self.pending_high as u64) << 32 | val as u64),
+            TIME_HIGH | ALARM_HIGH => self.pending_high = val,
+            ALARM_LOW => {
+                self.alarm = (self.pending_high as u64) << 32 | val as u64;
+                self.armed = true;
+                self.check_alarm();
+            },
+            IRQ_ENABLED => {
+                self.irq_enabled = val & 1 != 0;
+                if !self.irq_enabled {
+                    self.interrupt = false;
+                }
+            },
+            CLEAR_ALARM => self.armed = false,
+            CLEAR_INTERRUPT => self.interrupt = false,
+            _ => {},
+        }
+
+        Ok(())
+    }
+
+    fn run(&mut self, bus: &Bus) {
+        self.mtime = bus.mtime();
+        self.check_alarm();
+    }
+
+    fn irq(&self) -> Option<(usize, bool)> {
+        Some((RTC_IRQ, self.interrupt))
+    }
+
+    /// Disarms the alarm, the time the guest set is kept like on a battery backed clock
+    fn reset(&mut self) {
+        (self.time_high, self.pending_high, self.alarm) = (0, 0, 0);
+        (self.armed, self.irq_enabled, self.interrupt) = (false, false, false);
+    }
+}
@@ -0,0 +1,82 @@
+//! SiFive test finisher (`sifive,test0`), the syscon that guests power off and reboot the machine
+//! through. A write is latched, and the machine stops at the end of the instruction that made it.
+
+use crate::bus::*;
+use crate::cpu::Exception;
+use core::sync::atomic::*;
+
+const FINISHER_FAIL: u32 = 0x3333;
+const FINISHER_PASS: u32 = 0x5555;
+const FINISHER_RESET: u32 = 0x7777;
+
+/// Why a run stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exit {
+    /// Powered off with success
+    Pass,
+    /// Powered off with a failure, and the code in the upper half of the write
+    Fail(u16),
+    /// Asked for a reset
+    Reset,
+}
+
+impl Exit {
+    /// Status for the host process to exit with, a failure never exits with 0
+    pub fn status(self) -> i32 {
+        match self {
+            Exit::Pass | Exit::Reset => 0,
+            Exit::Fail(code) => code.max(1) as i32,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Syscon {
+    /// The finisher write that stops the machine, or 0
+    latch: AtomicU32,
+}
+
+impl Syscon {
+    /// The exit the guest asked for, if any. Cheap enough to check after every instruction.
+    pub(crate) fn exit(&self) -> Option<Exit> {
+        let val = self.latch.load(Ordering::SeqCst);
+        match val & 0xffff {
+            FINISHER_PASS => Some(Exit::Pass),
+            FINISHER_FAIL => Some(Exit::Fail((val >> 16) as u16)),
+            FINISHER_RESET => Some(Exit::Reset),
+            _ => None,
+        }
+    }
+
+    /// Latches `exit` like a write from the guest would
+    pub(crate) fn request(&self, exit: Exit) {
+        let val = match exit {
+            Exit::Pass => FINISHER_PASS,
+            Exit::Fail(code) => (code as u32) << 16 | FINISHER_FAIL,
+            Exit::Reset => FINISHER_RESET,
+        };
+
+        _ = self.latch.compare_exchange(0, val, Ordering::SeqCst, Ordering::SeqCst);
+    }
+
+    pub(crate) fn take_exit(&self) -> Option<Exit> {
+        let exit = self.exit();
+        self.latch.store(0, Ordering::SeqCst);
+        exit
+    }
+}
+
+impl SharedDevice for Syscon {
+    fn load_u32(&self, _addr: u64) -> Result<u32, Exception> {
+        Ok(0)
+    }
+
+    fn store_u32(&self, addr: u64, val: u32) -> Result<(), Exception> {
+        // other values are ignored, like on the real thing
+        if addr == SYSCON_BASE && matches!(val & 0xffff, FINISHER_FAIL | FINISHER_PASS | FINISHER_RESET) {
+            _ = self.latch.compare_exchange(0, val, Ordering::SeqCst, Ordering::SeqCst);
+        }
+
+        Ok(())
+    }
+}
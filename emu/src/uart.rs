@@ -1,39 +1,223 @@
 //! 16550(A) UART chip emulation
-// TODO:
-// - input
-// - output to anything not just stdout
-// - interrupts
 
 use crate::bus::*;
+use crate::chardev::Chardev;
 use crate::cpu::Exception;
+use std::collections::VecDeque;
 
-const RHR: u64 = UART_BASE + 0;
-const THR: u64 = UART_BASE + 0;
-const IER: u64 = UART_BASE + 1;
-const FCR: u64 = UART_BASE + 2;
-const ISR: u64 = UART_BASE + 2;
+/// PLIC sources of the UARTs, the first one is the same as on QEMU's virt machine
+pub const UART_IRQS: [usize; UARTS] = [10, 12, 13, 14];
+/// Number of UARTs a machine can have, one every `UART_SIZE` from `UART_BASE`
+pub const UARTS: usize = 4;
+
+// register offsets
+const RHR: u64 = 0;
+const THR: u64 = 0;
+const DLL: u64 = 0;
+const IER: u64 = 1;
+const DLM: u64 = 1;
+const FCR: u64 = 2;
+const ISR: u64 = 2;
+const LCR: u64 = 3;
+const MCR: u64 = 4;
+const LSR: u64 = 5;
+const MSR: u64 = 6;
+const SCR: u64 = 7;
+
+const IER_RX: u8 = 0x01;
+const IER_THRE: u8 = 0x02;
+
+const FCR_ENABLE: u8 = 0x01;
+const FCR_CLEAR_RX: u8 = 0x02;
+
+const ISR_NONE: u8 = 0x01;
+const ISR_THRE: u8 = 0x02;
+const ISR_RX: u8 = 0x04;
+const ISR_TIMEOUT: u8 = 0x0c;
+const ISR_FIFO: u8 = 0xc0;
+
+const LCR_DLAB: u8 = 0x80;
+const MCR_LOOP: u8 = 0x10;
+
+const LSR_DR: u8 = 0x01;
+const LSR_THRE: u8 = 0x20;
+const LSR_TEMT: u8 = 0x40;
+
+const FIFO_SIZE: usize = 16;
 
 pub struct Uart {
+    base: u64,
+    irq: usize,
+    backend: Box<dyn Chardev>,
+    rx: VecDeque<u8>,
+    /// Set when the transmitter empties, cleared by reading the ISR or writing the THR
+    thre_pending: bool,
+
+    ier: u8,
+    fcr: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    divisor: u16,
 }
 
 impl Uart {
-    pub fn new() -> Self {
+    pub fn new(base: u64, irq: usize, backend: Box<dyn Chardev>) -> Self {
         Self {
+            base,
+            irq,
+            backend,
+            rx: VecDeque::new(),
+            thre_pending: false,
+
+            ier: 0,
+            fcr: 0,
+            lcr: 0,
+            mcr: 0,
+            scr: 0,
+            divisor: 0,
         }
     }
+
+    fn fifo_size(&self) -> usize {
+        if self.fcr & FCR_ENABLE != 0 { FIFO_SIZE } else { 1 }
+    }
+
+    fn trigger_level(&self) -> usize {
+        if self.fcr & FCR_ENABLE == 0 {
+            return 1;
+        }
+
+        match self.fcr >> 6 {
+            0 => 1,
+            1 => 4,
+            2 => 8,
+            _ => 14,
+        }
+    }
+
+    fn receive(&mut self) {
+        while self.rx.len() < self.fifo_size() {
+            match self.backend.read() {
+                Some(b) => self.rx.push_back(b),
+                None => break,
+            }
+        }
+    }
+
+    /// The highest priority pending interrupt
+    fn isr(&self) -> u8 {
+        let fifo = if self.fcr & FCR_ENABLE != 0 { ISR_FIFO } else { 0 };
+
+        let id = if self.ier & IER_RX != 0 && self.rx.len() >= self.trigger_level() {
+            ISR_RX
+        } else if self.ier & IER_RX != 0 && !self.rx.is_empty() {
+            // characters below the trigger level time out right away, since there is no baud rate
+            ISR_TIMEOUT
+        } else if self.ier & IER_THRE != 0 && self.thre_pending {
+            ISR_THRE
+        } else {
+            ISR_NONE
+        };
+
+        fifo | id
+    }
+
+    fn transmit(&mut self, val: u8) {
+        if self.mcr & MCR_LOOP != 0 {
+            if self.rx.len() < self.fifo_size() {
+                self.rx.push_back(val);
+            }
+        } else {
+            self.backend.write(val);
+        }
+
+        self.thre_pending = true;
+    }
 }
 
 impl Device for Uart {
     fn load_u8(&mut self, addr: u64) -> Result<u8, Exception> {
-        Ok(match addr {
+        let dlab = self.lcr & LCR_DLAB != 0;
+
+        Ok(match addr - self.base {
+            DLL if dlab => self.divisor as u8,
+            DLM if dlab => (self.divisor >> 8) as u8,
+            RHR => {
+                let b = self.rx.pop_front().unwrap_or(0);
+                self.receive();
+                b
+            },
+            IER => self.ier,
+            ISR => {
+                let isr = self.isr();
+                if isr & 0xf == ISR_THRE {
+                    self.thre_pending = false;
+                }
+
+                isr
+            },
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => {
+                self.receive();
+                (!self.rx.is_empty() as u8 * LSR_DR) | LSR_THRE | LSR_TEMT
+            },
+            MSR => if self.mcr & MCR_LOOP != 0 {
+                // dtr, rts, out1 and out2 loop back to dsr, cts, ri and dcd
+                let m = self.mcr;
+                ((m & 1) << 5) | ((m & 2) << 3) | ((m & 4) << 4) | ((m & 8) << 4)
+            } else {
+                // cts, dsr and dcd are always up
+                0xb0
+            },
+            SCR => self.scr,
             _ => 0,
         })
     }
 
     fn store_u8(&mut self, addr: u64, val: u8) -> Result<(), Exception> {
-        Ok(match addr {
-            THR => print!("{}", val as char),
+        let dlab = self.lcr & LCR_DLAB != 0;
+
+        match addr - self.base {
+            DLL if dlab => self.divisor = (self.divisor & 0xff00) | val as u16,
+            DLM if dlab => self.divisor = (self.divisor & 0x00ff) | (val as u16) << 8,
+            THR => self.transmit(val),
+            IER => {
+                // enabling the thre interrupt while the transmitter is empty raises it
+                if val & IER_THRE != 0 && self.ier & IER_THRE == 0 {
+                    self.thre_pending = true;
+                }
+
+                self.ier = val & 0x0f;
+            },
+            FCR => {
+                if val & FCR_CLEAR_RX != 0 || (val ^ self.fcr) & FCR_ENABLE != 0 {
+                    self.rx.clear();
+                }
+
+                self.fcr = val & 0xc1;
+            },
+            LCR => self.lcr = val,
+            MCR => self.mcr = val & 0x1f,
+            SCR => self.scr = val,
             _ => {},
-        })
+        }
+
+        Ok(())
+    }
+
+    fn poll(&mut self) {
+        self.receive();
+    }
+
+    fn irq(&self) -> Option<(usize, bool)> {
+        Some((self.irq, self.isr() & ISR_NONE == 0))
+    }
+
+    fn reset(&mut self) {
+        self.rx.clear();
+        self.thre_pending = false;
+        (self.ier, self.fcr, self.lcr, self.mcr, self.scr, self.divisor) = (0, 0, 0, 0, 0, 0);
     }
 }
@@ -0,0 +1,162 @@
+//! virtio-blk, a block device on top of a `disk::Disk`
+
+use super::*;
+use crate::disk::{Drive, SECTOR_SIZE};
+
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_BLK_SIZE: u64 = 1 << 6;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+const VIRTIO_BLK_F_DISCARD: u64 = 1 << 13;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+const VIRTIO_BLK_T_DISCARD: u32 = 11;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+const ID_BYTES: usize = 20;
+const HEADER_SIZE: usize = 16;
+const DISCARD_SEG_SIZE: usize = 16;
+const MAX_DISCARD_SEG: u32 = 32;
+/// Largest discard segment, 32 MiB, which the driver splits larger discards to
+const MAX_DISCARD_SECTORS: u32 = 1 << 16;
+
+pub struct Blk {
+    drive: Drive,
+    config: [u8; 48],
+}
+
+impl Blk {
+    pub fn new(drive: Drive) -> Self {
+        let capacity = drive.disk.size() / SECTOR_SIZE;
+        let mut config = [0; 48];
+
+        config[0..8].copy_from_slice(&capacity.to_le_bytes());
+        config[20..24].copy_from_slice(&(SECTOR_SIZE as u32).to_le_bytes());
+        // max_discard_sectors, max_discard_seg and discard_sector_alignment
+        config[36..40].copy_from_slice(&MAX_DISCARD_SECTORS.to_le_bytes());
+        config[40..44].copy_from_slice(&MAX_DISCARD_SEG.to_le_bytes());
+        config[44..48].copy_from_slice(&1u32.to_le_bytes());
+
+        Self { drive, config }
+    }
+
+    /// Checks that `len` bytes from `sector` are on the disk, returns the byte offset
+    fn range(&self, sector: u64, len: u64) -> Option<u64> {
+        let off = sector.checked_mul(SECTOR_SIZE)?;
+        (off.checked_add(len)? <= self.drive.disk.size() / SECTOR_SIZE * SECTOR_SIZE).then_some(off)
+    }
+
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> u8 {
+        match self.range(sector, buf.len() as u64) {
+            Some(off) if self.drive.disk.read_at(off, buf).is_ok() => VIRTIO_BLK_S_OK,
+            _ => VIRTIO_BLK_S_IOERR,
+        }
+    }
+
+    fn write(&mut self, sector: u64, buf: &[u8]) -> u8 {
+        if self.drive.read_only {
+            return VIRTIO_BLK_S_IOERR;
+        }
+
+        match self.range(sector, buf.len() as u64) {
+            Some(off) if self.drive.disk.write_at(off, buf).is_ok() => VIRTIO_BLK_S_OK,
+            _ => VIRTIO_BLK_S_IOERR,
+        }
+    }
+
+    fn discard(&mut self, segments: &[u8]) -> u8 {
+        if self.drive.read_only || segments.len() / DISCARD_SEG_SIZE > MAX_DISCARD_SEG as usize {
+            return VIRTIO_BLK_S_IOERR;
+        }
+
+        for seg in segments.chunks_exact(DISCARD_SEG_SIZE) {
+            let sector = u64::from_le_bytes(seg[0..8].try_into().unwrap());
+            let sectors = u32::from_le_bytes(seg[8..12].try_into().unwrap());
+            if sectors > MAX_DISCARD_SECTORS {
+                return VIRTIO_BLK_S_IOERR;
+            }
+
+            let len = sectors as u64 * SECTOR_SIZE;
+
+            match self.range(sector, len) {
+                Some(off) if self.drive.disk.discard(off, len).is_ok() => {},
+                _ => return VIRTIO_BLK_S_IOERR,
+            }
+        }
+
+        VIRTIO_BLK_S_OK
+    }
+
+    /// Serves one request, returns the number of bytes written to the chain
+    fn request(&mut self, chain: &Chain, bus: &Bus) -> u32 {
+        // the data is held in memory, so its length is bounded like that of any chain
+        let len = chain.writable_len();
+        if len == 0 || len > MAX_CHAIN_LEN {
+            return 0;
+        }
+
+        let Ok(out) = chain.read(bus) else { return 0 };
+        if out.len() < HEADER_SIZE {
+            return 0;
+        }
+
+        let ty = u32::from_le_bytes(out[0..4].try_into().unwrap());
+        let sector = u64::from_le_bytes(out[8..16].try_into().unwrap());
+        let body = &out[HEADER_SIZE..];
+
+        // the status byte comes after whatever data the device writes
+        let mut data = vec![0; len - 1];
+
+        let status = match ty {
+            VIRTIO_BLK_T_IN if (data.len() as u64).is_multiple_of(SECTOR_SIZE) => self.read(sector, &mut data),
+            VIRTIO_BLK_T_OUT if (body.len() as u64).is_multiple_of(SECTOR_SIZE) => self.write(sector, body),
+            VIRTIO_BLK_T_IN | VIRTIO_BLK_T_OUT => VIRTIO_BLK_S_IOERR,
+            VIRTIO_BLK_T_FLUSH => match self.drive.disk.flush() {
+                Ok(()) => VIRTIO_BLK_S_OK,
+                Err(_) => VIRTIO_BLK_S_IOERR,
+            },
+            VIRTIO_BLK_T_GET_ID => {
+                let id = self.drive.serial.as_bytes();
+                let n = id.len().min(ID_BYTES).min(data.len());
+                data[..n].copy_from_slice(&id[..n]);
+                VIRTIO_BLK_S_OK
+            },
+            VIRTIO_BLK_T_DISCARD => self.discard(body),
+            _ => VIRTIO_BLK_S_UNSUPP,
+        };
+
+        data.push(status);
+        chain.write(bus, &data).unwrap_or(0)
+    }
+}
+
+impl Device for Blk {
+    const DEVICE_ID: u32 = 2;
+    const QUEUES: usize = 1;
+    const QUEUE_MAX: u16 = 256;
+
+    fn features(&self) -> u64 {
+        let ro = if self.drive.read_only { VIRTIO_BLK_F_RO } else { 0 };
+        VIRTIO_BLK_F_BLK_SIZE | VIRTIO_BLK_F_FLUSH | VIRTIO_BLK_F_DISCARD | ro
+    }
+
+    fn read_config(&self, offset: u64) -> u8 {
+        self.config.get(offset as usize).copied().unwrap_or(0)
+    }
+
+    fn reset(&mut self) {
+        _ = self.drive.disk.flush();
+    }
+
+    fn notify(&mut self, queue: usize, queues: &mut [Queue], bus: &Bus) {
+        while let Some(chain) = queues[queue].pop(bus) {
+            let written = self.request(&chain, bus);
+            queues[queue].push(bus, &chain, written);
+        }
+    }
+}
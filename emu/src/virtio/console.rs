@@ -0,0 +1,236 @@
+//! virtio-console, with ports attached to `chardev::Chardev`s. Without the multiport feature only
+//! the first port is used.
+
+use super::*;
+use crate::chardev::Chardev;
+use std::collections::VecDeque;
+
+const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1 << 1;
+const VIRTIO_CONSOLE_F_EMERG_WRITE: u64 = 1 << 2;
+
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+
+/// Most ports a console can have
+pub const MAX_PORTS: usize = 8;
+
+const CONTROL_RX: usize = 2;
+const CONTROL_TX: usize = 3;
+const CONTROL_SIZE: usize = 8;
+/// Host input buffered for a port the guest has not read yet
+const MAX_PENDING: usize = 64 * 1024;
+
+pub struct Port {
+    /// Name the guest sees the port by, e.g. in /dev/virtio-ports/
+    pub name: String,
+    /// Whether the port is a console rather than a plain serial port
+    pub console: bool,
+    pub backend: Box<dyn Chardev>,
+}
+
+impl Port {
+    /// Parses a port spec: `NAME=CHARDEV`, where a port named `console` is a console without a
+    /// name
+    pub fn open(spec: &str) -> std::io::Result<Self> {
+        let (name, chardev) = spec.split_once('=').ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "expected NAME=CHARDEV")
+        })?;
+
+        let backend = crate::chardev::open(chardev)?;
+        Ok(match name {
+            "console" => Self { name: String::new(), console: true, backend },
+            _ => Self { name: name.to_string(), console: false, backend },
+        })
+    }
+}
+
+struct PortState {
+    port: Port,
+    /// Opened by the guest
+    open: bool,
+    input: VecDeque<u8>,
+}
+
+pub struct Console {
+    ports: Vec<PortState>,
+    multiport: bool,
+    /// Control messages for the driver
+    control: VecDeque<Vec<u8>>,
+}
+
+/// Receive queue of a port, its transmit queue follows
+fn rx_queue(port: usize) -> usize {
+    if port == 0 { 0 } else { 2 + 2 * port }
+}
+
+fn control(id: usize, event: u16, value: u16) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(CONTROL_SIZE);
+    msg.extend_from_slice(&(id as u32).to_le_bytes());
+    msg.extend_from_slice(&event.to_le_bytes());
+    msg.extend_from_slice(&value.to_le_bytes());
+    msg
+}
+
+impl Console {
+    pub fn new(ports: Vec<Port>) -> Self {
+        assert!(!ports.is_empty() && ports.len() <= MAX_PORTS);
+
+        Self {
+            ports: ports.into_iter().map(|port| PortState { port, open: false, input: VecDeque::new() }).collect(),
+            multiport: false,
+            control: VecDeque::new(),
+        }
+    }
+
+    fn handle_control(&mut self, msg: &[u8]) {
+        if msg.len() < CONTROL_SIZE {
+            return;
+        }
+
+        let id = u32::from_le_bytes(msg[0..4].try_into().unwrap()) as usize;
+        let event = u16::from_le_bytes(msg[4..6].try_into().unwrap());
+        let value = u16::from_le_bytes(msg[6..8].try_into().unwrap());
+
+        match event {
+            VIRTIO_CONSOLE_DEVICE_READY if value == 1 => {
+                for id in 0..self.ports.len() {
+                    self.control.push_back(control(id, VIRTIO_CONSOLE_DEVICE_ADD, 1));
+                }
+            },
+            VIRTIO_CONSOLE_PORT_READY if value == 1 && id < self.ports.len() => {
+                let port = &self.ports[id].port;
+                if port.console {
+                    self.control.push_back(control(id, VIRTIO_CONSOLE_CONSOLE_PORT, 1));
+                }
+
+                if !port.name.is_empty() {
+                    let mut msg = control(id, VIRTIO_CONSOLE_PORT_NAME, 1);
+                    msg.extend_from_slice(port.name.as_bytes());
+                    self.control.push_back(msg);
+                }
+
+                // the host end is always connected
+                self.control.push_back(control(id, VIRTIO_CONSOLE_PORT_OPEN, 1));
+            },
+            VIRTIO_CONSOLE_PORT_OPEN if id < self.ports.len() => self.ports[id].open = value == 1,
+            _ => {},
+        }
+    }
+
+    fn transmit(&mut self, port: usize, queues: &mut [Queue], bus: &Bus) {
+        let q = rx_queue(port) + 1;
+
+        while let Some(chain) = queues[q].pop(bus) {
+            for b in chain.read(bus).unwrap_or_default() {
+                self.ports[port].port.backend.write(b);
+            }
+
+            queues[q].push(bus, &chain, 0);
+        }
+    }
+
+    /// Moves host input and control messages into the buffers of the driver
+    fn receive(&mut self, queues: &mut [Queue], bus: &Bus) {
+        if self.multiport {
+            while !self.control.is_empty() {
+                let Some(chain) = queues[CONTROL_RX].pop(bus) else { break };
+                let msg = self.control.pop_front().unwrap();
+                let written = chain.write(bus, &msg).unwrap_or(0);
+                queues[CONTROL_RX].push(bus, &chain, written);
+            }
+        }
+
+        let ports = if self.multiport { self.ports.len() } else { 1 };
+        for (i, p) in self.ports.iter_mut().enumerate().take(ports) {
+            while p.input.len() < MAX_PENDING {
+                let Some(b) = p.port.backend.read() else { break };
+                p.input.push_back(b);
+            }
+
+            // the guest only gets input on ports it opened, the console of a legacy driver is
+            // always open
+            if self.multiport && !p.open {
+                continue;
+            }
+
+            let q = rx_queue(i);
+            while !p.input.is_empty() {
+                let Some(chain) = queues[q].pop(bus) else { break };
+                let n = chain.writable_len().min(p.input.len());
+                let data = p.input.drain(..n).collect::<Vec<_>>();
+                let written = chain.write(bus, &data).unwrap_or(0);
+                queues[q].push(bus, &chain, written);
+            }
+        }
+    }
+}
+
+impl Device for Console {
+    const DEVICE_ID: u32 = 3;
+    const QUEUES: usize = 2 + 2 * MAX_PORTS;
+    const QUEUE_MAX: u16 = 128;
+
+    fn features(&self) -> u64 {
+        VIRTIO_CONSOLE_F_MULTIPORT | VIRTIO_CONSOLE_F_EMERG_WRITE
+    }
+
+    fn read_config(&self, offset: u64) -> u8 {
+        // cols, rows and max_nr_ports, emerg_wr reads as 0
+        match offset {
+            4..8 => (self.ports.len() as u32).to_le_bytes()[offset as usize - 4],
+            _ => 0,
+        }
+    }
+
+    fn write_config(&mut self, offset: u64, val: u8) {
+        // emerg_wr, the low byte of it is the character
+        if offset == 8 {
+            self.ports[0].port.backend.write(val);
+        }
+    }
+
+    fn activate(&mut self, features: u64) {
+        self.multiport = features & VIRTIO_CONSOLE_F_MULTIPORT != 0;
+    }
+
+    fn reset(&mut self) {
+        self.multiport = false;
+        self.control.clear();
+
+        for p in self.ports.iter_mut() {
+            p.open = false;
+        }
+    }
+
+    fn notify(&mut self, queue: usize, queues: &mut [Queue], bus: &Bus) {
+        match queue {
+            CONTROL_TX if self.multiport => {
+                while let Some(chain) = queues[CONTROL_TX].pop(bus) {
+                    if let Ok(msg) = chain.read(bus) {
+                        self.handle_control(&msg);
+                    }
+
+                    queues[CONTROL_TX].push(bus, &chain, 0);
+                }
+            },
+            CONTROL_RX => {},
+            q if q % 2 == 1 => {
+                let port = if q == 1 { 0 } else { (q - 3) / 2 };
+                if port < self.ports.len() {
+                    self.transmit(port, queues, bus);
+                }
+            },
+            _ => {},
+        }
+
+        self.receive(queues, bus);
+    }
+
+    fn poll(&mut self, queues: &mut [Queue], bus: &Bus) {
+        self.receive(queues, bus);
+    }
+}
@@ -0,0 +1,180 @@
+//! virtio-input, a keyboard typing what comes in on a `chardev::Chardev`. Every byte becomes the
+//! key presses of a US layout that type it, with shift and ctrl as needed.
+
+use super::*;
+use crate::chardev::Chardev;
+use std::collections::VecDeque;
+
+const VIRTIO_INPUT_CFG_ID_NAME: u8 = 0x01;
+const VIRTIO_INPUT_CFG_ID_SERIAL: u8 = 0x02;
+const VIRTIO_INPUT_CFG_ID_DEVIDS: u8 = 0x03;
+const VIRTIO_INPUT_CFG_EV_BITS: u8 = 0x11;
+
+const EV_SYN: u16 = 0x00;
+const EV_KEY: u16 = 0x01;
+const SYN_REPORT: u16 = 0;
+const BUS_VIRTUAL: u16 = 0x06;
+
+const KEY_LEFTCTRL: u16 = 29;
+const KEY_LEFTSHIFT: u16 = 42;
+
+const EVENTQ: usize = 0;
+const STATUSQ: usize = 1;
+/// Size of `virtio_input_event`
+const EVENT_SIZE: usize = 8;
+/// Events waiting for the driver before more input is taken in
+const MAX_EVENTS: usize = 1024;
+
+/// Keys of the characters from 0x20 on, with whether shift is held
+const KEYMAP: [(u16, bool); 95] = [
+    (57, false), (2, true), (40, true), (4, true), (5, true), (6, true), (8, true), (40, false), // space ! " # $ % & '
+    (10, true), (11, true), (9, true), (13, true), (51, false), (12, false), (52, false), (53, false), // ( ) * + , - . /
+    (11, false), (2, false), (3, false), (4, false), (5, false), (6, false), (7, false), (8, false), // 0-7
+    (9, false), (10, false), (39, true), (39, false), (51, true), (13, false), (52, true), (53, true), // 8 9 : ; < = > ?
+    (3, true), (30, true), (48, true), (46, true), (32, true), (18, true), (33, true), (34, true), // @ A-G
+    (35, true), (23, true), (36, true), (37, true), (38, true), (50, true), (49, true), (24, true), // H-O
+    (25, true), (16, true), (19, true), (31, true), (20, true), (22, true), (47, true), (17, true), // P-W
+    (45, true), (21, true), (44, true), (26, false), (43, false), (27, false), (7, true), (12, true), // X Y Z [ \ ] ^ _
+    (41, false), (30, false), (48, false), (46, false), (32, false), (18, false), (33, false), (34, false), // ` a-g
+    (35, false), (23, false), (36, false), (37, false), (38, false), (50, false), (49, false), (24, false), // h-o
+    (25, false), (16, false), (19, false), (31, false), (20, false), (22, false), (47, false), (17, false), // p-w
+    (45, false), (21, false), (44, false), (26, true), (43, true), (27, true), (41, true), // x y z { | } ~
+];
+
+/// The key and modifier typing `c`
+fn key(c: u8) -> Option<(u16, Option<u16>)> {
+    Some(match c {
+        0x1b => (1, None),
+        0x08 | 0x7f => (14, None),
+        b'\t' => (15, None),
+        b'\r' | b'\n' => (28, None),
+        0x20..0x7f => {
+            let (key, shift) = KEYMAP[c as usize - 0x20];
+            (key, shift.then_some(KEY_LEFTSHIFT))
+        },
+        // ctrl-a to ctrl-z, the ones that are not keys of their own
+        1..=26 => (KEYMAP[(c + 0x60 - 0x20) as usize].0, Some(KEY_LEFTCTRL)),
+        _ => return None,
+    })
+}
+
+pub struct Keyboard {
+    backend: Box<dyn Chardev>,
+    select: u8,
+    subsel: u8,
+    events: VecDeque<[u8; EVENT_SIZE]>,
+}
+
+fn event(ty: u16, code: u16, value: u32) -> [u8; EVENT_SIZE] {
+    let mut e = [0; EVENT_SIZE];
+    e[0..2].copy_from_slice(&ty.to_le_bytes());
+    e[2..4].copy_from_slice(&code.to_le_bytes());
+    e[4..8].copy_from_slice(&value.to_le_bytes());
+    e
+}
+
+impl Keyboard {
+    pub fn new(backend: Box<dyn Chardev>) -> Self {
+        Self { backend, select: 0, subsel: 0, events: VecDeque::new() }
+    }
+
+    fn press(&mut self, code: u16, value: u32) {
+        self.events.push_back(event(EV_KEY, code, value));
+        self.events.push_back(event(EV_SYN, SYN_REPORT, 0));
+    }
+
+    fn type_byte(&mut self, c: u8) {
+        let Some((code, modifier)) = key(c) else { return };
+
+        if let Some(m) = modifier {
+            self.press(m, 1);
+        }
+        self.press(code, 1);
+        self.press(code, 0);
+        if let Some(m) = modifier {
+            self.press(m, 0);
+        }
+    }
+
+    /// The config data for the current selection
+    fn config(&self) -> Vec<u8> {
+        match (self.select, self.subsel) {
+            (VIRTIO_INPUT_CFG_ID_NAME, 0) => b"rv64 keyboard".to_vec(),
+            (VIRTIO_INPUT_CFG_ID_SERIAL, 0) => b"0".to_vec(),
+            (VIRTIO_INPUT_CFG_ID_DEVIDS, 0) => [BUS_VIRTUAL, 0x0627, 0x0001, 0x0001].iter().flat_map(|v| v.to_le_bytes()).collect(),
+            (VIRTIO_INPUT_CFG_EV_BITS, sub) if sub as u16 == EV_KEY => {
+                let mut bits = vec![0u8; 16];
+                let codes = KEYMAP.iter().map(|k| k.0).chain([1, 14, 15, 28, KEY_LEFTCTRL, KEY_LEFTSHIFT]);
+                for code in codes {
+                    bits[code as usize / 8] |= 1 << (code % 8);
+                }
+                bits
+            },
+            _ => Vec::new(),
+        }
+    }
+
+    fn receive(&mut self, queues: &mut [Queue], bus: &Bus) {
+        while self.events.len() < MAX_EVENTS {
+            let Some(c) = self.backend.read() else { break };
+            self.type_byte(c);
+        }
+
+        while !self.events.is_empty() {
+            let Some(chain) = queues[EVENTQ].pop(bus) else { break };
+            let e = self.events.pop_front().unwrap();
+            let written = chain.write(bus, &e).unwrap_or(0);
+            queues[EVENTQ].push(bus, &chain, written);
+        }
+    }
+}
+
+impl Device for Keyboard {
+    const DEVICE_ID: u32 = 18;
+    const QUEUES: usize = 2;
+    const QUEUE_MAX: u16 = 64;
+
+    fn features(&self) -> u64 {
+        0
+    }
+
+    fn read_config(&self, offset: u64) -> u8 {
+        // select, subsel, size, 5 reserved bytes, then the data
+        match offset {
+            0 => self.select,
+            1 => self.subsel,
+            2 => self.config().len() as u8,
+            8..136 => *self.config().get(offset as usize - 8).unwrap_or(&0),
+            _ => 0,
+        }
+    }
+
+    fn write_config(&mut self, offset: u64, val: u8) {
+        match offset {
+            0 => self.select = val,
+            1 => self.subsel = val,
+            _ => {},
+        }
+    }
+
+    fn reset(&mut self) {
+        self.events.clear();
+        self.select = 0;
+        self.subsel = 0;
+    }
+
+    fn notify(&mut self, queue: usize, queues: &mut [Queue], bus: &Bus) {
+        // led changes from the driver, a keyboard without leds ignores them
+        if queue == STATUSQ {
+            while let Some(chain) = queues[STATUSQ].pop(bus) {
+                queues[STATUSQ].push(bus, &chain, 0);
+            }
+        }
+
+        self.receive(queues, bus);
+    }
+
+    fn poll(&mut self, queues: &mut [Queue], bus: &Bus) {
+        self.receive(queues, bus);
+    }
+}
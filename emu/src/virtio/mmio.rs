@@ -0,0 +1,291 @@
+//! virtio-mmio transport, version 2 with the legacy version 1 layout as a fallback
+
+use super::*;
+use crate::cpu::Exception;
+
+const MAGIC: u32 = 0x7472_6976;
+const VENDOR_ID: u32 = 0x554d_4551;
+
+const MAGIC_VALUE: u64 = 0x000;
+const VERSION: u64 = 0x004;
+const DEVICE_ID: u64 = 0x008;
+const VENDOR: u64 = 0x00c;
+const DEVICE_FEATURES: u64 = 0x010;
+const DEVICE_FEATURES_SEL: u64 = 0x014;
+const DRIVER_FEATURES: u64 = 0x020;
+const DRIVER_FEATURES_SEL: u64 = 0x024;
+const GUEST_PAGE_SIZE: u64 = 0x028;
+const QUEUE_SEL: u64 = 0x030;
+const QUEUE_NUM_MAX: u64 = 0x034;
+const QUEUE_NUM: u64 = 0x038;
+const QUEUE_ALIGN: u64 = 0x03c;
+const QUEUE_PFN: u64 = 0x040;
+const QUEUE_READY: u64 = 0x044;
+const QUEUE_NOTIFY: u64 = 0x050;
+const INTERRUPT_STATUS: u64 = 0x060;
+const INTERRUPT_ACK: u64 = 0x064;
+const STATUS: u64 = 0x070;
+const QUEUE_DESC_LOW: u64 = 0x080;
+const QUEUE_DESC_HIGH: u64 = 0x084;
+const QUEUE_DRIVER_LOW: u64 = 0x090;
+const QUEUE_DRIVER_HIGH: u64 = 0x094;
+const QUEUE_DEVICE_LOW: u64 = 0x0a0;
+const QUEUE_DEVICE_HIGH: u64 = 0x0a4;
+const CONFIG: u64 = 0x100;
+
+const STATUS_DRIVER_OK: u32 = 4;
+const STATUS_FEATURES_OK: u32 = 8;
+
+const INT_USED_BUFFER: u32 = 1;
+
+pub struct Mmio<D: Device> {
+    base: u64,
+    irq: usize,
+    /// Expose the version 1 registers, for drivers that predate virtio 1.0
+    legacy: bool,
+    device: D,
+    queues: Vec<Queue>,
+
+    queue_sel: u32,
+    device_features_sel: u32,
+    driver_features_sel: u32,
+    driver_features: u64,
+    status: u32,
+    interrupt_status: u32,
+    /// Legacy page size and used ring alignment of each queue
+    page_size: u32,
+    align: Vec<u32>,
+
+    /// Queues notified by the driver and not processed yet
+    notified: u64,
+    polled: bool,
+}
+
+fn set_low(v: &mut u64, val: u32) {
+    *v = (*v & !0xffff_ffff) | val as u64;
+}
+
+fn set_high(v: &mut u64, val: u32) {
+    *v = (*v & 0xffff_ffff) | (val as u64) << 32;
+}
+
+impl<D: Device> Mmio<D> {
+    pub fn new(base: u64, irq: usize, device: D, legacy: bool) -> Self {
+        Self {
+            base,
+            irq,
+            legacy,
+            device,
+            queues: vec![Queue::default(); D::QUEUES],
+
+            queue_sel: 0,
+            device_features_sel: 0,
+            driver_features_sel: 0,
+            driver_features: 0,
+            status: 0,
+            interrupt_status: 0,
+            page_size: 4096,
+            align: vec![4096; D::QUEUES],
+
+            notified: 0,
+            polled: false,
+        }
+    }
+
+    fn device_features(&self) -> u64 {
+        let version = if self.legacy { 0 } else { VIRTIO_F_VERSION_1 };
+        self.device.features() | VIRTIO_F_RING_INDIRECT_DESC | version
+    }
+
+    fn queue(&mut self) -> Option<&mut Queue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    fn reset(&mut self) {
+        self.device.reset();
+        self.queues.iter_mut().for_each(Queue::reset);
+        self.align.fill(4096);
+
+        self.queue_sel = 0;
+        self.device_features_sel = 0;
+        self.driver_features_sel = 0;
+        self.driver_features = 0;
+        self.status = 0;
+        self.interrupt_status = 0;
+        self.notified = 0;
+    }
+
+    fn set_status(&mut self, val: u32) {
+        if val == 0 {
+            self.reset();
+            return;
+        }
+
+        // the features are final once the driver sets FEATURES_OK, if they make sense
+        let mut val = val;
+        if val & STATUS_FEATURES_OK != 0 && self.status & STATUS_FEATURES_OK == 0 {
+            let ok = self.driver_features & !self.device_features() == 0
+                && (self.legacy || self.driver_features & VIRTIO_F_VERSION_1 != 0);
+
+            if !ok {
+                val &= !STATUS_FEATURES_OK;
+            }
+        }
+
+        if val & STATUS_DRIVER_OK != 0 && self.status & STATUS_DRIVER_OK == 0 {
+            self.device.activate(self.driver_features);
+        }
+
+        self.status = val;
+    }
+
+    fn load_reg(&mut self, off: u64) -> u32 {
+        let sel = self.queue_sel as usize;
+
+        match off {
+            MAGIC_VALUE => MAGIC,
+            VERSION => if self.legacy { 1 } else { 2 },
+            DEVICE_ID => D::DEVICE_ID,
+            VENDOR => VENDOR_ID,
+            DEVICE_FEATURES => match self.device_features_sel {
+                0 => self.device_features() as u32,
+                1 => (self.device_features() >> 32) as u32,
+                _ => 0,
+            },
+            QUEUE_NUM_MAX if sel < D::QUEUES => D::QUEUE_MAX as u32,
+            QUEUE_PFN if self.legacy => (self.queue().map_or(0, |q| q.desc) / self.page_size.max(1) as u64) as u32,
+            QUEUE_READY if !self.legacy => self.queue().is_some_and(|q| q.ready) as u32,
+            INTERRUPT_STATUS => self.interrupt_status,
+            STATUS => self.status,
+            _ => 0,
+        }
+    }
+
+    fn store_reg(&mut self, off: u64, val: u32) {
+        let legacy = self.legacy;
+
+        match off {
+            DEVICE_FEATURES_SEL => self.device_features_sel = val,
+            DRIVER_FEATURES => match self.driver_features_sel {
+                0 => set_low(&mut self.driver_features, val),
+                1 => set_high(&mut self.driver_features, val),
+                _ => {},
+            },
+            DRIVER_FEATURES_SEL => self.driver_features_sel = val,
+            GUEST_PAGE_SIZE if legacy => self.page_size = val,
+            QUEUE_SEL => self.queue_sel = val,
+            QUEUE_NUM => if let Some(q) = self.queue() {
+                q.size = (val as u16).min(D::QUEUE_MAX);
+            },
+            QUEUE_ALIGN if legacy => if let Some(a) = self.align.get_mut(self.queue_sel as usize) {
+                *a = val;
+            },
+            QUEUE_PFN if legacy => {
+                let addr = val as u64 * self.page_size as u64;
+                let align = self.align.get(self.queue_sel as usize).copied().unwrap_or(0) as u64;
+                if let Some(q) = self.queue() {
+                    q.set_legacy(addr, align);
+                }
+            },
+            QUEUE_READY if !legacy => if let Some(q) = self.queue() {
+                q.ready = val & 1 != 0;
+            },
+            QUEUE_NOTIFY if (val as usize) < D::QUEUES => self.notified |= 1 << val,
+            INTERRUPT_ACK => self.interrupt_status &= !val,
+            STATUS => self.set_status(val),
+            QUEUE_DESC_LOW if !legacy => if let Some(q) = self.queue() { set_low(&mut q.desc, val) },
+            QUEUE_DESC_HIGH if !legacy => if let Some(q) = self.queue() { set_high(&mut q.desc, val) },
+            QUEUE_DRIVER_LOW if !legacy => if let Some(q) = self.queue() { set_low(&mut q.driver, val) },
+            QUEUE_DRIVER_HIGH if !legacy => if let Some(q) = self.queue() { set_high(&mut q.driver, val) },
+            QUEUE_DEVICE_LOW if !legacy => if let Some(q) = self.queue() { set_low(&mut q.device, val) },
+            QUEUE_DEVICE_HIGH if !legacy => if let Some(q) = self.queue() { set_high(&mut q.device, val) },
+            _ => {},
+        }
+    }
+
+    fn load_config(&self, off: u64, size: u64) -> u64 {
+        (0..size).fold(0, |v, i| v | (self.device.read_config(off - CONFIG + i) as u64) << (i * 8))
+    }
+
+    fn store_config(&mut self, off: u64, size: u64, val: u64) {
+        for i in 0..size {
+            self.device.write_config(off - CONFIG + i, (val >> (i * 8)) as u8);
+        }
+    }
+}
+
+macro_rules! gen {
+    ($l: tt $s: tt $t: tt $sz: tt) => {
+        fn $l(&mut self, addr: u64) -> Result<$t, Exception> {
+            let off = addr - self.base;
+
+            if off >= CONFIG {
+                Ok(self.load_config(off, $sz) as $t)
+            } else if $sz == 4 {
+                Ok(self.load_reg(off) as $t)
+            } else {
+                Err(Exception::LoadAccessFault)
+            }
+        }
+
+        fn $s(&mut self, addr: u64, val: $t) -> Result<(), Exception> {
+            let off = addr - self.base;
+
+            if off >= CONFIG {
+                self.store_config(off, $sz, val as u64);
+            } else if $sz == 4 {
+                self.store_reg(off, val as u32);
+            } else {
+                return Err(Exception::StoreAccessFault);
+            }
+
+            Ok(())
+        }
+    };
+}
+
+impl<D: Device> crate::bus::Device for Mmio<D> {
+    gen!(load_u8 store_u8 u8 1);
+    gen!(load_u16 store_u16 u16 2);
+    gen!(load_u32 store_u32 u32 4);
+    gen!(load_u64 store_u64 u64 8);
+
+    fn poll(&mut self) {
+        self.polled = true;
+    }
+
+    fn run(&mut self, bus: &Bus) {
+        if self.status & STATUS_DRIVER_OK == 0 {
+            return;
+        }
+
+        while self.notified != 0 {
+            let q = self.notified.trailing_zeros() as usize;
+            self.notified &= !(1 << q);
+            self.device.notify(q, &mut self.queues, bus);
+        }
+
+        if self.polled {
+            self.polled = false;
+            self.device.poll(&mut self.queues, bus);
+        }
+
+        for q in self.queues.iter_mut() {
+            if q.interrupt {
+                q.interrupt = false;
+                self.interrupt_status |= INT_USED_BUFFER;
+            }
+        }
+    }
+
+    fn irq(&self) -> Option<(usize, bool)> {
+        Some((self.irq, self.interrupt_status != 0))
+    }
+
+    /// Like a reset by the driver, which also forgets the legacy page size
+    fn reset(&mut self) {
+        Mmio::reset(self);
+        self.page_size = 4096;
+        self.polled = false;
+    }
+}
@@ -1,21 +1,52 @@
 //! VirtIO devices
 
-pub trait Device {
-    const MAGIC: u32;
+mod blk;
+mod console;
+mod input;
+mod mmio;
+mod net;
+mod p9;
+mod queue;
+mod rng;
+mod vsock;
+
+pub use blk::Blk;
+pub use console::{Console, Port, MAX_PORTS};
+pub use input::Keyboard;
+pub use mmio::Mmio;
+pub use net::Net;
+pub use p9::P9;
+pub use queue::{Buffer, Chain, Queue};
+pub(crate) use queue::MAX_CHAIN_LEN;
+pub use rng::Rng;
+pub use vsock::Vsock;
+
+use crate::bus::Bus;
+
+pub const VIRTIO_F_RING_INDIRECT_DESC: u64 = 1 << 28;
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// A device model, which the transport exposes to the guest
+pub trait Device: Send {
+    /// Device type, e.g. 2 for a block device
     const DEVICE_ID: u32;
-    const VENDOR_ID: u32;
-    const DEVICE_FEATURES: u32;
-    const QUEUE_MAX: u32;
-
-    fn get_driver_features(&self) -> u32;
-    fn get_queue_pfn(&self) -> u32;
-    fn get_status(&self) -> u32;
-
-    fn set_driver_features(&self) -> u32;
-    fn set_page_size(&self) -> u32;
-    fn set_queue_sel(&self) -> u32;
-    fn set_queue_num(&self) -> u32;
-    fn set_queue_pfn(&self) -> u32;
-    fn set_queue_notify(&self) -> u32;
-    fn set_status(&self) -> u32;
+    /// Number of virtqueues
+    const QUEUES: usize;
+    /// Largest number of entries in a virtqueue
+    const QUEUE_MAX: u16;
+
+    /// Device specific feature bits
+    fn features(&self) -> u64;
+
+    fn read_config(&self, offset: u64) -> u8;
+    fn write_config(&mut self, _offset: u64, _val: u8) {}
+
+    /// Called once the driver is ready, with the features it accepted
+    fn activate(&mut self, _features: u64) {}
+    fn reset(&mut self);
+
+    /// Processes the buffers the driver made available on `queue`
+    fn notify(&mut self, queue: usize, queues: &mut [Queue], bus: &Bus);
+    /// Called regularly, to take in input from the host
+    fn poll(&mut self, _queues: &mut [Queue], _bus: &Bus) {}
 }
@@ -0,0 +1,109 @@
+//! virtio-net, an Ethernet card on top of a `net::NetBackend`
+
+use super::*;
+use crate::net::{enqueue, NetBackend};
+use std::collections::VecDeque;
+
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+
+const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+const RX: usize = 0;
+const TX: usize = 1;
+
+/// Size of `virtio_net_hdr`, which has `num_buffers` with virtio 1.0 but not in legacy mode
+const HDR_SIZE: usize = 12;
+const LEGACY_HDR_SIZE: usize = 10;
+
+pub struct Net {
+    backend: Box<dyn NetBackend>,
+    mac: [u8; 6],
+    hdr_size: usize,
+    /// Frames from the backend waiting for receive buffers
+    rx: VecDeque<Vec<u8>>,
+}
+
+impl Net {
+    pub fn new(backend: Box<dyn NetBackend>, mac: [u8; 6]) -> Self {
+        Self {
+            backend,
+            mac,
+            hdr_size: HDR_SIZE,
+            rx: VecDeque::new(),
+        }
+    }
+
+    fn transmit(&mut self, queues: &mut [Queue], bus: &Bus) {
+        while let Some(chain) = queues[TX].pop(bus) {
+            if let Ok(data) = chain.read(bus) {
+                if data.len() > self.hdr_size {
+                    self.backend.send(&data[self.hdr_size..]);
+                }
+            }
+
+            queues[TX].push(bus, &chain, 0);
+        }
+    }
+
+    /// Hands the frames from the backend to the guest, as long as it has buffers for them
+    fn receive(&mut self, queues: &mut [Queue], bus: &Bus) {
+        while let Some(frame) = self.backend.recv() {
+            enqueue(&mut self.rx, frame);
+        }
+
+        while !self.rx.is_empty() {
+            let Some(chain) = queues[RX].pop(bus) else { break };
+            let frame = self.rx.pop_front().unwrap();
+
+            // no offloads, and every frame fits in one buffer
+            let mut data = vec![0; self.hdr_size];
+            if self.hdr_size == HDR_SIZE {
+                data[10..12].copy_from_slice(&1u16.to_le_bytes());
+            }
+            data.extend_from_slice(&frame);
+
+            let written = chain.write(bus, &data).unwrap_or(0);
+            queues[RX].push(bus, &chain, written);
+        }
+    }
+}
+
+impl Device for Net {
+    const DEVICE_ID: u32 = 1;
+    const QUEUES: usize = 2;
+    const QUEUE_MAX: u16 = 256;
+
+    fn features(&self) -> u64 {
+        VIRTIO_NET_F_MAC | VIRTIO_NET_F_STATUS
+    }
+
+    fn read_config(&self, offset: u64) -> u8 {
+        match offset {
+            0..6 => self.mac[offset as usize],
+            6..8 => VIRTIO_NET_S_LINK_UP.to_le_bytes()[offset as usize - 6],
+            _ => 0,
+        }
+    }
+
+    fn activate(&mut self, features: u64) {
+        self.hdr_size = if features & VIRTIO_F_VERSION_1 != 0 { HDR_SIZE } else { LEGACY_HDR_SIZE };
+    }
+
+    fn reset(&mut self) {
+        self.rx.clear();
+    }
+
+    fn notify(&mut self, queue: usize, queues: &mut [Queue], bus: &Bus) {
+        if queue == TX {
+            self.transmit(queues, bus);
+        }
+
+        // replies of the backend can be ready right away, and new buffers let waiting frames in
+        self.receive(queues, bus);
+    }
+
+    fn poll(&mut self, queues: &mut [Queue], bus: &Bus) {
+        self.receive(queues, bus);
+    }
+}
@@ -0,0 +1,71 @@
+//! virtio-9p, carrying the messages of a `p9::Server` with the mount tag the guest mounts it by
+
+use super::*;
+use crate::p9::Server;
+
+const VIRTIO_9P_MOUNT_TAG: u64 = 1 << 0;
+
+pub struct P9 {
+    server: Server,
+    tag: String,
+}
+
+impl P9 {
+    pub fn new(server: Server, tag: &str) -> Self {
+        Self { server, tag: tag.to_string() }
+    }
+
+    /// Parses a share spec: `path=DIR[,tag=TAG][,readonly=on]`, the tag defaults to `share`
+    pub fn open(spec: &str) -> std::io::Result<Self> {
+        let invalid = |msg: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, msg);
+        let mut path = None;
+        let mut tag = "share";
+        let mut read_only = false;
+
+        for opt in spec.split(',') {
+            match opt.split_once('=') {
+                Some(("path", p)) => path = Some(p),
+                Some(("tag", t)) if !t.is_empty() && t.len() <= u16::MAX as usize => tag = t,
+                Some(("readonly", "on")) => read_only = true,
+                Some(("readonly", "off")) => read_only = false,
+                _ => return Err(invalid(format!("unknown share option `{opt}`"))),
+            }
+        }
+
+        let server = Server::new(path.ok_or_else(|| invalid("missing path=".into()))?, read_only)?;
+        Ok(Self::new(server, tag))
+    }
+}
+
+impl Device for P9 {
+    const DEVICE_ID: u32 = 9;
+    const QUEUES: usize = 1;
+    const QUEUE_MAX: u16 = 128;
+
+    fn features(&self) -> u64 {
+        VIRTIO_9P_MOUNT_TAG
+    }
+
+    fn read_config(&self, offset: u64) -> u8 {
+        // tag_len, then the tag without a terminating nul
+        let tag = self.tag.as_bytes();
+        match offset as usize {
+            0..2 => (tag.len() as u16).to_le_bytes()[offset as usize],
+            n => *tag.get(n - 2).unwrap_or(&0),
+        }
+    }
+
+    fn reset(&mut self) {}
+
+    fn notify(&mut self, _queue: usize, queues: &mut [Queue], bus: &Bus) {
+        while let Some(chain) = queues[0].pop(bus) {
+            let reply = match chain.read(bus) {
+                Ok(msg) => self.server.handle(&msg),
+                Err(_) => Vec::new(),
+            };
+
+            let written = chain.write(bus, &reply).unwrap_or(0);
+            queues[0].push(bus, &chain, written);
+        }
+    }
+}
@@ -0,0 +1,260 @@
+//! Split virtqueues: a descriptor table, an available ring written by the driver and a used ring
+//! written by the device, all in guest memory
+
+use crate::bus::Bus;
+use crate::cpu::Exception;
+use core::sync::atomic::{fence, Ordering};
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+const VIRTQ_DESC_F_INDIRECT: u16 = 4;
+const VIRTQ_AVAIL_F_NO_INTERRUPT: u16 = 1;
+
+const DESC_SIZE: u64 = 16;
+
+/// Most bytes a chain can have in either direction, far more than any driver asks for at once. The
+/// lengths come from the guest, and devices allocate buffers of that size.
+pub(crate) const MAX_CHAIN_LEN: usize = 16 << 20;
+
+#[derive(Debug, Default, Clone)]
+pub struct Queue {
+    /// Number of entries, set by the driver
+    pub(crate) size: u16,
+    pub(crate) ready: bool,
+    /// Guest addresses of the descriptor table, the available ring and the used ring
+    pub(crate) desc: u64,
+    pub(crate) driver: u64,
+    pub(crate) device: u64,
+
+    /// Next entry of the available ring to take
+    last_avail: u16,
+    used_idx: u16,
+    /// Set when buffers were used and the driver did not suppress the interrupt
+    pub(crate) interrupt: bool,
+}
+
+/// One buffer of a descriptor chain
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    pub addr: u64,
+    pub len: u32,
+    /// Written by the device rather than read
+    pub write: bool,
+}
+
+/// A descriptor chain taken from the available ring, to be given back with `Queue::push`
+#[derive(Debug)]
+pub struct Chain {
+    head: u16,
+    pub buffers: Vec<Buffer>,
+}
+
+fn read_u16(bus: &Bus, addr: u64) -> Result<u16, Exception> {
+    let mut b = [0; 2];
+    bus.dma_read(addr, &mut b)?;
+    Ok(u16::from_le_bytes(b))
+}
+
+impl Queue {
+    /// Lays out the queue the legacy way: the rings follow the descriptors in the pages starting
+    /// at `addr`, with the used ring aligned to `align`
+    pub(crate) fn set_legacy(&mut self, addr: u64, align: u64) {
+        let size = self.size as u64;
+        self.desc = addr;
+        self.driver = addr + DESC_SIZE * size;
+        self.device = (self.driver + 6 + 2 * size).next_multiple_of(align.max(1));
+        self.ready = addr != 0;
+    }
+
+    /// Takes the next chain the driver made available
+    pub fn pop(&mut self, bus: &Bus) -> Option<Chain> {
+        if !self.ready || self.size == 0 {
+            return None;
+        }
+
+        let idx = read_u16(bus, self.driver + 2).ok()?;
+        if idx == self.last_avail {
+            return None;
+        }
+
+        // the ring entry is written before idx
+        fence(Ordering::SeqCst);
+
+        let slot = (self.last_avail % self.size) as u64;
+        let head = read_u16(bus, self.driver + 4 + 2 * slot).ok()?;
+        self.last_avail = self.last_avail.wrapping_add(1);
+
+        match self.chain(bus, head) {
+            Ok(buffers) => Some(Chain { head, buffers }),
+            Err(_) => {
+                // a broken chain is given back empty, so the driver is not stuck on it
+                self.push(bus, &Chain { head, buffers: Vec::new() }, 0);
+                None
+            },
+        }
+    }
+
+    /// Follows a chain through the descriptor table, and through an indirect table if there is one.
+    /// Chains with more descriptors than the queue size or more than `MAX_CHAIN_LEN` bytes either
+    /// way are broken.
+    fn chain(&self, bus: &Bus, head: u16) -> Result<Vec<Buffer>, Exception> {
+        let mut buffers = Vec::new();
+        let (mut readable, mut writable) = (0, 0);
+        let (mut table, mut size) = (self.desc, self.size as u64);
+        let mut i = head as u64;
+        let mut left = size;
+
+        loop {
+            if i >= size || left == 0 {
+                return Err(Exception::LoadAccessFault);
+            }
+            left -= 1;
+
+            let mut d = [0; 16];
+            bus.dma_read(table + DESC_SIZE * i, &mut d)?;
+            let addr = u64::from_le_bytes(d[0..8].try_into().unwrap());
+            let len = u32::from_le_bytes(d[8..12].try_into().unwrap());
+            let flags = u16::from_le_bytes(d[12..14].try_into().unwrap());
+            let next = u16::from_le_bytes(d[14..16].try_into().unwrap());
+
+            if flags & VIRTQ_DESC_F_INDIRECT != 0 {
+                // the whole chain continues in the indirect table, which cannot nest
+                if table != self.desc {
+                    return Err(Exception::LoadAccessFault);
+                }
+
+                (table, size, i) = (addr, len as u64 / DESC_SIZE, 0);
+                left = size.min(self.size as u64);
+                continue;
+            }
+
+            let write = flags & VIRTQ_DESC_F_WRITE != 0;
+            let total = if write { &mut writable } else { &mut readable };
+            *total += len as usize;
+            if *total > MAX_CHAIN_LEN {
+                return Err(Exception::LoadAccessFault);
+            }
+
+            buffers.push(Buffer { addr, len, write });
+
+            if flags & VIRTQ_DESC_F_NEXT == 0 {
+                return Ok(buffers);
+            }
+
+            i = next as u64;
+        }
+    }
+
+    /// Gives a chain back to the driver, with the number of bytes written to it
+    pub fn push(&mut self, bus: &Bus, chain: &Chain, written: u32) {
+        let slot = (self.used_idx % self.size) as u64;
+        let mut elem = [0; 8];
+        elem[0..4].copy_from_slice(&(chain.head as u32).to_le_bytes());
+        elem[4..8].copy_from_slice(&written.to_le_bytes());
+
+        if bus.dma_write(self.device + 4 + 8 * slot, &elem).is_err() {
+            return;
+        }
+
+        // the driver has to see the element before idx
+        fence(Ordering::SeqCst);
+        self.used_idx = self.used_idx.wrapping_add(1);
+        _ = bus.dma_write(self.device + 2, &self.used_idx.to_le_bytes());
+
+        let flags = read_u16(bus, self.driver).unwrap_or(0);
+        self.interrupt |= flags & VIRTQ_AVAIL_F_NO_INTERRUPT == 0;
+    }
+
+    pub(crate) fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+impl Chain {
+    /// Total length of the buffers the device reads
+    pub fn readable_len(&self) -> usize {
+        self.buffers.iter().filter(|b| !b.write).map(|b| b.len as usize).sum()
+    }
+
+    /// Total length of the buffers the device writes
+    pub fn writable_len(&self) -> usize {
+        self.buffers.iter().filter(|b| b.write).map(|b| b.len as usize).sum()
+    }
+
+    /// Reads the device readable buffers, one after the other
+    pub(crate) fn read(&self, bus: &Bus) -> Result<Vec<u8>, Exception> {
+        let len = self.readable_len();
+        if len > MAX_CHAIN_LEN {
+            return Err(Exception::LoadAccessFault);
+        }
+
+        let mut data = vec![0; len];
+        let mut off = 0;
+
+        for b in self.buffers.iter().filter(|b| !b.write) {
+            bus.dma_read(b.addr, &mut data[off..off + b.len as usize])?;
+            off += b.len as usize;
+        }
+
+        Ok(data)
+    }
+
+    /// Fills the device writable buffers with `data`, one after the other. Returns the number of
+    /// bytes written, which is less than `data` if the buffers are too small.
+    pub(crate) fn write(&self, bus: &Bus, data: &[u8]) -> Result<u32, Exception> {
+        let mut off = 0;
+
+        for b in self.buffers.iter().filter(|b| b.write) {
+            if off == data.len() {
+                break;
+            }
+
+            let n = (b.len as usize).min(data.len() - off);
+            bus.dma_write(b.addr, &data[off..off + n])?;
+            off += n;
+        }
+
+        Ok(off as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::RAM_BASE;
+    use crate::clint::Timebase;
+    use crate::ram::Ram;
+
+    const DESC: u64 = RAM_BASE;
+    const DRIVER: u64 = RAM_BASE + 0x1000;
+    const DEVICE: u64 = RAM_BASE + 0x2000;
+
+    fn queue(bus: &Bus, descriptors: &[(u64, u32, u16, u16)]) -> Queue {
+        for (i, &(addr, len, flags, next)) in descriptors.iter().enumerate() {
+            let mut d = [0; 16];
+            d[0..8].copy_from_slice(&addr.to_le_bytes());
+            d[8..12].copy_from_slice(&len.to_le_bytes());
+            d[12..14].copy_from_slice(&flags.to_le_bytes());
+            d[14..16].copy_from_slice(&next.to_le_bytes());
+            bus.dma_write(DESC + DESC_SIZE * i as u64, &d).unwrap();
+        }
+
+        // one chain available, starting at descriptor 0
+        bus.dma_write(DRIVER + 2, &1u16.to_le_bytes()).unwrap();
+        Queue { size: 8, ready: true, desc: DESC, driver: DRIVER, device: DEVICE, ..Default::default() }
+    }
+
+    #[test]
+    fn oversized_chains_are_given_back() {
+        let bus = Bus::new(Ram::new(&[]), 1, Timebase::Instructions(1));
+
+        let mut q = queue(&bus, &[(RAM_BASE, u32::MAX, VIRTQ_DESC_F_NEXT, 1), (RAM_BASE, u32::MAX, 0, 0)]);
+        assert!(q.pop(&bus).is_none());
+        assert_eq!(read_u16(&bus, DEVICE + 2), Ok(1));
+
+        let half = (MAX_CHAIN_LEN / 2) as u32;
+        let mut q = queue(&bus, &[(RAM_BASE, half, VIRTQ_DESC_F_NEXT, 1), (RAM_BASE, half, VIRTQ_DESC_F_WRITE, 0)]);
+        let chain = q.pop(&bus).unwrap();
+        assert_eq!((chain.readable_len(), chain.writable_len()), (half as usize, half as usize));
+    }
+}
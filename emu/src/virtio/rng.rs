@@ -0,0 +1,75 @@
+//! virtio-rng, an entropy source that is either the host's or a seeded generator for runs that
+//! have to be reproducible
+
+use super::*;
+use std::io::Read;
+
+enum Source {
+    Host(std::fs::File),
+    Seeded(crate::machine::Rng),
+}
+
+pub struct Rng {
+    source: Source,
+}
+
+impl Rng {
+    /// Entropy from the host's /dev/urandom
+    pub fn host() -> std::io::Result<Self> {
+        Ok(Self { source: Source::Host(std::fs::File::open("/dev/urandom")?) })
+    }
+
+    /// The same bytes for the same seed, every run
+    pub fn seeded(seed: u64) -> Self {
+        Self { source: Source::Seeded(crate::machine::Rng::new(seed)) }
+    }
+
+    /// Parses an rng spec: `host` or `seed=N`
+    pub fn open(spec: &str) -> std::io::Result<Self> {
+        match spec.split_once('=') {
+            None if spec == "host" => Self::host(),
+            Some(("seed", n)) => n.parse().map(Self::seeded).map_err(|_| {
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("bad seed `{n}`"))
+            }),
+            _ => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("unknown rng `{spec}`"))),
+        }
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+        match &mut self.source {
+            Source::Host(f) => _ = f.read_exact(buf),
+            Source::Seeded(rng) => {
+                for chunk in buf.chunks_mut(8) {
+                    chunk.copy_from_slice(&rng.next().to_le_bytes()[..chunk.len()]);
+                }
+            },
+        }
+    }
+}
+
+impl Device for Rng {
+    const DEVICE_ID: u32 = 4;
+    const QUEUES: usize = 1;
+    const QUEUE_MAX: u16 = 64;
+
+    fn features(&self) -> u64 {
+        0
+    }
+
+    fn read_config(&self, _offset: u64) -> u8 {
+        0
+    }
+
+    fn reset(&mut self) {}
+
+    fn notify(&mut self, _queue: usize, queues: &mut [Queue], bus: &Bus) {
+        while let Some(chain) = queues[0].pop(bus) {
+            // the guest asks for small amounts, more than a page at once is not worth giving
+            let mut data = vec![0; chain.writable_len().min(4096)];
+            self.fill(&mut data);
+
+            let written = chain.write(bus, &data).unwrap_or(0);
+            queues[0].push(bus, &chain, written);
+        }
+    }
+}
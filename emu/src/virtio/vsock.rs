@@ -0,0 +1,542 @@
+//! virtio-vsock, with stream connections to the host carried over Unix sockets
+//!
+//! The device listens on a Unix socket at `path`. A host program connects to it and sends
+//! `CONNECT <port>\n` to reach a guest listening on vsock port `port`; once the guest accepts, it
+//! gets `OK <host port>\n` and the socket carries the stream. A guest connecting to port `port` of
+//! the host (CID 2) is connected to the Unix socket at `<path>_<port>`, which a host program has to
+//! listen on.
+
+use super::*;
+use std::collections::{HashMap, VecDeque};
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+
+const HOST_CID: u64 = 2;
+
+const VIRTIO_VSOCK_TYPE_STREAM: u16 = 1;
+
+const VIRTIO_VSOCK_OP_REQUEST: u16 = 1;
+const VIRTIO_VSOCK_OP_RESPONSE: u16 = 2;
+const VIRTIO_VSOCK_OP_RST: u16 = 3;
+const VIRTIO_VSOCK_OP_SHUTDOWN: u16 = 4;
+const VIRTIO_VSOCK_OP_RW: u16 = 5;
+const VIRTIO_VSOCK_OP_CREDIT_UPDATE: u16 = 6;
+const VIRTIO_VSOCK_OP_CREDIT_REQUEST: u16 = 7;
+
+const VIRTIO_VSOCK_SHUTDOWN_F_RECEIVE: u32 = 1;
+const VIRTIO_VSOCK_SHUTDOWN_F_SEND: u32 = 2;
+
+const RX: usize = 0;
+const TX: usize = 1;
+
+/// Size of `virtio_vsock_hdr`
+const HDR_SIZE: usize = 44;
+/// Receive buffer of the host end of every connection
+const BUF_ALLOC: u32 = 64 * 1024;
+/// Most data in one packet to the guest
+const MAX_PAYLOAD: usize = 4096;
+/// Packets waiting for receive buffers before the host sockets stop being read
+const MAX_RX: usize = 64;
+/// Host ports handed out to connections from host programs
+const FIRST_HOST_PORT: u32 = 49152;
+
+#[derive(PartialEq)]
+enum State {
+    /// A host program asked for the connection, the guest did not answer yet
+    Connecting,
+    Established,
+    /// The host program closed its socket, waiting for the guest to close too
+    Closing,
+}
+
+struct Conn {
+    stream: UnixStream,
+    state: State,
+    /// Data from the guest the host socket did not take yet
+    out: Vec<u8>,
+    /// Bytes of the guest written to the host socket, and the count last told to the guest
+    fwd_cnt: u32,
+    fwd_reported: u32,
+    /// Bytes sent to the guest, and the receive buffer of the guest with how much of it it read
+    tx_cnt: u32,
+    peer_buf_alloc: u32,
+    peer_fwd_cnt: u32,
+}
+
+struct Header {
+    src_cid: u64,
+    dst_cid: u64,
+    src_port: u32,
+    dst_port: u32,
+    len: u32,
+    ty: u16,
+    op: u16,
+    flags: u32,
+    buf_alloc: u32,
+    fwd_cnt: u32,
+}
+
+impl Header {
+    fn parse(b: &[u8]) -> Self {
+        let u32_at = |at: usize| u32::from_le_bytes(b[at..at + 4].try_into().unwrap());
+        let u16_at = |at: usize| u16::from_le_bytes(b[at..at + 2].try_into().unwrap());
+
+        Self {
+            src_cid: u64::from_le_bytes(b[0..8].try_into().unwrap()),
+            dst_cid: u64::from_le_bytes(b[8..16].try_into().unwrap()),
+            src_port: u32_at(16),
+            dst_port: u32_at(20),
+            len: u32_at(24),
+            ty: u16_at(28),
+            op: u16_at(30),
+            flags: u32_at(32),
+            buf_alloc: u32_at(36),
+            fwd_cnt: u32_at(40),
+        }
+    }
+}
+
+impl Conn {
+    fn new(stream: UnixStream, state: State) -> Self {
+        Self {
+            stream,
+            state,
+            out: Vec::new(),
+            fwd_cnt: 0,
+            fwd_reported: 0,
+            tx_cnt: 0,
+            peer_buf_alloc: 0,
+            peer_fwd_cnt: 0,
+        }
+    }
+
+    /// How much the guest can still take
+    fn credit(&self) -> u32 {
+        self.peer_buf_alloc.saturating_sub(self.tx_cnt.wrapping_sub(self.peer_fwd_cnt))
+    }
+
+    /// Writes what the host socket takes of the data from the guest, `false` once the socket is
+    /// gone
+    fn flush(&mut self) -> bool {
+        while !self.out.is_empty() {
+            match self.stream.write(&self.out) {
+                Ok(0) => return false,
+                Ok(n) => {
+                    self.out.drain(..n);
+                    self.fwd_cnt = self.fwd_cnt.wrapping_add(n as u32);
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(_) => return false,
+            }
+        }
+
+        true
+    }
+}
+
+pub struct Vsock {
+    cid: u64,
+    path: String,
+    listener: UnixListener,
+    /// Host programs that did not send their `CONNECT` line yet
+    handshakes: Vec<(UnixStream, Vec<u8>)>,
+    /// Connections by host port and guest port
+    conns: HashMap<(u32, u32), Conn>,
+    next_port: u32,
+    /// Packets for the guest, waiting for receive buffers
+    rx: VecDeque<Vec<u8>>,
+}
+
+impl Vsock {
+    pub fn new(cid: u64, path: &str) -> std::io::Result<Self> {
+        if cid <= HOST_CID || cid >= u32::MAX as u64 {
+            return Err(std::io::Error::new(ErrorKind::InvalidInput, format!("invalid guest cid {cid}")));
+        }
+
+        crate::chardev::remove_socket(path)?;
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+
+        Ok(Self {
+            cid,
+            path: path.to_string(),
+            listener,
+            handshakes: Vec::new(),
+            conns: HashMap::new(),
+            next_port: FIRST_HOST_PORT,
+            rx: VecDeque::new(),
+        })
+    }
+
+    /// Parses a vsock spec: `cid=N,path=PATH`
+    pub fn open(spec: &str) -> std::io::Result<Self> {
+        let invalid = |msg: String| std::io::Error::new(ErrorKind::InvalidInput, msg);
+        let mut cid = None;
+        let mut path = None;
+
+        for opt in spec.split(',') {
+            match opt.split_once('=') {
+                Some(("cid", v)) => cid = Some(v.parse().map_err(|_| invalid(format!("bad cid `{v}`")))?),
+                Some(("path", v)) => path = Some(v),
+                _ => return Err(invalid(format!("unknown vsock option `{opt}`"))),
+            }
+        }
+
+        Self::new(cid.ok_or_else(|| invalid("missing cid=".into()))?, path.ok_or_else(|| invalid("missing path=".into()))?)
+    }
+
+    /// Queues a packet for the guest from `host_port` to `guest_port`
+    fn send(&mut self, host_port: u32, guest_port: u32, op: u16, flags: u32, data: &[u8]) {
+        let (fwd_cnt, buf_alloc) = match self.conns.get_mut(&(host_port, guest_port)) {
+            Some(conn) => {
+                conn.fwd_reported = conn.fwd_cnt;
+                conn.tx_cnt = conn.tx_cnt.wrapping_add(data.len() as u32);
+                (conn.fwd_cnt, BUF_ALLOC)
+            },
+            None => (0, 0),
+        };
+
+        let mut p = Vec::with_capacity(HDR_SIZE + data.len());
+        p.extend_from_slice(&HOST_CID.to_le_bytes());
+        p.extend_from_slice(&self.cid.to_le_bytes());
+        p.extend_from_slice(&host_port.to_le_bytes());
+        p.extend_from_slice(&guest_port.to_le_bytes());
+        p.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        p.extend_from_slice(&VIRTIO_VSOCK_TYPE_STREAM.to_le_bytes());
+        p.extend_from_slice(&op.to_le_bytes());
+        p.extend_from_slice(&flags.to_le_bytes());
+        p.extend_from_slice(&buf_alloc.to_le_bytes());
+        p.extend_from_slice(&fwd_cnt.to_le_bytes());
+        p.extend_from_slice(data);
+
+        self.rx.push_back(p);
+    }
+
+    fn reset_conn(&mut self, host_port: u32, guest_port: u32) {
+        self.conns.remove(&(host_port, guest_port));
+        self.send(host_port, guest_port, VIRTIO_VSOCK_OP_RST, 0, &[]);
+    }
+
+    /// Handles a packet of the guest
+    fn packet(&mut self, data: &[u8]) {
+        if data.len() < HDR_SIZE {
+            return;
+        }
+
+        let h = Header::parse(data);
+        let payload = &data[HDR_SIZE..(HDR_SIZE + h.len as usize).min(data.len())];
+        if h.src_cid != self.cid || h.dst_cid != HOST_CID || h.ty != VIRTIO_VSOCK_TYPE_STREAM {
+            return;
+        }
+
+        let key = (h.dst_port, h.src_port);
+        if let Some(conn) = self.conns.get_mut(&key) {
+            conn.peer_buf_alloc = h.buf_alloc;
+            conn.peer_fwd_cnt = h.fwd_cnt;
+        }
+
+        match h.op {
+            VIRTIO_VSOCK_OP_REQUEST => {
+                if self.conns.contains_key(&key) {
+                    return self.reset_conn(key.0, key.1);
+                }
+
+                let stream = UnixStream::connect(format!("{}_{}", self.path, h.dst_port));
+                match stream.and_then(|s| s.set_nonblocking(true).map(|_| s)) {
+                    Ok(stream) => {
+                        let mut conn = Conn::new(stream, State::Established);
+                        conn.peer_buf_alloc = h.buf_alloc;
+                        conn.peer_fwd_cnt = h.fwd_cnt;
+                        self.conns.insert(key, conn);
+                        self.send(key.0, key.1, VIRTIO_VSOCK_OP_RESPONSE, 0, &[]);
+                    },
+                    Err(_) => self.send(key.0, key.1, VIRTIO_VSOCK_OP_RST, 0, &[]),
+                }
+            },
+            VIRTIO_VSOCK_OP_RESPONSE => match self.conns.get_mut(&key) {
+                Some(conn) if conn.state == State::Connecting => {
+                    conn.state = State::Established;
+                    _ = conn.stream.write_all(format!("OK {}\n", key.0).as_bytes());
+                },
+                _ => self.reset_conn(key.0, key.1),
+            },
+            VIRTIO_VSOCK_OP_RST => {
+                self.conns.remove(&key);
+            },
+            VIRTIO_VSOCK_OP_SHUTDOWN => {
+                // whatever the guest still sent and the host socket takes goes out before it is
+                // closed
+                if let Some(mut conn) = self.conns.remove(&key) {
+                    conn.flush();
+                }
+
+                if h.flags & (VIRTIO_VSOCK_SHUTDOWN_F_RECEIVE | VIRTIO_VSOCK_SHUTDOWN_F_SEND) != 0 {
+                    self.send(key.0, key.1, VIRTIO_VSOCK_OP_RST, 0, &[]);
+                }
+            },
+            VIRTIO_VSOCK_OP_RW => match self.conns.get_mut(&key) {
+                Some(conn) if conn.state != State::Connecting => {
+                    conn.out.extend_from_slice(payload);
+                    if !conn.flush() {
+                        self.reset_conn(key.0, key.1);
+                    }
+                },
+                _ => self.reset_conn(key.0, key.1),
+            },
+            VIRTIO_VSOCK_OP_CREDIT_UPDATE => {},
+            VIRTIO_VSOCK_OP_CREDIT_REQUEST if self.conns.contains_key(&key) => {
+                self.send(key.0, key.1, VIRTIO_VSOCK_OP_CREDIT_UPDATE, 0, &[]);
+            },
+            _ => self.reset_conn(key.0, key.1),
+        }
+    }
+
+    /// Takes in new host programs and their `CONNECT` lines
+    fn accept(&mut self) {
+        while let Ok((stream, _)) = self.listener.accept() {
+            if stream.set_nonblocking(true).is_ok() {
+                self.handshakes.push((stream, Vec::new()));
+            }
+        }
+
+        let mut i = 0;
+        while i < self.handshakes.len() {
+            let (stream, line) = &mut self.handshakes[i];
+
+            // a byte at a time, so that nothing after the line is taken from the stream
+            let mut b = [0];
+            let done = loop {
+                match stream.read(&mut b) {
+                    Ok(0) => break Some(None),
+                    Ok(_) if b[0] == b'\n' => break Some(Some(String::from_utf8_lossy(line).into_owned())),
+                    Ok(_) if line.len() < 64 => line.push(b[0]),
+                    Ok(_) => break Some(None),
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => break None,
+                    Err(_) => break Some(None),
+                }
+            };
+
+            let Some(line) = done else {
+                i += 1;
+                continue;
+            };
+
+            let (stream, _) = self.handshakes.swap_remove(i);
+            let Some(port) = line.and_then(|l| l.trim().strip_prefix("CONNECT ")?.parse::<u32>().ok()) else {
+                continue;
+            };
+
+            let host_port = self.next_port;
+            self.next_port = self.next_port.checked_add(1).unwrap_or(FIRST_HOST_PORT);
+            self.conns.insert((host_port, port), Conn::new(stream, State::Connecting));
+            self.send(host_port, port, VIRTIO_VSOCK_OP_REQUEST, 0, &[]);
+        }
+    }
+
+    /// Reads the host sockets as far as the guest has room for the data
+    fn forward(&mut self) {
+        let keys = self.conns.keys().copied().collect::<Vec<_>>();
+
+        for key in keys {
+            let conn = self.conns.get_mut(&key).unwrap();
+            if !conn.flush() {
+                self.reset_conn(key.0, key.1);
+                continue;
+            }
+
+            if conn.state == State::Established {
+                let mut buf = vec![0; MAX_PAYLOAD];
+                while self.rx.len() < MAX_RX {
+                    let conn = self.conns.get_mut(&key).unwrap();
+                    let n = (conn.credit() as usize).min(MAX_PAYLOAD);
+                    if n == 0 {
+                        break;
+                    }
+
+                    match conn.stream.read(&mut buf[..n]) {
+                        Ok(0) => {
+                            conn.state = State::Closing;
+                            let flags = VIRTIO_VSOCK_SHUTDOWN_F_RECEIVE | VIRTIO_VSOCK_SHUTDOWN_F_SEND;
+                            self.send(key.0, key.1, VIRTIO_VSOCK_OP_SHUTDOWN, flags, &[]);
+                            break;
+                        },
+                        Ok(n) => self.send(key.0, key.1, VIRTIO_VSOCK_OP_RW, 0, &buf[..n]),
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                        Err(_) => {
+                            self.reset_conn(key.0, key.1);
+                            break;
+                        },
+                    }
+                }
+            }
+
+            // the guest waits for credit once it filled the buffer, tell it before it has to ask
+            if let Some(conn) = self.conns.get(&key) {
+                if conn.fwd_cnt.wrapping_sub(conn.fwd_reported) >= BUF_ALLOC / 2 {
+                    self.send(key.0, key.1, VIRTIO_VSOCK_OP_CREDIT_UPDATE, 0, &[]);
+                }
+            }
+        }
+    }
+
+    fn receive(&mut self, queues: &mut [Queue], bus: &Bus) {
+        self.accept();
+        self.forward();
+
+        while !self.rx.is_empty() {
+            let Some(chain) = queues[RX].pop(bus) else { break };
+            let p = self.rx.pop_front().unwrap();
+            let written = chain.write(bus, &p).unwrap_or(0);
+            queues[RX].push(bus, &chain, written);
+        }
+    }
+}
+
+impl Device for Vsock {
+    const DEVICE_ID: u32 = 19;
+    /// rx, tx and event, the event queue is never used since the device is never migrated
+    const QUEUES: usize = 3;
+    const QUEUE_MAX: u16 = 128;
+
+    fn features(&self) -> u64 {
+        0
+    }
+
+    fn read_config(&self, offset: u64) -> u8 {
+        match offset {
+            0..8 => self.cid.to_le_bytes()[offset as usize],
+            _ => 0,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.conns.clear();
+        self.rx.clear();
+    }
+
+    fn notify(&mut self, queue: usize, queues: &mut [Queue], bus: &Bus) {
+        if queue == TX {
+            while let Some(chain) = queues[TX].pop(bus) {
+                if let Ok(data) = chain.read(bus) {
+                    self.packet(&data);
+                }
+
+                queues[TX].push(bus, &chain, 0);
+            }
+        }
+
+        self.receive(queues, bus);
+    }
+
+    fn poll(&mut self, queues: &mut [Queue], bus: &Bus) {
+        self.receive(queues, bus);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GUEST_CID: u64 = 3;
+
+    /// A socket path under the system temporary directory, with nothing there yet
+    fn socket_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("emu-vsock-{name}-{}", std::process::id()));
+        _ = std::fs::remove_file(&path);
+        path.to_str().unwrap().to_string()
+    }
+
+    /// A packet of the guest from `src_port` to host port `dst_port`
+    fn packet(src_port: u32, dst_port: u32, op: u16, data: &[u8]) -> Vec<u8> {
+        let mut p = Vec::new();
+        p.extend_from_slice(&GUEST_CID.to_le_bytes());
+        p.extend_from_slice(&HOST_CID.to_le_bytes());
+        p.extend_from_slice(&src_port.to_le_bytes());
+        p.extend_from_slice(&dst_port.to_le_bytes());
+        p.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        p.extend_from_slice(&VIRTIO_VSOCK_TYPE_STREAM.to_le_bytes());
+        p.extend_from_slice(&op.to_le_bytes());
+        p.extend_from_slice(&0u32.to_le_bytes());
+        p.extend_from_slice(&BUF_ALLOC.to_le_bytes());
+        p.extend_from_slice(&0u32.to_le_bytes());
+        p.extend_from_slice(data);
+        p
+    }
+
+    /// The next packet for the guest, as its source port, destination port, op and data
+    fn next(vsock: &mut Vsock) -> (u32, u32, u16, Vec<u8>) {
+        let p = vsock.rx.pop_front().expect("no packet for the guest");
+        let h = Header::parse(&p);
+        assert_eq!((h.src_cid, h.dst_cid, h.ty), (HOST_CID, GUEST_CID, VIRTIO_VSOCK_TYPE_STREAM));
+        (h.src_port, h.dst_port, h.op, p[HDR_SIZE..].to_vec())
+    }
+
+    fn read_exact(stream: &mut UnixStream, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        stream.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn host_connects_to_guest_listener() {
+        let path = socket_path("host");
+        let mut vsock = Vsock::new(GUEST_CID, &path).unwrap();
+
+        let mut host = UnixStream::connect(&path).unwrap();
+        host.write_all(b"CONNECT 1234\n").unwrap();
+        vsock.accept();
+        assert_eq!(next(&mut vsock), (FIRST_HOST_PORT, 1234, VIRTIO_VSOCK_OP_REQUEST, vec![]));
+
+        // the guest accepts, and the host program is told its port
+        vsock.packet(&packet(1234, FIRST_HOST_PORT, VIRTIO_VSOCK_OP_RESPONSE, &[]));
+        assert_eq!(read_exact(&mut host, 9), b"OK 49152\n");
+
+        host.write_all(b"ping").unwrap();
+        vsock.forward();
+        assert_eq!(next(&mut vsock), (FIRST_HOST_PORT, 1234, VIRTIO_VSOCK_OP_RW, b"ping".to_vec()));
+        vsock.packet(&packet(1234, FIRST_HOST_PORT, VIRTIO_VSOCK_OP_RW, b"pong"));
+        assert_eq!(read_exact(&mut host, 4), b"pong");
+
+        // closing the socket shuts the connection down
+        drop(host);
+        vsock.forward();
+        assert_eq!(next(&mut vsock).2, VIRTIO_VSOCK_OP_SHUTDOWN);
+
+        // and a host program that does not say where to connect is dropped
+        let mut host = UnixStream::connect(&path).unwrap();
+        host.write_all(b"HELLO\n").unwrap();
+        vsock.accept();
+        assert!(vsock.rx.is_empty());
+        assert_eq!(host.read(&mut [0]).unwrap(), 0);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn guest_connects_to_host_port() {
+        let path = socket_path("guest");
+        let mut vsock = Vsock::new(GUEST_CID, &path).unwrap();
+        let listener = UnixListener::bind(format!("{path}_5000")).unwrap();
+
+        vsock.packet(&packet(1000, 5000, VIRTIO_VSOCK_OP_REQUEST, &[]));
+        assert_eq!(next(&mut vsock), (5000, 1000, VIRTIO_VSOCK_OP_RESPONSE, vec![]));
+        let (mut host, _) = listener.accept().unwrap();
+
+        vsock.packet(&packet(1000, 5000, VIRTIO_VSOCK_OP_RW, b"hello"));
+        assert_eq!(read_exact(&mut host, 5), b"hello");
+        host.write_all(b"world").unwrap();
+        vsock.forward();
+        assert_eq!(next(&mut vsock), (5000, 1000, VIRTIO_VSOCK_OP_RW, b"world".to_vec()));
+
+        // once the guest shuts down, the host program sees the end of the stream
+        vsock.packet(&packet(1000, 5000, VIRTIO_VSOCK_OP_SHUTDOWN, &[]));
+        assert_eq!(host.read(&mut [0]).unwrap(), 0);
+
+        // a port nothing listens on is refused
+        vsock.packet(&packet(1001, 5001, VIRTIO_VSOCK_OP_REQUEST, &[]));
+        assert_eq!(next(&mut vsock), (5001, 1001, VIRTIO_VSOCK_OP_RST, vec![]));
+
+        std::fs::remove_file(format!("{path}_5000")).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
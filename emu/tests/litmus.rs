@@ -0,0 +1,47 @@
+//! Runs the litmus tests in `tests/litmus` with weak memory and fixed seeds: the relaxed outcome of
+//! each test without ordering shows up, and never once the test orders its accesses.
+
+use emu::litmus::{explore, reg_index};
+use std::collections::BTreeMap;
+
+const HARTS: usize = 2;
+const RUNS: usize = 1000;
+const SEED: u64 = 1;
+
+fn run(name: &str, observe: &[&str]) -> BTreeMap<String, usize> {
+    let path = format!("{}/../tests/litmus/{name}.bin", env!("CARGO_MANIFEST_DIR"));
+    let image = std::fs::read(&path).unwrap_or_else(|e| panic!("`{path}`: {e}"));
+    let observe = observe.iter().map(|r| reg_index(r).unwrap()).collect::<Vec<_>>();
+
+    let outcomes = explore(&image, HARTS, RUNS, SEED, &observe);
+    assert!(!outcomes.contains_key("timeout"), "{name}: {outcomes:?}");
+    outcomes
+}
+
+/// Whether some outcome has the values in `relaxed`
+fn seen(outcomes: &BTreeMap<String, usize>, relaxed: &str) -> bool {
+    outcomes.keys().any(|o| o.contains(relaxed))
+}
+
+#[test]
+fn store_buffering() {
+    let sb = run("sb", &["a0"]);
+    assert!(seen(&sb, "0:a0=0 1:a0=0"), "sb: {sb:?}");
+
+    let sb_fence = run("sb_fence", &["a0"]);
+    assert!(!seen(&sb_fence, "0:a0=0 1:a0=0"), "sb_fence: {sb_fence:?}");
+    // the fences still let every other interleaving happen
+    assert_eq!(sb_fence.len(), 3, "sb_fence: {sb_fence:?}");
+}
+
+#[test]
+fn message_passing() {
+    let mp = run("mp", &["a0", "a1"]);
+    assert!(seen(&mp, "1:a0=1 1:a1=0"), "mp: {mp:?}");
+
+    for name in ["mp_fence", "mp_aqrl"] {
+        let outcomes = run(name, &["a0", "a1"]);
+        assert!(!seen(&outcomes, "1:a0=1 1:a1=0"), "{name}: {outcomes:?}");
+        assert!(seen(&outcomes, "1:a0=1 1:a1=1"), "{name}: {outcomes:?}");
+    }
+}
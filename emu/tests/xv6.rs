@@ -0,0 +1,74 @@
+//! Boots xv6-riscv to its shell and drives it through an in-memory UART backend. Needs a build of
+//! xv6-riscv: set `XV6` to its directory, with `kernel/kernel` and `fs.img` in it, and run with
+//! `cargo test -r -- --ignored`. xv6 from before 2022 talks to the legacy virtio-mmio interface, set
+//! `XV6_VIRTIO_LEGACY=1` for it.
+
+use emu::bus::Bus;
+use emu::chardev::{Memory, MemoryHandle};
+use emu::clint::Timebase;
+use emu::machine::Machine;
+
+const HARTS: usize = 3;
+/// Instructions to give up after, waiting for some output. Booting takes most of them, `kinit`
+/// fills all of RAM a byte at a time.
+const LIMIT: usize = 4_000_000_000;
+
+/// Runs until the guest has written something since `from` and the transcript ends with `prompt`,
+/// returns what was written since `from`
+fn run_until(machine: &mut Machine, uart: &MemoryHandle, from: usize, prompt: &str) -> String {
+    let mut steps = 0;
+    loop {
+        machine.step(false);
+        steps += 1000 * HARTS;
+
+        let transcript = uart.transcript();
+        if transcript.len() > from && transcript.ends_with(prompt.as_bytes()) {
+            return String::from_utf8_lossy(&transcript[from..]).into_owned();
+        }
+
+        assert!(steps < LIMIT, "no `{prompt}` after {steps} instructions:\n{}", String::from_utf8_lossy(&transcript));
+    }
+}
+
+/// Types a command, and returns its output up to the next prompt
+fn command(machine: &mut Machine, uart: &MemoryHandle, line: &str) -> String {
+    let from = uart.transcript().len();
+    uart.send(format!("{line}\n").as_bytes());
+    run_until(machine, uart, from, "$ ")
+}
+
+#[test]
+#[ignore = "needs an xv6-riscv build in $XV6"]
+fn shell() {
+    let dir = std::env::var("XV6").expect("XV6 is not set");
+    let legacy = std::env::var("XV6_VIRTIO_LEGACY").is_ok_and(|v| v == "1");
+
+    let kernel = std::fs::read(format!("{dir}/kernel/kernel")).unwrap();
+    let kernel = emu::elf::Elf::parse(&kernel).unwrap();
+    let drive = emu::disk::open(&format!("file={dir}/fs.img,snapshot=on")).unwrap();
+
+    let mut bus = Bus::new(emu::ram::Ram::new(&[]), HARTS, Timebase::Instructions(1));
+    let (backend, uart) = Memory::new();
+    bus.add_uart(Box::new(backend)).unwrap();
+    bus.add_virtio(emu::virtio::Blk::new(drive), legacy).unwrap();
+    kernel.load(&bus).unwrap();
+    bus.set_reset_vector(kernel.entry);
+
+    let mut machine = Machine::new(&bus, HARTS, 1000, false);
+    let boot = run_until(&mut machine, &uart, 0, "$ ");
+    assert!(boot.contains("xv6 kernel is booting"), "{boot}");
+    assert!(boot.contains("init: starting sh"), "{boot}");
+
+    let out = command(&mut machine, &uart, "echo hello xv6");
+    assert!(out.contains("\nhello xv6\n"), "{out}");
+
+    let out = command(&mut machine, &uart, "ls");
+    assert!(out.contains("README"), "{out}");
+    assert!(out.contains("console"), "{out}");
+
+    // a pipe and a file on the disk, through the shell
+    let out = command(&mut machine, &uart, "echo on disk > f");
+    assert!(!out.contains("cannot"), "{out}");
+    let out = command(&mut machine, &uart, "cat f | wc");
+    assert!(out.contains("1 2 8 "), "{out}");
+}
@@ -1,22 +1,378 @@
 use clap::*;
+use emu::clint::Timebase;
+use std::sync::OnceLock;
+
+#[derive(Clone, Copy, ValueEnum)]
+enum TimebaseArg {
+    /// Advance mtime by instructions retired on hart 0
+    Instructions,
+    /// Advance mtime by the host monotonic clock
+    Host,
+}
+
 #[derive(Parser)]
 struct Args {
-    prog: String,
+    #[arg(required_unless_present_any = ["commit_overlay", "discard_overlay", "checkpoint_overlay", "kernel"])]
+    prog: Option<String>,
 
     #[arg(long)]
     testing: bool,
+
+    /// Where the harts start: the boot ROM by default, which passes the hart ID in a0 and the
+    /// device tree in a1 and jumps to --entry
+    #[arg(long, value_parser = parse_addr, default_value = "0x1000")]
+    reset_vector: u64,
+
+    /// Where the boot ROM jumps to: the entry of an ELF program by default, else 0x80000000
+    #[arg(long, value_parser = parse_addr)]
+    entry: Option<u64>,
+
+    /// Handle S-mode ecalls with the built-in SBI firmware: the harts start in S-mode at --entry or
+    /// the kernel, hart 0 first and the others when started through HSM
+    #[arg(long)]
+    sbi: bool,
+
+    /// Run prog, a static riscv64 Linux program, in U-mode with its system calls done by the host,
+    /// like qemu-riscv64. The arguments after `--` are passed on to it.
+    #[arg(long, conflicts_with_all = ["kernel", "sbi", "litmus"])]
+    user: bool,
+
+    /// Arguments of the --user program
+    #[arg(last = true, requires = "user")]
+    user_args: Vec<String>,
+
+    /// Device tree blob to pass to the guest instead of the one generated from the machine
+    #[arg(long)]
+    dtb: Option<String>,
+
+    /// Write the generated device tree blob to a file, then exit
+    #[arg(long)]
+    dump_dtb: Option<String>,
+
+    /// Linux kernel Image to load at its text offset in RAM, for the firmware or --sbi to boot
+    #[arg(long)]
+    kernel: Option<String>,
+
+    /// Initramfs to load below the device tree at the top of RAM
+    #[arg(long, requires = "kernel")]
+    initrd: Option<String>,
+
+    /// Kernel command line
+    #[arg(long, requires = "kernel")]
+    append: Option<String>,
+
+    /// What drives mtime
+    #[arg(long, value_enum, default_value_t = TimebaseArg::Instructions)]
+    timebase: TimebaseArg,
+
+    /// Instructions of hart 0 per mtime tick, with the instructions timebase
+    #[arg(long, default_value_t = 100)]
+    insts_per_tick: u64,
+
+    /// Frequency of mtime in Hz, that the host timebase runs at and devices count time in
+    #[arg(long, default_value_t = 10_000_000)]
+    timebase_freq: u64,
+
+    /// Number of harts
+    #[arg(long, default_value_t = 1)]
+    harts: usize,
+
+    /// Instructions each hart runs before switching to the next one
+    #[arg(long, default_value_t = 1000)]
+    quantum: usize,
+
+    /// Run each hart on its own host thread instead of the deterministic scheduler
+    #[arg(long)]
+    parallel: bool,
+
+    /// Don't switch harts in the middle of a constrained LR/SC loop
+    #[arg(long)]
+    forward_progress: bool,
+
+    /// Give each hart a store buffer and interleave them randomly, to find missing fences
+    #[arg(long, conflicts_with = "parallel")]
+    weak_memory: bool,
+
+    /// Seed of the weak memory interleavings
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+
+    /// Run a litmus test this many times with weak memory and print the outcomes
+    #[arg(long, conflicts_with = "parallel")]
+    litmus: Option<usize>,
+
+    /// Registers that make up the outcome of a litmus test
+    #[arg(long, value_delimiter = ',', default_value = "a0")]
+    observe: Vec<String>,
+
+    /// Backend of the next UART: stdio, pty, unix:PATH, file:PATH, tcp:ADDR or null
+    #[arg(long, default_value = "stdio")]
+    serial: Vec<String>,
+
+    /// Disk of the next virtio-blk device: file=PATH[,format=raw|cow][,overlay=PATH][,readonly=on][,snapshot=on]
+    #[arg(long)]
+    drive: Vec<String>,
+
+    /// Backend of the next virtio-net device: user, unix:LOCAL:PEER, pcap:PATH or null, then
+    /// [,mac=MAC][,pcap=PATH]
+    #[arg(long)]
+    netdev: Vec<String>,
+
+    /// Port of the virtio-console device: NAME=CHARDEV, with the name `console` for a console port
+    #[arg(long)]
+    vport: Vec<String>,
+
+    /// virtio-vsock device: cid=N,path=PATH, connections to the host go over Unix sockets at PATH
+    #[arg(long)]
+    vsock: Option<String>,
+
+    /// Entropy of the virtio-rng device: host, or seed=N for the same bytes every run
+    #[arg(long)]
+    rng: Option<String>,
+
+    /// Host directory exported over virtio-9p: path=DIR[,tag=TAG][,readonly=on]
+    #[arg(long)]
+    share: Vec<String>,
+
+    /// Backend whose input a virtio-input keyboard types: stdio, pty, unix:PATH, tcp:ADDR
+    #[arg(long)]
+    keyboard: Option<String>,
+
+    /// Clock of the Goldfish RTC: host, or epoch=SECONDS to start there and advance with mtime
+    #[arg(long, default_value = "host")]
+    rtc: String,
+
+    /// Framebuffer at 0x50000000: WIDTHxHEIGHT[,format=FORMAT][,dump=PATH,every=N], with every=N
+    /// saving the screen every N frames (at 60 per second of mtime) as PATH-FRAME.png or .ppm
+    #[arg(long)]
+    framebuffer: Option<String>,
+
+    /// Backend of the monitor, which takes commands like `screendump FILE`: stdio, pty, unix:PATH,
+    /// tcp:ADDR
+    #[arg(long)]
+    monitor: Option<String>,
+
+    /// Expose the virtio devices with the legacy (version 1) virtio-mmio interface
+    #[arg(long)]
+    virtio_legacy: bool,
+
+    /// Write the changes in a copy-on-write overlay to its base image and empty it, then exit
+    #[arg(long)]
+    commit_overlay: Option<String>,
+
+    /// Throw away the changes in a copy-on-write overlay, then exit
+    #[arg(long)]
+    discard_overlay: Option<String>,
+
+    /// Freeze a copy-on-write overlay as a disk state to come back to, and start a new overlay on
+    /// top of it, then exit. Run on with `file=OVERLAY,overlay=NEW`.
+    #[arg(long, num_args = 2, value_names = ["OVERLAY", "NEW"])]
+    checkpoint_overlay: Option<Vec<String>>,
 }
 
 fn main() {
     let args = Args::parse();
 
-    let mut ram = std::fs::read(&args.prog).unwrap();
-    ram.resize(emu::bus::RAM_SIZE as usize, 0);
-    let ram = emu::ram::Ram::new(&mut ram);
-    let mut bus = emu::bus::Bus::new(ram);
-    let mut cpu = emu::cpu::Cpu::new(&mut bus);
+    if let Some(path) = &args.commit_overlay {
+        emu::disk::Cow::open(path, true, true).and_then(|mut o| o.commit()).unwrap_or_else(|e| panic!("commit `{path}`: {e}"));
+        return;
+    }
+
+    if let Some(path) = &args.discard_overlay {
+        emu::disk::Cow::open(path, true, false).and_then(|mut o| o.discard_all()).unwrap_or_else(|e| panic!("discard `{path}`: {e}"));
+        return;
+    }
+
+    if let Some([path, new]) = args.checkpoint_overlay.as_deref() {
+        emu::disk::Cow::open(path, true, false).and_then(|mut o| o.checkpoint(new)).unwrap_or_else(|e| panic!("checkpoint `{path}`: {e}"));
+        return;
+    }
+
+    let ram = args.prog.as_ref().map_or_else(Vec::new, |p| std::fs::read(p).unwrap());
+
+    if let Some(runs) = args.litmus {
+        let observe = args.observe.iter().map(|r| emu::litmus::reg_index(r).expect("unknown register")).collect::<Vec<_>>();
+
+        for (outcome, count) in emu::litmus::explore(&ram, args.harts, runs, args.seed, &observe) {
+            println!("{count:>8} {outcome}");
+        }
+
+        return;
+    }
+
+    let timebase = match args.timebase {
+        TimebaseArg::Instructions => Timebase::Instructions(args.insts_per_tick.max(1)),
+        TimebaseArg::Host => Timebase::Host(args.timebase_freq),
+    };
+
+    if args.user {
+        let path = args.prog.as_deref().unwrap();
+        let elf = emu::elf::Elf::parse(&ram).unwrap_or_else(|e| panic!("user `{path}`: {e}"));
+        let bus = emu::bus::Bus::new(emu::ram::Ram::new(&[]), 1, timebase);
+
+        let argv = std::iter::once(path.to_string()).chain(args.user_args.iter().cloned()).collect::<Vec<_>>();
+        let envp = std::env::vars_os().map(|(k, v)| format!("{}={}", k.to_string_lossy(), v.to_string_lossy())).collect::<Vec<_>>();
+        let mut machine = emu::machine::Machine::user(&bus, &elf, &argv, &envp).unwrap_or_else(|e| panic!("user `{path}`: {e}"));
+        std::process::exit(machine.run(args.testing).status());
+    }
+
+    // ELF programs go where their segments say, anything else is an image of the start of RAM
+    let elf = ram.starts_with(&emu::elf::MAGIC).then(|| emu::elf::Elf::parse(&ram).unwrap_or_else(|e| panic!("elf: {e}")));
+    let firmware_len = elf.as_ref().map_or(ram.len() as u64, |e| e.end().saturating_sub(emu::bus::RAM_BASE));
+
+    let mut bus = emu::bus::Bus::new(emu::ram::Ram::new(&[]), args.harts, timebase);
+    match &elf {
+        Some(elf) => elf.load(&bus).unwrap_or_else(|e| panic!("elf: {e}")),
+        None => bus.load(emu::bus::RAM_BASE, &ram).unwrap_or_else(|e| panic!("program: {e}")),
+    }
+    bus.set_reset_vector(args.reset_vector);
+    bus.set_entry(args.entry.or(elf.as_ref().map(|e| e.entry)).unwrap_or(emu::bus::RAM_BASE));
+    if args.sbi {
+        bus.enable_sbi();
+    }
+
+    for spec in &args.serial {
+        let backend = emu::chardev::open(spec).unwrap_or_else(|e| panic!("serial `{spec}`: {e}"));
+        bus.add_uart(backend).expect("too many UARTs");
+    }
+
+    for spec in &args.drive {
+        let drive = emu::disk::open(spec).unwrap_or_else(|e| panic!("drive `{spec}`: {e}"));
+        bus.add_virtio(emu::virtio::Blk::new(drive), args.virtio_legacy).expect("too many virtio devices");
+    }
+
+    for (i, spec) in args.netdev.iter().enumerate() {
+        let netdev = emu::net::open(spec).unwrap_or_else(|e| panic!("netdev `{spec}`: {e}"));
+        let mac = netdev.mac.unwrap_or([0x52, 0x54, 0x00, 0x12, 0x34, 0x56 + i as u8]);
+        bus.add_virtio(emu::virtio::Net::new(netdev.backend, mac), args.virtio_legacy).expect("too many virtio devices");
+    }
+
+    if !args.vport.is_empty() {
+        let ports = args.vport.iter().map(|spec| emu::virtio::Port::open(spec).unwrap_or_else(|e| panic!("vport `{spec}`: {e}")));
+        let ports = ports.collect::<Vec<_>>();
+        assert!(ports.len() <= emu::virtio::MAX_PORTS, "too many virtio-console ports");
+        bus.add_virtio(emu::virtio::Console::new(ports), args.virtio_legacy).expect("too many virtio devices");
+    }
+
+    if let Some(spec) = &args.vsock {
+        let vsock = emu::virtio::Vsock::open(spec).unwrap_or_else(|e| panic!("vsock `{spec}`: {e}"));
+        bus.add_virtio(vsock, args.virtio_legacy).expect("too many virtio devices");
+    }
+
+    if let Some(spec) = &args.rng {
+        let rng = emu::virtio::Rng::open(spec).unwrap_or_else(|e| panic!("rng `{spec}`: {e}"));
+        bus.add_virtio(rng, args.virtio_legacy).expect("too many virtio devices");
+    }
+
+    for spec in &args.share {
+        let share = emu::virtio::P9::open(spec).unwrap_or_else(|e| panic!("share `{spec}`: {e}"));
+        bus.add_virtio(share, args.virtio_legacy).expect("too many virtio devices");
+    }
+
+    if let Some(spec) = &args.keyboard {
+        let backend = emu::chardev::open(spec).unwrap_or_else(|e| panic!("keyboard `{spec}`: {e}"));
+        bus.add_virtio(emu::virtio::Keyboard::new(backend), args.virtio_legacy).expect("too many virtio devices");
+    }
+
+    let rtc = emu::rtc::Rtc::open(&args.rtc, args.timebase_freq).unwrap_or_else(|e| panic!("rtc `{}`: {e}", args.rtc));
+    bus.add_rtc(rtc);
+
+    if let Some(spec) = &args.framebuffer {
+        let fb = emu::framebuffer::Framebuffer::open(spec, args.timebase_freq).unwrap_or_else(|e| panic!("framebuffer `{spec}`: {e}"));
+        bus.add_framebuffer(fb);
+    }
+
+    if let Some(spec) = &args.monitor {
+        let backend = emu::chardev::open(spec).unwrap_or_else(|e| panic!("monitor `{spec}`: {e}"));
+        bus.set_monitor(emu::monitor::Monitor::new(backend));
+    }
+
+    let mut initrd = None;
+    if let Some(path) = &args.kernel {
+        assert!(args.prog.is_some() || args.sbi, "a kernel needs firmware to boot it, or --sbi");
+
+        let data = std::fs::read(path).unwrap_or_else(|e| panic!("kernel `{path}`: {e}"));
+        let image = emu::kernel::Image::parse(&data).unwrap_or_else(|e| panic!("kernel `{path}`: {e}"));
+        assert!(firmware_len <= image.text_offset, "firmware overlaps the kernel");
+
+        let data = args.initrd.as_ref().map(|p| std::fs::read(p).unwrap_or_else(|e| panic!("initrd `{p}`: {e}")));
+        let boot = emu::kernel::load(&bus, &image, data.as_deref()).unwrap_or_else(|e| panic!("kernel `{path}`: {e}"));
+
+        bus.set_next_addr(boot.entry);
+        if args.sbi {
+            bus.set_entry(boot.entry);
+        }
+        initrd = boot.initrd;
+    }
+
+    let dtb = match &args.dtb {
+        Some(path) => std::fs::read(path).unwrap_or_else(|e| panic!("dtb `{path}`: {e}")),
+        None => {
+            let config = emu::fdt::Config { timebase_freq: args.timebase_freq, bootargs: args.append.clone(), initrd: initrd.clone() };
+            emu::fdt::generate(&bus, &config)
+        },
+    };
+
+    if let Some(path) = &args.dump_dtb {
+        std::fs::write(path, &dtb).unwrap_or_else(|e| panic!("dump dtb `{path}`: {e}"));
+        return;
+    }
+
+    let dtb = bus.load_dtb(&dtb).expect("device tree does not fit in RAM");
+    assert!(initrd.is_none_or(|i| dtb >= i.end), "device tree overlaps the initrd");
+
+    let vports = args.vport.iter().filter_map(|p| p.split_once('=').map(|p| p.1));
+    let others = args.keyboard.as_deref().into_iter().chain(args.monitor.as_deref());
+    if !args.testing && args.serial.iter().map(String::as_str).chain(vports).chain(others).any(|s| s == "stdio") {
+        raw_mode();
+    }
+
+    let mut machine = emu::machine::Machine::new(&bus, args.harts, args.quantum, args.forward_progress);
+
+    if args.weak_memory {
+        machine.weak_memory(args.seed);
+    }
 
     loop {
-        cpu.step(args.testing);
+        let exit = if args.parallel { machine.run_parallel(args.testing) } else { machine.run(args.testing) };
+
+        match exit {
+            emu::syscon::Exit::Reset => machine.reset(),
+            _ => std::process::exit(exit.status()),
+        }
+    }
+}
+
+/// Parses an address, in hex with a 0x prefix or in decimal
+fn parse_addr(s: &str) -> Result<u64, String> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|e| e.to_string())
+}
+
+/// Puts the terminal in raw mode so that the guest gets every key, and restores it at exit
+fn raw_mode() {
+    static ORIGINAL: OnceLock<libc::termios> = OnceLock::new();
+
+    extern "C" fn restore() {
+        if let Some(t) = ORIGINAL.get() {
+            unsafe { libc::tcsetattr(0, libc::TCSANOW, t) };
+        }
+    }
+
+    unsafe {
+        let mut t = core::mem::zeroed();
+        if libc::isatty(0) == 0 || libc::tcgetattr(0, &mut t) != 0 {
+            return;
+        }
+
+        _ = ORIGINAL.set(t);
+        libc::cfmakeraw(&mut t);
+        // keep \n -> \r\n, guests often print bare newlines
+        t.c_oflag |= libc::OPOST;
+        libc::tcsetattr(0, libc::TCSANOW, &t);
+        libc::atexit(restore);
     }
 }
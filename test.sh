@@ -7,8 +7,10 @@ failed=()
 for f in "./tests/rv64$1"*.bin; do
     echo -e "     \x1b[34;1mTesting\x1b[0m \`$(basename $f)\`"
     log="./logs/$(basename $f).log"
+    # extra arguments of a test, like the disk it needs
+    args=$(cat "${f%.bin}.args" 2>/dev/null)
 
-    timeout 5 ./target/release/rv64 "$f" --testing 2>&1 > $log && {
+    timeout 5 ./target/release/rv64 "$f" --testing $args 2>&1 > $log && {
         rm $log &
     } || {
         failed+=("$(basename $f)")